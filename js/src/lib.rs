@@ -207,6 +207,22 @@ async fn request_scan_async() -> Result<String, String> {
     Ok("Sync task launched".to_string())
 }
 
+#[node_bindgen]
+fn rescan() -> Result<String, String> {
+    run_blocking(|wallet| async move {
+        let rep = wallet.rescan().await.map_err(|e| e.to_string())?;
+        Ok(json::object! {"height" => rep.height}.pretty(2))
+    })
+}
+
+#[node_bindgen]
+fn rewind_to(height: u32) -> Result<String, String> {
+    run_blocking(|wallet| async move {
+        let rep = wallet.rewind_to(height).await.map_err(|e| e.to_string())?;
+        Ok(json::object! {"height" => rep.height}.pretty(2))
+    })
+}
+
 fn transfer_to_obj(t: Transfer) -> json::JsonValue {
     json::object! {
         "address" => t.address,
@@ -1,5 +1,7 @@
 use rocket::serde::{Deserialize, Serialize};
 
+use crate::BalanceMode;
+
 pub struct Account {
     pub account_index: u32,
     pub address: String,
@@ -10,6 +12,9 @@ pub struct SubAccount {
     pub account_index: u32,
     pub sub_account_index: u32,
     pub address: String,
+    /// Unix time after which the address is inactive if unpaid
+    pub expires_at: Option<u64>,
+    pub active: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,4 +25,35 @@ pub struct AccountBalance {
     pub label: String,
     pub tag: String,
     pub unlocked_balance: u64,
+    pub balance_mode: BalanceMode,
+}
+
+/// Payments received by an address after its expiry
+#[derive(Serialize, Deserialize)]
+pub struct LatePayment {
+    pub account_index: u32,
+    pub address_index: u32,
+    pub address: String,
+    pub label: String,
+    pub expires_at: u64,
+    pub amount: u64,
+    pub txids: Vec<String>,
+}
+
+/// Balance of an account at a past height, with the sub-accounts that
+/// held funds
+#[derive(Serialize, Deserialize)]
+pub struct HistoricalBalance {
+    pub account_index: u32,
+    pub label: String,
+    pub balance: u64,
+    pub sub_accounts: Vec<SubAccountBalance>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubAccountBalance {
+    pub address_index: u32,
+    pub address: String,
+    pub label: String,
+    pub balance: u64,
 }
@@ -0,0 +1,417 @@
+//! Authentication of the REST API with static API keys or HTTP Digest
+//! (RFC 7616 with MD5, like monero-wallet-rpc `--rpc-login`).
+//!
+//! Routes take a `ReadAccess` or `AdminAccess` guard. Without any key or
+//! login configured, every request is accepted.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+use crate::{rpc::WalletPrefix, WalletConfig};
+
+const REALM: &str = "zcash-walletd";
+/// Lifetime of a Digest nonce in seconds
+const NONCE_LIFETIME: u64 = 300;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Scope {
+    ReadOnly,
+    Admin,
+}
+
+struct Login {
+    user: String,
+    password: String,
+    scope: Scope,
+}
+
+struct ApiKey {
+    key: String,
+    scope: Scope,
+    /// Hosted wallet the key is restricted to, any wallet if None
+    wallet: Option<String>,
+}
+
+pub struct ApiAuth {
+    keys: Vec<ApiKey>,
+    logins: Vec<Login>,
+    secret: [u8; 32],
+}
+
+/// Why a request was not authenticated
+#[derive(Debug, PartialEq)]
+pub enum AuthFailure {
+    Missing,
+    Invalid,
+    /// The Digest nonce expired, the client should retry with a new one
+    Stale,
+}
+
+/// `WWW-Authenticate` challenge of a rejected request, read by the
+/// error catcher
+#[derive(Default)]
+pub struct AuthChallenge(pub Option<String>);
+
+impl ApiAuth {
+    pub fn new(config: &WalletConfig) -> Result<Self> {
+        let mut keys = vec![];
+        let mut lists = vec![
+            (&config.api_keys, Scope::Admin, None),
+            (&config.read_only_api_keys, Scope::ReadOnly, None),
+        ];
+        for hosted in config.wallets.iter() {
+            lists.push((&hosted.api_keys, Scope::Admin, Some(&hosted.id)));
+            lists.push((
+                &hosted.read_only_api_keys,
+                Scope::ReadOnly,
+                Some(&hosted.id),
+            ));
+        }
+        for (list, scope, wallet) in lists {
+            if let Some(list) = list {
+                keys.extend(
+                    list.split(',')
+                        .map(|key| key.trim())
+                        .filter(|key| !key.is_empty())
+                        .map(|key| ApiKey {
+                            key: key.to_string(),
+                            scope,
+                            wallet: wallet.cloned(),
+                        }),
+                );
+            }
+        }
+        let mut logins = vec![];
+        for (login, scope) in [
+            (&config.rpc_login, Scope::Admin),
+            (&config.read_only_rpc_login, Scope::ReadOnly),
+        ] {
+            if let Some(login) = login {
+                let (user, password) = login
+                    .split_once(':')
+                    .ok_or(anyhow!("RPC login must be user:password"))?;
+                logins.push(Login {
+                    user: user.to_string(),
+                    password: password.to_string(),
+                    scope,
+                });
+            }
+        }
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Ok(ApiAuth {
+            keys,
+            logins,
+            secret,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty() || !self.logins.is_empty()
+    }
+
+    /// Scope of a request to `wallet`, the hosted wallet id or None for
+    /// the default one, given its `X-API-Key` and `Authorization` headers.
+    /// The keys of a hosted wallet are refused for the other wallets.
+    pub fn authenticate(
+        &self,
+        method: &str,
+        uri: &str,
+        wallet: Option<&str>,
+        api_key: Option<&str>,
+        authorization: Option<&str>,
+    ) -> Result<Scope, AuthFailure> {
+        let bearer = authorization.and_then(|a| a.strip_prefix("Bearer "));
+        if let Some(key) = api_key.or(bearer) {
+            return self
+                .keys
+                .iter()
+                .filter(|k| k.wallet.is_none() || k.wallet.as_deref() == wallet)
+                .find(|k| constant_time_eq(k.key.as_bytes(), key.trim().as_bytes()))
+                .map(|k| k.scope)
+                .ok_or(AuthFailure::Invalid);
+        }
+        match authorization.and_then(|a| a.strip_prefix("Digest ")) {
+            Some(params) => self.check_digest(method, uri, params),
+            None => Err(AuthFailure::Missing),
+        }
+    }
+
+    fn check_digest(&self, method: &str, uri: &str, params: &str) -> Result<Scope, AuthFailure> {
+        let params = parse_params(params);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        let (Some(user), Some(nonce), Some(digest_uri), Some(response)) = (
+            param("username"),
+            param("nonce"),
+            param("uri"),
+            param("response"),
+        ) else {
+            return Err(AuthFailure::Invalid);
+        };
+        if param("realm") != Some(REALM) || digest_uri != uri {
+            return Err(AuthFailure::Invalid);
+        }
+        let login = self
+            .logins
+            .iter()
+            .find(|l| l.user == user)
+            .ok_or(AuthFailure::Invalid)?;
+
+        let ha1 = md5_hex(&format!("{}:{REALM}:{}", login.user, login.password));
+        let ha2 = md5_hex(&format!("{method}:{digest_uri}"));
+        let expected = match param("qop") {
+            Some("auth") => {
+                let (Some(nc), Some(cnonce)) = (param("nc"), param("cnonce")) else {
+                    return Err(AuthFailure::Invalid);
+                };
+                md5_hex(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"))
+            }
+            None => md5_hex(&format!("{ha1}:{nonce}:{ha2}")),
+            Some(_) => return Err(AuthFailure::Invalid),
+        };
+        if !constant_time_eq(expected.as_bytes(), response.as_bytes()) {
+            return Err(AuthFailure::Invalid);
+        }
+        // Checked last so that only valid credentials are told to retry
+        self.check_nonce(nonce)?;
+        Ok(login.scope)
+    }
+
+    /// Nonces are a timestamp signed with the secret of the process, no
+    /// state is kept between requests
+    fn nonce_at(&self, timestamp: u64) -> String {
+        let mac = md5_hex(&format!("{timestamp:x}:{}", hex::encode(self.secret)));
+        format!("{timestamp:x}{mac}")
+    }
+
+    fn check_nonce(&self, nonce: &str) -> Result<(), AuthFailure> {
+        let timestamp = nonce
+            .get(..nonce.len().saturating_sub(32))
+            .and_then(|ts| u64::from_str_radix(ts, 16).ok())
+            .ok_or(AuthFailure::Invalid)?;
+        if !constant_time_eq(self.nonce_at(timestamp).as_bytes(), nonce.as_bytes()) {
+            return Err(AuthFailure::Invalid);
+        }
+        if now().saturating_sub(timestamp) > NONCE_LIFETIME {
+            return Err(AuthFailure::Stale);
+        }
+        Ok(())
+    }
+
+    /// `WWW-Authenticate` header value, when Digest logins are configured
+    pub fn challenge(&self, stale: bool) -> Option<String> {
+        if self.logins.is_empty() {
+            return None;
+        }
+        let stale = if stale { ", stale=true" } else { "" };
+        Some(format!(
+            "Digest realm=\"{REALM}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{stale}",
+            self.nonce_at(now())
+        ))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn md5_hex(data: &str) -> String {
+    hex::encode(Md5::digest(data.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Split `k1="v1", k2=v2` on the commas outside of quotes
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in params.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => parts.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    parts.push(current);
+    parts
+        .iter()
+        .filter_map(|part| part.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+fn authorize(request: &Request<'_>, required: Scope) -> Outcome<(), AuthFailure> {
+    let Some(auth) = request.rocket().state::<ApiAuth>() else {
+        return Outcome::Success(());
+    };
+    if !auth.is_enabled() {
+        return Outcome::Success(());
+    }
+    // Digest signs the URI sent by the client, before any rewrite
+    let prefix = request.local_cache(|| None::<WalletPrefix>);
+    let uri = match prefix {
+        Some(prefix) => prefix.uri.clone(),
+        None => request.uri().to_string(),
+    };
+    let headers = request.headers();
+    match auth.authenticate(
+        request.method().as_str(),
+        &uri,
+        prefix.as_ref().map(|prefix| prefix.id.as_str()),
+        headers.get_one("X-API-Key"),
+        headers.get_one("Authorization"),
+    ) {
+        Ok(scope) if scope >= required => Outcome::Success(()),
+        Ok(_) => Outcome::Error((Status::Forbidden, AuthFailure::Invalid)),
+        Err(failure) => {
+            let stale = failure == AuthFailure::Stale;
+            request.local_cache(|| AuthChallenge(auth.challenge(stale)));
+            Outcome::Error((Status::Unauthorized, failure))
+        }
+    }
+}
+
+/// Guard of the routes that only read the wallet
+pub struct ReadAccess;
+
+/// Guard of the routes that create accounts and addresses or drop
+/// scanned data
+pub struct AdminAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ReadAccess {
+    type Error = AuthFailure;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, Scope::ReadOnly).map(|_| ReadAccess)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAccess {
+    type Error = AuthFailure;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, Scope::Admin).map(|_| AdminAccess)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_auth() -> ApiAuth {
+        ApiAuth {
+            keys: vec![
+                ApiKey {
+                    key: "admin-key".to_string(),
+                    scope: Scope::Admin,
+                    wallet: None,
+                },
+                ApiKey {
+                    key: "shop-key".to_string(),
+                    scope: Scope::ReadOnly,
+                    wallet: Some("shop".to_string()),
+                },
+            ],
+            logins: vec![Login {
+                user: "shop".to_string(),
+                password: "secret".to_string(),
+                scope: Scope::ReadOnly,
+            }],
+            secret: [3u8; 32],
+        }
+    }
+
+    fn digest_header(nonce: &str, password: &str) -> String {
+        let ha1 = md5_hex(&format!("shop:{REALM}:{password}"));
+        let ha2 = md5_hex("POST:/get_transfers");
+        let response = md5_hex(&format!("{ha1}:{nonce}:00000001:abcd:auth:{ha2}"));
+        format!(
+            "Digest username=\"shop\", realm=\"{REALM}\", nonce=\"{nonce}\", \
+            uri=\"/get_transfers\", qop=auth, nc=00000001, cnonce=\"abcd\", \
+            response=\"{response}\""
+        )
+    }
+
+    #[test]
+    fn api_keys() {
+        let auth = test_auth();
+        let check = |wallet, key, bearer: Option<&str>| {
+            auth.authenticate("POST", "/rewind", wallet, key, bearer)
+        };
+        assert_eq!(check(None, Some("admin-key"), None), Ok(Scope::Admin));
+        assert_eq!(
+            check(Some("shop"), Some("admin-key"), None),
+            Ok(Scope::Admin)
+        );
+        assert_eq!(
+            check(Some("shop"), None, Some("Bearer shop-key")),
+            Ok(Scope::ReadOnly)
+        );
+        assert_eq!(check(Some("other"), None, None), Err(AuthFailure::Missing));
+        assert_eq!(check(None, Some("other"), None), Err(AuthFailure::Invalid));
+
+        // Keys of a hosted wallet only open that wallet
+        assert_eq!(
+            check(None, Some("shop-key"), None),
+            Err(AuthFailure::Invalid)
+        );
+        assert_eq!(
+            check(Some("cafe"), Some("shop-key"), None),
+            Err(AuthFailure::Invalid)
+        );
+    }
+
+    #[test]
+    fn digest() {
+        let auth = test_auth();
+        let nonce = auth.nonce_at(now());
+        let check =
+            |header: &str| auth.authenticate("POST", "/get_transfers", None, None, Some(header));
+        assert_eq!(check(&digest_header(&nonce, "secret")), Ok(Scope::ReadOnly));
+        assert_eq!(
+            check(&digest_header(&nonce, "wrong")),
+            Err(AuthFailure::Invalid)
+        );
+
+        // Forged and expired nonces
+        let forged = format!("{:x}{}", now(), md5_hex("forged"));
+        assert_eq!(
+            check(&digest_header(&forged, "secret")),
+            Err(AuthFailure::Invalid)
+        );
+        let expired = auth.nonce_at(now() - NONCE_LIFETIME - 1);
+        assert_eq!(
+            check(&digest_header(&expired, "secret")),
+            Err(AuthFailure::Stale)
+        );
+
+        // Signed for another route
+        let header = digest_header(&nonce, "secret");
+        assert_eq!(
+            auth.authenticate("POST", "/rewind", None, None, Some(&header)),
+            Err(AuthFailure::Invalid)
+        );
+        assert!(auth.challenge(false).unwrap().starts_with("Digest realm="));
+    }
+}
@@ -0,0 +1,353 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use prost::Message;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteRow},
+    Row, SqlitePool,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tracing::info;
+
+use crate::{
+    chain::{BlockStream, ChainInfo, ChainSource},
+    lwd::LwdServerStatus,
+    lwd_rpc::{BlockId, CompactBlock, TreeState},
+    metrics::BLOCK_CACHE_READS,
+    Hash, SAFE_REORG_DISTANCE,
+};
+
+/// Number of blocks read from the cache or the backend at a time
+const BATCH_SIZE: u32 = 1000;
+
+/// Compact blocks stored by height in their own SQLite database
+pub struct BlockCache {
+    pool: SqlitePool,
+}
+
+impl BlockCache {
+    pub async fn open(path: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS compact_blocks (
+            height INTEGER PRIMARY KEY,
+            hash BLOB NOT NULL,
+            data BLOB NOT NULL)",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(BlockCache { pool })
+    }
+
+    /// Height and hash of the highest cached block
+    pub async fn tip(&self) -> Result<Option<(u32, Hash)>> {
+        let tip =
+            sqlx::query("SELECT height, hash FROM compact_blocks ORDER BY height DESC LIMIT 1")
+                .map(|r: SqliteRow| (r.get::<u32, _>(0), r.get::<Vec<u8>, _>(1)))
+                .fetch_optional(&self.pool)
+                .await?;
+        let tip = match tip {
+            Some((height, hash)) => Some((height, hash.try_into().unwrap())),
+            None => None,
+        };
+        Ok(tip)
+    }
+
+    /// Cached blocks from `start` up to `end`, stopping at the first
+    /// missing height
+    pub async fn get_blocks(&self, start: u32, end: u32) -> Result<Vec<CompactBlock>> {
+        let rows = sqlx::query(
+            "SELECT height, data FROM compact_blocks
+            WHERE height >= ?1 AND height <= ?2 ORDER BY height",
+        )
+        .bind(start)
+        .bind(end)
+        .map(|r: SqliteRow| (r.get::<u32, _>(0), r.get::<Vec<u8>, _>(1)))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut blocks = vec![];
+        for (height, data) in rows {
+            if height != start + blocks.len() as u32 {
+                break;
+            }
+            blocks.push(CompactBlock::decode(&*data)?);
+        }
+        Ok(blocks)
+    }
+
+    pub async fn store_blocks(&self, blocks: &[CompactBlock]) -> Result<()> {
+        let mut db_tx = self.pool.begin().await?;
+        for block in blocks {
+            let mut data = vec![];
+            block.encode(&mut data)?;
+            sqlx::query(
+                "INSERT INTO compact_blocks(height, hash, data) VALUES (?1, ?2, ?3)
+                ON CONFLICT (height) DO UPDATE SET hash = excluded.hash, data = excluded.data",
+            )
+            .bind(block.height as u32)
+            .bind(&block.hash)
+            .bind(data)
+            .execute(&mut *db_tx)
+            .await?;
+        }
+        db_tx.commit().await?;
+        Ok(())
+    }
+
+    /// Drop the blocks above `height`
+    pub async fn truncate(&self, height: u32) -> Result<()> {
+        sqlx::query("DELETE FROM compact_blocks WHERE height > ?1")
+            .bind(height)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Drop the blocks below `height`
+    pub async fn prune(&self, height: u32) -> Result<()> {
+        sqlx::query("DELETE FROM compact_blocks WHERE height < ?1")
+            .bind(height)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Chain source that serves compact blocks from a `BlockCache` and
+/// stores the blocks it has to download
+pub struct CachedSource {
+    inner: Arc<dyn ChainSource>,
+    cache: Arc<BlockCache>,
+}
+
+impl CachedSource {
+    pub fn new(inner: Arc<dyn ChainSource>, cache: Arc<BlockCache>) -> Self {
+        CachedSource { inner, cache }
+    }
+
+    /// Download the blocks above the cache tip, or above `from` when the
+    /// cache is empty, every `interval`
+    pub fn spawn_prefetch(self: &Arc<Self>, from: u32, interval: Duration) -> JoinHandle<()> {
+        let source = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = source.prefetch(from).await {
+                    log::warn!("Block prefetch failed: {e:#}");
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    async fn prefetch(&self, from: u32) -> Result<()> {
+        let tip = self.inner.latest_height().await?;
+        loop {
+            let (start, mut prev_hash) = match self.cache.tip().await? {
+                Some((height, hash)) => (height + 1, Some(hash)),
+                None => (from + 1, None),
+            };
+            if start > tip {
+                return Ok(());
+            }
+            let end = tip.min(start + BATCH_SIZE - 1);
+            let mut blocks = self.inner.block_range(start, end).await?;
+            let mut batch = vec![];
+            while let Some(block) = blocks.next().await {
+                let block = block?;
+                if let Some(prev_hash) = prev_hash {
+                    if block.prev_hash != prev_hash {
+                        // The cached tip was orphaned
+                        let height = (block.height as u32).saturating_sub(SAFE_REORG_DISTANCE);
+                        info!(
+                            "Block cache reorg at {}, truncating to {height}",
+                            block.height
+                        );
+                        self.cache.store_blocks(&batch).await?;
+                        self.cache.truncate(height).await?;
+                        return Ok(());
+                    }
+                }
+                prev_hash = Some(block.hash.clone().try_into().unwrap());
+                batch.push(block);
+            }
+            self.cache.store_blocks(&batch).await?;
+        }
+    }
+}
+
+#[async_trait]
+impl ChainSource for CachedSource {
+    async fn latest_block(&self) -> Result<BlockId> {
+        self.inner.latest_block().await
+    }
+
+    async fn chain_info(&self) -> Result<ChainInfo> {
+        self.inner.chain_info().await
+    }
+
+    /// Cached blocks first, then the rest from the backend. Blocks are
+    /// not checked against each other here, the scanner detects a stale
+    /// cache as a reorg and the rewind invalidates it.
+    async fn block_range(&self, start: u32, end: u32) -> Result<BlockStream> {
+        let (tx, rx) = mpsc::channel(BATCH_SIZE as usize);
+        let inner = self.inner.clone();
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            let mut height = start;
+            while height <= end {
+                let batch_end = end.min(height + BATCH_SIZE - 1);
+                let blocks = match cache.get_blocks(height, batch_end).await {
+                    Ok(blocks) if !blocks.is_empty() => blocks,
+                    Ok(_) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                };
+                BLOCK_CACHE_READS
+                    .with_label_values(&["hit"])
+                    .inc_by(blocks.len() as u64);
+                height += blocks.len() as u32;
+                for block in blocks {
+                    if tx.send(Ok(block)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            if height > end {
+                return;
+            }
+
+            let mut blocks = match inner.block_range(height, end).await {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let mut batch = vec![];
+            while let Some(block) = blocks.next().await {
+                let failed = block.is_err();
+                if let Ok(block) = &block {
+                    BLOCK_CACHE_READS.with_label_values(&["miss"]).inc();
+                    batch.push(block.clone());
+                }
+                if batch.len() >= BATCH_SIZE as usize {
+                    if let Err(e) = cache.store_blocks(&batch).await {
+                        log::warn!("Failed to cache blocks: {e:#}");
+                    }
+                    batch.clear();
+                }
+                if tx.send(block).await.is_err() || failed {
+                    break;
+                }
+            }
+            if let Err(e) = cache.store_blocks(&batch).await {
+                log::warn!("Failed to cache blocks: {e:#}");
+            }
+        });
+        Ok(Box::pin(ReceiverStream::new(rx)))
+    }
+
+    async fn tree_state(&self, height: u32) -> Result<TreeState> {
+        self.inner.tree_state(height).await
+    }
+
+    async fn raw_transaction(&self, txid: &Hash) -> Result<Vec<u8>> {
+        self.inner.raw_transaction(txid).await
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Hash> {
+        match self.cache.get_blocks(height, height).await?.pop() {
+            Some(block) => Ok(block.hash.try_into().unwrap()),
+            None => self.inner.block_hash(height).await,
+        }
+    }
+
+    async fn rewind(&self, height: u32) -> Result<()> {
+        self.cache.truncate(height).await?;
+        self.inner.rewind(height).await
+    }
+
+    fn servers(&self) -> Vec<LwdServerStatus> {
+        self.inner.servers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockLightwalletd, TestChain};
+
+    async fn open_cache(name: &str) -> Result<Arc<BlockCache>> {
+        let path = std::env::temp_dir().join(format!("zcash-walletd-{name}.cache.db"));
+        let _ = std::fs::remove_file(&path);
+        Ok(Arc::new(BlockCache::open(path.to_str().unwrap()).await?))
+    }
+
+    async fn hashes(source: &dyn ChainSource, start: u32, end: u32) -> Result<Vec<Vec<u8>>> {
+        let blocks = source.block_range(start, end).await?;
+        blocks.map(|b| b.map(|b| b.hash)).collect().await
+    }
+
+    #[tokio::test]
+    async fn prefetch_and_serve_from_cache() -> Result<()> {
+        let mut chain = TestChain::new(100, 21);
+        chain.add_empty_blocks(10);
+        let lwd = MockLightwalletd::new(chain);
+        let cache = open_cache("prefetch").await?;
+        let source = CachedSource::new(Arc::new(lwd.clone()), cache.clone());
+
+        source.prefetch(100).await?;
+        assert_eq!(cache.tip().await?.map(|(h, _)| h), Some(110));
+
+        // Served from the cache even when the backend lost the blocks
+        let expected = hashes(&lwd, 101, 110).await?;
+        lwd.chain().fork(105);
+        assert_eq!(hashes(&source, 101, 110).await?, expected);
+
+        // A rewind invalidates the cache above the height
+        source.rewind(105).await?;
+        lwd.chain().add_empty_blocks(5);
+        let refetched = hashes(&source, 101, 110).await?;
+        assert_eq!(refetched[..5], expected[..5]);
+        assert_ne!(refetched[5..], expected[5..]);
+        assert_eq!(cache.get_blocks(101, 110).await?.len(), 10);
+
+        cache.prune(105).await?;
+        assert!(cache.get_blocks(101, 110).await?.is_empty());
+        assert_eq!(cache.get_blocks(105, 110).await?.len(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prefetch_truncates_orphaned_blocks() -> Result<()> {
+        let mut chain = TestChain::new(100, 22);
+        chain.add_empty_blocks(3);
+        let lwd = MockLightwalletd::new(chain);
+        let cache = open_cache("prefetch-reorg").await?;
+        let source = CachedSource::new(Arc::new(lwd.clone()), cache.clone());
+        source.prefetch(100).await?;
+
+        {
+            let mut chain = lwd.chain();
+            chain.fork(101);
+            chain.add_empty_blocks(3);
+        }
+        source.prefetch(100).await?;
+        assert!(cache.tip().await?.is_none());
+        source.prefetch(100).await?;
+        assert_eq!(cache.tip().await?.map(|(h, _)| h), Some(104));
+        assert_eq!(
+            hashes(&source, 101, 104).await?,
+            hashes(&lwd, 101, 104).await?
+        );
+        Ok(())
+    }
+}
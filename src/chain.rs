@@ -0,0 +1,58 @@
+use std::pin::Pin;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio_stream::Stream;
+
+use crate::{
+    lwd::LwdServerStatus,
+    lwd_rpc::{BlockId, CompactBlock, TreeState},
+    Hash,
+};
+
+pub type BlockStream = Pin<Box<dyn Stream<Item = Result<CompactBlock>> + Send>>;
+
+pub struct ChainInfo {
+    /// Height of the best block known to the backend
+    pub height: u32,
+    /// Estimated height of the network, larger while the backend syncs
+    pub estimated_height: u32,
+}
+
+/// Where the scanner gets its blocks and transactions from.
+///
+/// Hashes and txids are in internal byte order, i.e. reversed compared
+/// to block explorers, like in the compact block format.
+#[async_trait]
+pub trait ChainSource: Send + Sync + 'static {
+    /// Height and hash of the chain tip
+    async fn latest_block(&self) -> Result<BlockId>;
+
+    async fn chain_info(&self) -> Result<ChainInfo>;
+
+    /// Compact blocks from `start` to `end` inclusive
+    async fn block_range(&self, start: u32, end: u32) -> Result<BlockStream>;
+
+    /// Note commitment trees at the end of the block at `height`
+    async fn tree_state(&self, height: u32) -> Result<TreeState>;
+
+    /// Serialized transaction
+    async fn raw_transaction(&self, txid: &Hash) -> Result<Vec<u8>>;
+
+    async fn block_hash(&self, height: u32) -> Result<Hash>;
+
+    /// Forget any data kept above `height`, after a reorg
+    async fn rewind(&self, _height: u32) -> Result<()> {
+        Ok(())
+    }
+
+    async fn latest_height(&self) -> Result<u32> {
+        let block_id = self.latest_block().await?;
+        Ok(block_id.height as u32)
+    }
+
+    /// State of each lightwalletd server, empty for other backends
+    fn servers(&self) -> Vec<LwdServerStatus> {
+        vec![]
+    }
+}
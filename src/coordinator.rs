@@ -0,0 +1,165 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Number of finished jobs kept around for `/get_scan_job`
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ScanJobState {
+    Pending,
+    Running,
+    Done { blocks: u32 },
+    Failed { error: String },
+}
+
+impl ScanJobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            ScanJobState::Done { .. } | ScanJobState::Failed { .. }
+        )
+    }
+}
+
+/// Handle on a scan submitted to the `ScanCoordinator`
+pub struct ScanJob {
+    pub id: u64,
+    rx: watch::Receiver<ScanJobState>,
+}
+
+impl ScanJob {
+    pub fn state(&self) -> ScanJobState {
+        self.rx.borrow().clone()
+    }
+
+    /// Wait until the scan has completed or failed
+    pub async fn wait(mut self) -> ScanJobState {
+        loop {
+            let state = self.state();
+            if state.is_finished() {
+                return state;
+            }
+            if self.rx.changed().await.is_err() {
+                return self.state();
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    running: bool,
+    pending: Option<(u64, watch::Sender<ScanJobState>)>,
+    jobs: HashMap<u64, watch::Receiver<ScanJobState>>,
+    finished: VecDeque<u64>,
+}
+
+/// Runs at most one scan at a time.
+///
+/// Requests made while a scan is running are coalesced into a single
+/// follow-up scan that all of them await, so that every caller sees
+/// the blocks mined before its request.
+#[derive(Default)]
+pub struct ScanCoordinator {
+    state: Mutex<State>,
+    /// Held by whoever modifies the synced blocks: a scan or a rewind
+    pub(crate) lock: tokio::sync::Mutex<()>,
+    rewound: tokio::sync::Notify,
+}
+
+impl ScanCoordinator {
+    /// Queue a scan. Returns the job and whether the caller must start
+    /// a driver, i.e. no scan is currently running.
+    pub fn submit(&self) -> (ScanJob, bool) {
+        let mut state = self.state.lock().unwrap();
+        let id = match &state.pending {
+            Some((id, _)) => *id,
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                let (tx, rx) = watch::channel(ScanJobState::Pending);
+                state.jobs.insert(id, rx);
+                state.pending = Some((id, tx));
+                id
+            }
+        };
+        let rx = state.jobs[&id].clone();
+        let start_driver = !state.running;
+        state.running = true;
+        (ScanJob { id, rx }, start_driver)
+    }
+
+    /// Take the next pending job. When there is none, the driver
+    /// must stop.
+    pub fn next_job(&self) -> Option<(u64, watch::Sender<ScanJobState>)> {
+        let mut state = self.state.lock().unwrap();
+        let job = state.pending.take();
+        match &job {
+            Some((id, _)) => {
+                state.finished.push_back(*id);
+                while state.finished.len() > MAX_FINISHED_JOBS {
+                    if let Some(id) = state.finished.pop_front() {
+                        state.jobs.remove(&id);
+                    }
+                }
+            }
+            None => state.running = false,
+        }
+        job
+    }
+
+    pub fn job_state(&self, id: u64) -> Option<ScanJobState> {
+        let state = self.state.lock().unwrap();
+        state.jobs.get(&id).map(|rx| rx.borrow().clone())
+    }
+
+    /// Record a rewind of the synced blocks. The tip watcher wakes up,
+    /// even if it only waits for it later.
+    pub fn notify_rewind(&self) {
+        self.rewound.notify_one();
+    }
+
+    /// Wait for the next rewind
+    pub async fn rewound(&self) {
+        self.rewound.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_scan() {
+        let coordinator = ScanCoordinator::default();
+        let (first, start_driver) = coordinator.submit();
+        assert!(start_driver);
+        let (_, tx) = coordinator.next_job().unwrap();
+        tx.send(ScanJobState::Running).unwrap();
+
+        // Both requests arrive while the first scan runs
+        let (second, start_driver) = coordinator.submit();
+        assert!(!start_driver);
+        let (third, _) = coordinator.submit();
+        assert_eq!(second.id, third.id);
+        assert_ne!(first.id, second.id);
+
+        tx.send(ScanJobState::Done { blocks: 1 }).unwrap();
+        assert_eq!(first.wait().await, ScanJobState::Done { blocks: 1 });
+
+        let (id, tx) = coordinator.next_job().unwrap();
+        assert_eq!(id, second.id);
+        tx.send(ScanJobState::Done { blocks: 2 }).unwrap();
+        assert_eq!(second.wait().await, ScanJobState::Done { blocks: 2 });
+        assert_eq!(third.wait().await, ScanJobState::Done { blocks: 2 });
+
+        assert!(coordinator.next_job().is_none());
+        let (_, start_driver) = coordinator.submit();
+        assert!(start_driver);
+    }
+}
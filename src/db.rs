@@ -1,67 +1,191 @@
-use crate::account::{Account, AccountBalance, SubAccount};
-use crate::lwd_rpc::BlockId;
+use crate::account::{
+    Account, AccountBalance, HistoricalBalance, LatePayment, SubAccount, SubAccountBalance,
+};
+use crate::chain::ChainSource;
+use crate::error::WalletError;
+use crate::export::ExportRow;
+use crate::keys::ViewingKey;
+use crate::metrics::{pool_label, ADDRESS_POOL_MISSES};
 use crate::network::Network;
 use crate::notifier::TxNotifier;
-use crate::scan::ScanEvent;
+use crate::scan::{ReceivedNote, ScanEvent};
 use crate::transaction::{SubAddress, Transfer};
-use crate::{Client, Hash};
-use anyhow::Result;
+use crate::{AddressOptions, Hash};
+use anyhow::{anyhow, Result};
 use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
 use sqlx::{Acquire, Row, SqliteConnection, SqlitePool};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tonic::Request;
+use tokio::sync::{Mutex, Notify};
 use zcash_keys::address::UnifiedAddress;
 use zcash_keys::encoding::AddressCodec;
-use zcash_keys::keys::{UnifiedAddressRequest, UnifiedFullViewingKey};
 use zcash_protocol::consensus::{NetworkUpgrade, Parameters};
 
+/// Number of stored addresses derived again at startup
+const ADDRESS_CHECK_SAMPLE: u32 = 20;
+
+/// Label of the addresses of the unassigned account
+const UNASSIGNED_LABEL: &str = "unassigned";
+
+/// Lookups of the fiat rate of a transaction before giving up, the delay
+/// between two of them doubling from `RATE_RETRY_DELAY_SECS`
+const RATE_MAX_ATTEMPTS: u32 = 10;
+const RATE_RETRY_DELAY_SECS: i64 = 60;
+
+/// Columns read by the export, which `create` adds to older databases,
+/// and the properties that record the data migrations
+const EXPORT_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "received_notes",
+        &[
+            "id_note",
+            "address",
+            "account",
+            "sub_account",
+            "id_tx",
+            "pool",
+            "height",
+            "value",
+            "memo",
+            "spent",
+            "spent_id_tx",
+        ],
+    ),
+    (
+        "transactions",
+        &["id_tx", "txid", "timestamp", "rate", "fee"],
+    ),
+    ("receivers", &["id_address", "receiver_address"]),
+    ("addresses", &["id_address", "label", "address"]),
+    ("blocks", &["height"]),
+    ("properties", &["name", "value"]),
+];
+
+const RECEIVED_NOTES_TABLE: &str = "CREATE TABLE IF NOT EXISTS received_notes (
+    id_note INTEGER PRIMARY KEY,
+    address TEXT NOT NULL,
+    account INTEGER,
+    sub_account INTEGER,
+    id_tx INTEGER NOT NULL,
+    pool INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    diversifier BLOB NOT NULL,
+    value INTEGER NOT NULL,
+    rcm BLOB NOT NULL,
+    nf BLOB UNIQUE,
+    rho BLOB,
+    memo TEXT,
+    spent INTEGER,
+    spent_id_tx INTEGER,
+    late BOOL NOT NULL DEFAULT FALSE,
+    CONSTRAINT tx_output UNIQUE (pool, position))";
+
+/// Viewing key imported at runtime as its own account
+pub struct ImportedKey {
+    pub account: u32,
+    pub key: ViewingKey,
+    pub birth_height: u32,
+    /// Last block scanned for the key, and its hash
+    pub synced_height: u32,
+    pub synced_hash: Hash,
+}
+
 pub struct Db {
     network: Network,
     pool: SqlitePool,
-    ufvk: UnifiedFullViewingKey,
+    key: ViewingKey,
     notifier: Option<Arc<dyn TxNotifier>>,
     address_creation_lock: Mutex<()>,
+    address_reserved: Notify,
+    transactions_stored: Notify,
 }
 
 impl Db {
     pub async fn new(
         network: Network,
         db_path: &str,
-        ufvk: &UnifiedFullViewingKey,
+        key: &ViewingKey,
         notifier: Option<Arc<dyn TxNotifier>>,
     ) -> Result<Self> {
         let options = SqliteConnectOptions::new()
             .filename(db_path)
             .create_if_missing(true);
         let pool = SqlitePool::connect_with(options).await?;
-        Ok(Db {
+        Ok(Self::with_pool(network, pool, key, notifier))
+    }
+
+    fn with_pool(
+        network: Network,
+        pool: SqlitePool,
+        key: &ViewingKey,
+        notifier: Option<Arc<dyn TxNotifier>>,
+    ) -> Self {
+        Db {
             network,
             pool,
-            ufvk: ufvk.clone(),
+            key: key.clone(),
             notifier,
             address_creation_lock: Mutex::new(()),
-        })
+            address_reserved: Notify::new(),
+            transactions_stored: Notify::new(),
+        }
+    }
+
+    /// Open an existing database for the export, without creating or
+    /// migrating it. Fails if a column read by the export is missing or
+    /// the spent heights were not migrated: the wallet must run once on
+    /// the database first.
+    pub async fn open_read_only(network: Network, db_path: &str, key: &ViewingKey) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(db_path)
+            .read_only(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        let mut connection = pool.acquire().await?;
+        for (table, columns) in EXPORT_COLUMNS {
+            let present = sqlx::query(&format!("SELECT name FROM pragma_table_info('{table}')"))
+                .map(|r: SqliteRow| r.get::<String, _>(0))
+                .fetch_all(&mut *connection)
+                .await?;
+            if let Some(missing) = columns.iter().find(|c| !present.iter().any(|p| p == *c)) {
+                anyhow::bail!(
+                    "The database has no column {table}.{missing}, start the wallet once to \
+                    upgrade it"
+                );
+            }
+        }
+        if sqlx::query("SELECT 1 FROM properties WHERE name = 'spent_heights'")
+            .fetch_optional(&mut *connection)
+            .await?
+            .is_none()
+        {
+            anyhow::bail!("The database was not upgraded, start the wallet once to upgrade it");
+        }
+        drop(connection);
+        Ok(Self::with_pool(network, pool, key, None))
     }
 
-    pub async fn new_account(&self, name: &str) -> Result<Account> {
+    pub async fn new_account(&self, name: &str, options: &AddressOptions) -> Result<Account> {
         let _guard = self.address_creation_lock.lock().await;
         let mut connection = self.pool.acquire().await?;
         let (id_account,): (Option<u32>,) = sqlx::query_as("SELECT MAX(account) FROM addresses")
             .fetch_one(&mut *connection)
             .await?;
         let id_account = id_account.map(|id| id + 1).unwrap_or(0);
-        let (diversifier_index, address) = self.next_diversifier(&mut connection).await?;
-        self.store_receivers(
-            &mut connection,
-            name,
-            id_account,
-            0,
-            diversifier_index,
-            &address,
-        )
-        .await?;
+        let (diversifier_index, address) = self
+            .next_diversifier(&mut connection, id_account, options)
+            .await?;
+        let address = self
+            .store_receivers(
+                &mut connection,
+                name,
+                id_account,
+                0,
+                diversifier_index,
+                &address,
+                options,
+            )
+            .await?;
 
         let account = Account {
             account_index: id_account,
@@ -70,34 +194,155 @@ impl Db {
         Ok(account)
     }
 
-    pub async fn new_sub_account(&self, id_account: u32, name: &str) -> Result<SubAccount> {
+    /// New address of `id_account`. After `expires_at`, it becomes inactive
+    /// if it was not paid.
+    pub async fn new_sub_account(
+        &self,
+        id_account: u32,
+        name: &str,
+        options: &AddressOptions,
+        expires_at: Option<u64>,
+    ) -> Result<SubAccount> {
         let _guard = self.address_creation_lock.lock().await;
         let mut connection = self.pool.acquire().await?;
-        let (id_sub_account,): (u32,) =
+        let (id_sub_account,): (Option<u32>,) =
             sqlx::query_as("SELECT MAX(sub_account) FROM addresses WHERE account = ?1")
                 .bind(id_account)
                 .fetch_one(&mut *connection)
                 .await?;
-        let id_sub_account = id_sub_account + 1;
-        let (diversifier_index, address) = self.next_diversifier(&mut connection).await?;
-        self.store_receivers(
-            &mut connection,
-            name,
-            id_account,
-            id_sub_account,
-            diversifier_index,
-            &address,
-        )
-        .await?;
+        let id_sub_account = id_sub_account.ok_or(WalletError::NotFound(format!(
+            "Unknown account {id_account}"
+        )))? + 1;
+        if Self::get_unassigned_account(&mut connection).await? == Some(id_account) {
+            return Err(WalletError::InvalidArgument(
+                "Addresses cannot be created in the unassigned account".to_string(),
+            )
+            .into());
+        }
+        let (diversifier_index, address) = self
+            .next_diversifier(&mut connection, id_account, options)
+            .await?;
+        let address = self
+            .store_receivers(
+                &mut connection,
+                name,
+                id_account,
+                id_sub_account,
+                diversifier_index,
+                &address,
+                options,
+            )
+            .await?;
+        sqlx::query("UPDATE addresses SET expires_at = ?3 WHERE account = ?1 AND sub_account = ?2")
+            .bind(id_account)
+            .bind(id_sub_account)
+            .bind(expires_at.map(|t| t as i64))
+            .execute(&mut *connection)
+            .await?;
 
         let sub_account = SubAccount {
             account_index: id_account,
             sub_account_index: id_sub_account,
             address,
+            expires_at,
+            active: true,
         };
         Ok(sub_account)
     }
 
+    /// Add `key` as a new account. Its scan starts after `birth_height`,
+    /// whose hash is `birth_hash`.
+    pub async fn import_viewing_key(
+        &self,
+        key: &ViewingKey,
+        name: &str,
+        birth_height: u32,
+        birth_hash: &Hash,
+    ) -> Result<Account> {
+        let _guard = self.address_creation_lock.lock().await;
+        let imported = self.get_viewing_keys().await?;
+        if key.same_as(&self.key, &self.network)
+            || imported.iter().any(|k| k.key.same_as(key, &self.network))
+        {
+            return Err(WalletError::InvalidArgument(
+                "The viewing key is already in the wallet".to_string(),
+            )
+            .into());
+        }
+
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+        let (id_account,): (Option<u32>,) = sqlx::query_as("SELECT MAX(account) FROM addresses")
+            .fetch_one(&mut *db_tx)
+            .await?;
+        let id_account = id_account.map(|id| id + 1).unwrap_or(0);
+        sqlx::query(
+            "INSERT INTO viewing_keys(account, vk, birth_height, synced_height, synced_hash)
+            VALUES (?1, ?2, ?3, ?3, ?4)",
+        )
+        .bind(id_account)
+        .bind(key.encode(&self.network))
+        .bind(birth_height)
+        .bind(birth_hash.as_slice())
+        .execute(&mut *db_tx)
+        .await?;
+        let options = AddressOptions::default();
+        let (diversifier_index, address) =
+            self.next_diversifier(db_tx, id_account, &options).await?;
+        let address = self
+            .store_receivers(
+                db_tx,
+                name,
+                id_account,
+                0,
+                diversifier_index,
+                &address,
+                &options,
+            )
+            .await?;
+        db_transaction.commit().await?;
+
+        let account = Account {
+            account_index: id_account,
+            address,
+        };
+        Ok(account)
+    }
+
+    pub async fn get_viewing_keys(&self) -> Result<Vec<ImportedKey>> {
+        let mut connection = self.pool.acquire().await?;
+        let rows = sqlx::query(
+            "SELECT account, vk, birth_height, synced_height, synced_hash
+            FROM viewing_keys ORDER BY account",
+        )
+        .map(|r: SqliteRow| {
+            let account: u32 = r.get(0);
+            let vk: String = r.get(1);
+            let birth_height: u32 = r.get(2);
+            let synced_height: u32 = r.get(3);
+            let synced_hash: Vec<u8> = r.get(4);
+            (account, vk, birth_height, synced_height, synced_hash)
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+
+        let mut keys = vec![];
+        for (account, vk, birth_height, synced_height, synced_hash) in rows {
+            keys.push(ImportedKey {
+                account,
+                key: ViewingKey::decode(&self.network, &vk)?,
+                birth_height,
+                synced_height,
+                synced_hash: synced_hash.try_into().unwrap(),
+            });
+        }
+        Ok(keys)
+    }
+
+    /// Store the address `ua` with its shielded receivers. Returns its
+    /// encoding: a Sapling address for a legacy address type.
+    #[allow(clippy::too_many_arguments)]
     async fn store_receivers(
         &self,
         connection: &mut SqliteConnection,
@@ -105,19 +350,28 @@ impl Db {
         id_account: u32,
         id_sub_account: u32,
         diversifier_index: u64,
-        address: &str,
-    ) -> Result<()> {
-        let r = sqlx::query("INSERT INTO addresses(label, account, sub_account, address, diversifier_index) VALUES (?1,?2,?3,?4,?5)")
-            .bind(name)
-            .bind(id_account)
-            .bind(id_sub_account)
-            .bind(address)
-            .bind(diversifier_index as i64)
-            .execute(&mut *connection)
-            .await?;
+        ua: &UnifiedAddress,
+        options: &AddressOptions,
+    ) -> Result<String> {
+        let address = match ua.sapling() {
+            Some(sapling) if options.is_legacy() => sapling.encode(&self.network),
+            _ => ua.encode(&self.network),
+        };
+        let r = sqlx::query(
+            "INSERT INTO addresses(label, account, sub_account, address, diversifier_index,
+            address_type, transparent) VALUES (?1,?2,?3,?4,?5,?6,?7)",
+        )
+        .bind(name)
+        .bind(id_account)
+        .bind(id_sub_account)
+        .bind(&address)
+        .bind(diversifier_index as i64)
+        .bind(options.address_type.as_str())
+        .bind(options.transparent)
+        .execute(&mut *connection)
+        .await?;
         let id_address = r.last_insert_rowid() as u32;
 
-        let ua = UnifiedAddress::decode(&self.network, address).unwrap();
         if let Some(address) = ua.sapling() {
             sqlx::query(
                 "INSERT INTO receivers(pool, id_address, receiver_address)
@@ -140,16 +394,283 @@ impl Db {
             .await?;
         }
 
+        Ok(address)
+    }
+
+    /// Account and sub-account of a receiver not in `receivers`. It is
+    /// added to the address generated at its diversifier index, if any.
+    /// Otherwise it becomes an orphan address of the unassigned account,
+    /// or of the imported account `account`.
+    async fn assign_receiver(
+        &self,
+        connection: &mut SqliteConnection,
+        account: Option<u32>,
+        note: &ReceivedNote,
+    ) -> Result<(u32, u32)> {
+        let index = note.diversifier_index.and_then(|di| u64::try_from(di).ok());
+        if let Some(index) = index.filter(|&i| i <= i64::MAX as u64) {
+            let key = self.account_key(&mut *connection, account).await?;
+            if key.receiver(&self.network, note.pool, index).as_ref() == Some(&note.address) {
+                let generated = sqlx::query(
+                    "SELECT id_address, account, sub_account FROM addresses
+                    WHERE diversifier_index = ?1 AND NOT orphan AND CASE
+                    WHEN ?2 IS NULL THEN account NOT IN (SELECT account FROM viewing_keys)
+                    ELSE account = ?2 END
+                    ORDER BY id_address LIMIT 1",
+                )
+                .bind(index as i64)
+                .bind(account)
+                .map(|r: SqliteRow| (r.get::<u32, _>(0), r.get::<u32, _>(1), r.get::<u32, _>(2)))
+                .fetch_optional(&mut *connection)
+                .await?;
+                if let Some((id_address, account, sub_account)) = generated {
+                    Self::insert_receiver(connection, note.pool, id_address, &note.address).await?;
+                    return Ok((account, sub_account));
+                }
+            }
+        }
+
+        let (account, label) = match account {
+            Some(account) => (account, ""),
+            None => {
+                // Never hand out an address that was already paid. The row
+                // stays so that the pool is not refilled at its index.
+                if let Some(index) = index {
+                    sqlx::query("UPDATE address_pool SET paid = TRUE WHERE diversifier_index = ?1")
+                        .bind(index as i64)
+                        .execute(&mut *connection)
+                        .await?;
+                }
+                (
+                    self.unassigned_account(&mut *connection).await?,
+                    UNASSIGNED_LABEL,
+                )
+            }
+        };
+        let sub_account = sqlx::query("SELECT MAX(sub_account) FROM addresses WHERE account = ?1")
+            .bind(account)
+            .map(|r: SqliteRow| {
+                let sub_account: Option<u32> = r.get(0);
+                sub_account.map(|x| x + 1).unwrap_or_default()
+            })
+            .fetch_one(&mut *connection)
+            .await?;
+        let r = sqlx::query(
+            "INSERT INTO addresses
+            (label, account, sub_account, address, diversifier_index, full_diversifier_index,
+            address_type, orphan)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6,
+            CASE ?7 WHEN 1 THEN 'sapling' ELSE 'orchard' END, TRUE)",
+        )
+        .bind(label)
+        .bind(account)
+        .bind(sub_account)
+        .bind(&note.address)
+        .bind(index.and_then(|i| i64::try_from(i).ok()).unwrap_or(-1))
+        .bind(note.diversifier_index.map(|di| di.as_bytes().to_vec()))
+        .bind(note.pool)
+        .execute(&mut *connection)
+        .await?;
+        let id_address = r.last_insert_rowid() as u32;
+        Self::insert_receiver(connection, note.pool, id_address, &note.address).await?;
+        Ok((account, sub_account))
+    }
+
+    async fn insert_receiver(
+        connection: &mut SqliteConnection,
+        pool: u8,
+        id_address: u32,
+        receiver: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO receivers(pool, id_address, receiver_address)
+            VALUES (?1, ?2, ?3)",
+        )
+        .bind(pool)
+        .bind(id_address)
+        .bind(receiver)
+        .execute(&mut *connection)
+        .await?;
         Ok(())
     }
 
+    /// Key of the imported account `account`, or the wallet key
+    async fn account_key(
+        &self,
+        connection: &mut SqliteConnection,
+        account: Option<u32>,
+    ) -> Result<ViewingKey> {
+        let Some(account) = account else {
+            return Ok(self.key.clone());
+        };
+        let (vk,): (String,) = sqlx::query_as("SELECT vk FROM viewing_keys WHERE account = ?1")
+            .bind(account)
+            .fetch_one(&mut *connection)
+            .await?;
+        ViewingKey::decode(&self.network, &vk)
+    }
+
+    async fn get_unassigned_account(connection: &mut SqliteConnection) -> Result<Option<u32>> {
+        let account = sqlx::query("SELECT value FROM properties WHERE name = 'unassigned_account'")
+            .map(|r: SqliteRow| r.get::<String, _>(0))
+            .fetch_optional(&mut *connection)
+            .await?;
+        Ok(account.map(|a| a.parse::<u32>()).transpose()?)
+    }
+
+    /// Account of the payments to unknown addresses of the wallet key,
+    /// created on first use
+    async fn unassigned_account(&self, connection: &mut SqliteConnection) -> Result<u32> {
+        if let Some(account) = Self::get_unassigned_account(&mut *connection).await? {
+            return Ok(account);
+        }
+        let (account,): (Option<u32>,) = sqlx::query_as("SELECT MAX(account) FROM addresses")
+            .fetch_one(&mut *connection)
+            .await?;
+        let account = account.map(|id| id + 1).unwrap_or(0);
+        sqlx::query("INSERT INTO properties(name, value) VALUES ('unassigned_account', ?1)")
+            .bind(account.to_string())
+            .execute(&mut *connection)
+            .await?;
+        Ok(account)
+    }
+
+    /// Move the orphan receiver `address` and its notes to a sub-account of
+    /// the same key. Returns the number of notes moved.
+    pub async fn reassign_receiver(
+        &self,
+        address: &str,
+        id_account: u32,
+        id_sub_account: u32,
+    ) -> Result<u32> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+
+        let (from_id_address, from_account, from_imported) = sqlx::query(
+            "SELECT a.id_address, a.account, a.account IN (SELECT account FROM viewing_keys)
+            FROM addresses a JOIN receivers r ON a.id_address = r.id_address
+            WHERE r.receiver_address = ?1 AND a.orphan",
+        )
+        .bind(address)
+        .map(|r: SqliteRow| (r.get::<u32, _>(0), r.get::<u32, _>(1), r.get::<bool, _>(2)))
+        .fetch_optional(&mut *db_tx)
+        .await?
+        .ok_or(WalletError::NotFound(format!(
+            "Unassigned address {address}"
+        )))?;
+        let (id_address, to_imported) = sqlx::query(
+            "SELECT id_address, account IN (SELECT account FROM viewing_keys) FROM addresses
+            WHERE account = ?1 AND sub_account = ?2 AND NOT orphan",
+        )
+        .bind(id_account)
+        .bind(id_sub_account)
+        .map(|r: SqliteRow| (r.get::<u32, _>(0), r.get::<bool, _>(1)))
+        .fetch_optional(&mut *db_tx)
+        .await?
+        .ok_or(WalletError::NotFound(format!(
+            "Unknown address {id_account}/{id_sub_account}"
+        )))?;
+        // Notes stay with the key that can detect their spends
+        let same_key = if from_imported {
+            id_account == from_account
+        } else {
+            !to_imported
+        };
+        if !same_key {
+            return Err(WalletError::InvalidArgument(format!(
+                "The address {address} belongs to another viewing key"
+            ))
+            .into());
+        }
+
+        sqlx::query("UPDATE receivers SET id_address = ?1 WHERE receiver_address = ?2")
+            .bind(id_address)
+            .bind(address)
+            .execute(&mut *db_tx)
+            .await?;
+        let r = sqlx::query(
+            "UPDATE received_notes SET account = ?1, sub_account = ?2 WHERE address = ?3",
+        )
+        .bind(id_account)
+        .bind(id_sub_account)
+        .bind(address)
+        .execute(&mut *db_tx)
+        .await?;
+        // The orphan address had this receiver only
+        sqlx::query(
+            "DELETE FROM addresses WHERE id_address = ?1
+            AND NOT EXISTS (SELECT 1 FROM receivers WHERE id_address = ?1)",
+        )
+        .bind(from_id_address)
+        .execute(&mut *db_tx)
+        .await?;
+        db_transaction.commit().await?;
+        Ok(r.rows_affected() as u32)
+    }
+
+    /// Mark the addresses that expired before `now` without being paid as
+    /// inactive. They are still watched. Returns the number of addresses
+    /// marked.
+    pub async fn expire_addresses(&self, now: u64) -> Result<u64> {
+        let mut connection = self.pool.acquire().await?;
+        let r = sqlx::query(
+            "UPDATE addresses SET active = FALSE
+            WHERE active AND expires_at <= ?1 AND NOT EXISTS (
+            SELECT 1 FROM received_notes n
+            WHERE n.account = addresses.account AND n.sub_account = addresses.sub_account)",
+        )
+        .bind(now as i64)
+        .execute(&mut *connection)
+        .await?;
+        Ok(r.rows_affected())
+    }
+
+    /// Expired addresses that were paid after their expiry
+    pub async fn get_late_payments(&self) -> Result<Vec<LatePayment>> {
+        let mut connection = self.pool.acquire().await?;
+        let payments = sqlx::query(
+            "SELECT a.account, a.sub_account, a.address, a.label, a.expires_at,
+            SUM(n.value), GROUP_CONCAT(DISTINCT hex(t.txid))
+            FROM addresses a
+            JOIN received_notes n ON n.account = a.account AND n.sub_account = a.sub_account
+            JOIN transactions t ON t.id_tx = n.id_tx
+            WHERE n.late AND NOT a.orphan
+            GROUP BY a.id_address ORDER BY a.account, a.sub_account",
+        )
+        .map(|row: SqliteRow| {
+            let txids: String = row.get(6);
+            let txids = txids
+                .split(',')
+                .map(|txid| {
+                    // Hashes are stored in internal byte order
+                    let mut txid = hex::decode(txid).unwrap();
+                    txid.reverse();
+                    hex::encode(txid)
+                })
+                .collect();
+            LatePayment {
+                account_index: row.get(0),
+                address_index: row.get(1),
+                address: row.get(2),
+                label: row.get(3),
+                expires_at: row.get::<i64, _>(4) as u64,
+                amount: row.get::<i64, _>(5) as u64,
+                txids,
+            }
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(payments)
+    }
+
     pub async fn get_accounts(
         &self,
         height: u32,
         confirmations: u32,
     ) -> Result<Vec<AccountBalance>> {
         let mut connection = self.pool.acquire().await?;
-        let confirmed_height = height - confirmations + 1;
+        let confirmed_height = (height + 1).saturating_sub(confirmations);
         let sub_accounts = sqlx::query(
             "WITH base AS (SELECT account, address FROM addresses WHERE sub_account = 0), \
                 balances AS (SELECT account, SUM(value) AS total from received_notes WHERE spent IS NULL GROUP BY account), \
@@ -170,30 +691,119 @@ impl Db {
                     unlocked_balance: unlocked,
                     base_address,
                     tag: "".to_string(),
+                    balance_mode: self.key.balance_mode(),
                 }
             })
             .fetch_all(&mut *connection)
             .await?;
+        let mut sub_accounts = sub_accounts;
+        for key in self.get_viewing_keys().await? {
+            for account in sub_accounts
+                .iter_mut()
+                .filter(|a| a.account_index == key.account)
+            {
+                account.balance_mode = key.key.balance_mode();
+            }
+        }
 
         Ok(sub_accounts)
     }
 
-    pub async fn get_addresses(&self) -> Result<Vec<SubAccount>> {
-    let mut connection = self.pool.acquire().await?;
-        let addresses = sqlx::query(
-            "SELECT account, sub_account, address FROM addresses")
-            .map(|row: SqliteRow| {
-                let account_index = row.get(0);
-                let sub_account_index = row.get(1);
-                let address = row.get(2);
-                SubAccount {
-                    account_index,
-                    sub_account_index,
-                    address,
+    /// Balances at `height`: the notes received at or below it and not
+    /// spent by then. Accounts without funds are left out.
+    pub async fn get_balances_at(&self, height: u32) -> Result<Vec<HistoricalBalance>> {
+        let mut connection = self.pool.acquire().await?;
+        let rows = sqlx::query(
+            "WITH balances AS (SELECT account, sub_account, SUM(value) AS balance
+            FROM received_notes WHERE height <= ?1 AND (spent IS NULL OR spent > ?1)
+            GROUP BY account, sub_account)
+            SELECT b.account, b.sub_account, b.balance, a.address, a.label, base.label
+            FROM balances b
+            LEFT JOIN addresses a ON a.account = b.account AND a.sub_account = b.sub_account
+            LEFT JOIN addresses base ON base.account = b.account AND base.sub_account = 0
+            ORDER BY b.account, b.sub_account",
+        )
+        .bind(height)
+        .map(|row: SqliteRow| {
+            let account: u32 = row.get(0);
+            let sub_account = SubAccountBalance {
+                address_index: row.get(1),
+                balance: row.get::<i64, _>(2) as u64,
+                address: row.get::<Option<String>, _>(3).unwrap_or_default(),
+                label: row.get::<Option<String>, _>(4).unwrap_or_default(),
+            };
+            let account_label: Option<String> = row.get(5);
+            (account, account_label.unwrap_or_default(), sub_account)
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+
+        let mut accounts: Vec<HistoricalBalance> = vec![];
+        for (account_index, label, sub_account) in rows {
+            match accounts.last_mut() {
+                Some(account) if account.account_index == account_index => {
+                    account.balance += sub_account.balance;
+                    account.sub_accounts.push(sub_account);
                 }
-            })
-            .fetch_all(&mut *connection)
-            .await?;
+                _ => accounts.push(HistoricalBalance {
+                    account_index,
+                    label,
+                    balance: sub_account.balance,
+                    sub_accounts: vec![sub_account],
+                }),
+            }
+        }
+        Ok(accounts)
+    }
+
+    /// Highest stored block such that it and every block before it are
+    /// not after `timestamp`, None if there is none. Block times do not
+    /// always increase, so the times are compared through their running
+    /// maximum. The blocks with notes received or spent are stored, so the
+    /// balances do not change between the result and `timestamp`. Fails if
+    /// a block above the result was stored without time by an older
+    /// version.
+    pub async fn height_at_time(&self, timestamp: u64) -> Result<Option<u32>> {
+        let mut connection = self.pool.acquire().await?;
+        let (height, untimed): (Option<u32>, Option<u32>) = sqlx::query_as(
+            "SELECT (SELECT MAX(height) FROM (SELECT height,
+            MAX(max_time) OVER (ORDER BY height) AS running_time
+            FROM blocks WHERE max_time IS NOT NULL) WHERE running_time <= ?1),
+            (SELECT MAX(height) FROM blocks WHERE max_time IS NULL)",
+        )
+        .bind(timestamp as i64)
+        .fetch_one(&mut *connection)
+        .await?;
+        if let Some(untimed) = untimed.filter(|&u| height.map_or(true, |h| u > h)) {
+            return Err(WalletError::NotSynced(format!(
+                "The time of block {untimed} is not stored, rescan the wallet to look up \
+                this timestamp"
+            ))
+            .into());
+        }
+        Ok(height)
+    }
+
+    pub async fn get_addresses(&self) -> Result<Vec<SubAccount>> {
+        let mut connection = self.pool.acquire().await?;
+        let addresses =
+            sqlx::query("SELECT account, sub_account, address, expires_at, active FROM addresses")
+                .map(|row: SqliteRow| {
+                    let account_index = row.get(0);
+                    let sub_account_index = row.get(1);
+                    let address = row.get(2);
+                    let expires_at = row.get(3);
+                    let active = row.get(4);
+                    SubAccount {
+                        account_index,
+                        sub_account_index,
+                        address,
+                        expires_at,
+                        active,
+                    }
+                })
+                .fetch_all(&mut *connection)
+                .await?;
 
         Ok(addresses)
     }
@@ -245,10 +855,13 @@ impl Db {
         txid.reverse();
         let memo: String = row.get(4);
         let height: u32 = row.get(5);
+        let timestamp: Option<u64> = row.get(6);
+        let late: bool = row.get(7);
+        let rate: Option<f64> = row.get(8);
         Transfer {
             address,
             amount: value,
-            confirmations: latest_height - height + 1,
+            confirmations: (latest_height + 1).saturating_sub(height),
             height,
             fee: 0,
             note: memo,
@@ -258,10 +871,12 @@ impl Db {
                 minor: sub_account,
             },
             suggested_confirmations_threshold: confirmations,
-            timestamp: 0, // TODO: Check if needed
+            timestamp: timestamp.unwrap_or_default(),
             txid: hex::encode(txid),
             r#type: "in".to_string(),
             unlock_time: 0,
+            late,
+            rate,
         }
     }
 
@@ -275,7 +890,8 @@ impl Db {
         let mut connection = self.pool.acquire().await?;
 
         let transfers = sqlx::query(
-            "SELECT address, n.value, sub_account, txid, memo, n.height \
+            "SELECT address, n.value, sub_account, txid, memo, n.height, t.timestamp, n.late, \
+            t.rate \
             FROM received_notes n JOIN transactions t ON n.id_tx = t.id_tx WHERE \
             account = ?1 ORDER BY n.height",
         )
@@ -300,10 +916,14 @@ impl Db {
     ) -> Result<Vec<Transfer>> {
         let mut connection = self.pool.acquire().await?;
 
-        let mut txid = hex::decode(txid)?;
+        let mut txid = match hex::decode(txid) {
+            Ok(txid) if txid.len() == 32 => txid,
+            _ => return Err(WalletError::InvalidArgument(format!("Invalid txid {txid}")).into()),
+        };
         txid.reverse();
         let transfers = sqlx::query(
-            "SELECT a.address, n.value, n.sub_account, txid, memo, n.height
+            "SELECT a.address, n.value, n.sub_account, txid, memo, n.height, t.timestamp, n.late,
+            t.rate
             FROM received_notes n
 			JOIN transactions t ON n.id_tx = t.id_tx
 			JOIN receivers r ON n.address = r.receiver_address
@@ -318,69 +938,257 @@ impl Db {
         Ok(transfers)
     }
 
-    pub async fn truncate_height(&self, height: u32) -> Result<()> {
+    /// Notes received (`in`) and spent (`out`) in the blocks of `heights`
+    /// whose time is within `times`, in chain order. Spends are reported
+    /// at the height of the spending transaction, without memo. The fee is
+    /// only known for the fully shielded transactions that paid the wallet.
+    pub async fn export_rows(
+        &self,
+        heights: (u32, u32),
+        times: (u64, u64),
+        synced_height: u32,
+        confirmations: u32,
+    ) -> Result<Vec<ExportRow>> {
         let mut connection = self.pool.acquire().await?;
+        let rows = sqlx::query(
+            "SELECT 'in', t.txid, n.height, t.timestamp, n.account, n.sub_account,
+            a.label, a.address, n.address, n.pool, n.value, n.memo, n.id_note, t.rate, t.fee
+            FROM received_notes n
+            JOIN transactions t ON t.id_tx = n.id_tx
+            LEFT JOIN receivers r ON r.receiver_address = n.address
+            LEFT JOIN addresses a ON a.id_address = r.id_address
+            WHERE n.height BETWEEN ?1 AND ?2 AND COALESCE(t.timestamp, 0) BETWEEN ?3 AND ?4
+            UNION ALL
+            SELECT 'out', s.txid, n.spent, s.timestamp, n.account, n.sub_account,
+            a.label, a.address, n.address, n.pool, n.value, '', n.id_note, s.rate, s.fee
+            FROM received_notes n
+            LEFT JOIN transactions s ON s.id_tx = n.spent_id_tx
+            LEFT JOIN receivers r ON r.receiver_address = n.address
+            LEFT JOIN addresses a ON a.id_address = r.id_address
+            WHERE n.spent BETWEEN ?1 AND ?2 AND COALESCE(s.timestamp, 0) BETWEEN ?3 AND ?4
+            ORDER BY 3, 1, 13",
+        )
+        .bind(heights.0)
+        .bind(heights.1)
+        .bind(times.0 as i64)
+        .bind(times.1 as i64)
+        .map(|row: SqliteRow| {
+            let r#type: String = row.get(0);
+            let txid: Option<Vec<u8>> = row.get(1);
+            let txid = txid
+                .map(|mut txid| {
+                    txid.reverse();
+                    hex::encode(txid)
+                })
+                .unwrap_or_default();
+            let height: u32 = row.get(2);
+            let timestamp: Option<i64> = row.get(3);
+            let address: Option<String> = row.get(7);
+            let receiver: String = row.get(8);
+            let pool: u32 = row.get(9);
+            let memo: Option<String> = row.get(11);
+            let rate: Option<f64> = row.get(13);
+            let fee: Option<i64> = row.get(14);
+            let confirmations_count = (synced_height + 1).saturating_sub(height);
+            ExportRow {
+                r#type,
+                txid,
+                height,
+                timestamp: timestamp.unwrap_or_default() as u64,
+                account_index: row.get(4),
+                address_index: row.get(5),
+                label: row.get::<Option<String>, _>(6).unwrap_or_default(),
+                address: address.unwrap_or(receiver),
+                pool: pool_label(pool as u8).to_string(),
+                amount: row.get::<i64, _>(10) as u64,
+                fee: fee.map(|fee| fee as u64),
+                memo: memo.unwrap_or_default(),
+                confirmations: confirmations_count,
+                confirmed: confirmations_count >= confirmations,
+                rate,
+            }
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(rows)
+    }
 
-        sqlx::query("DELETE FROM transactions WHERE height >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-        sqlx::query("DELETE FROM received_notes WHERE height >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-        sqlx::query("DELETE FROM blocks WHERE height >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-        sqlx::query("UPDATE received_notes SET spent = NULL WHERE spent >= ?1")
-            .bind(height)
-            .execute(&mut *connection)
-            .await?;
-
-        Ok(())
+    /// Received transactions with a block time but no fiat rate, whose
+    /// next lookup is due at `now`
+    pub async fn get_unrated_transactions(&self, now: u64) -> Result<Vec<(u32, u64)>> {
+        let mut connection = self.pool.acquire().await?;
+        let transactions = sqlx::query(
+            "SELECT id_tx, timestamp FROM transactions
+            WHERE rate IS NULL AND timestamp IS NOT NULL AND rate_attempts < ?2
+            AND (rate_checked_at IS NULL
+            OR rate_checked_at + (?3 << (rate_attempts - 1)) <= ?1)
+            AND id_tx IN (SELECT id_tx FROM received_notes)
+            ORDER BY id_tx",
+        )
+        .bind(now as i64)
+        .bind(RATE_MAX_ATTEMPTS)
+        .bind(RATE_RETRY_DELAY_SECS)
+        .map(|row: SqliteRow| (row.get::<u32, _>(0), row.get::<i64, _>(1) as u64))
+        .fetch_all(&mut *connection)
+        .await?;
+        Ok(transactions)
     }
 
-    pub async fn fetch_block_hash(&self, client: &mut Client, height: u32) -> Result<()> {
+    pub async fn set_rate(&self, id_tx: u32, rate: f64) -> Result<()> {
         let mut connection = self.pool.acquire().await?;
-        if sqlx::query("SELECT 1 FROM blocks WHERE height = ?1")
-            .bind(height)
-            .fetch_optional(&mut *connection)
-            .await?
-            .is_none()
-        {
-            let b = client
-                .get_block(Request::new(BlockId {
-                    height: height as u64,
-                    hash: vec![],
-                }))
-                .await?
-                .into_inner();
-            let hash: Hash = b.hash.try_into().unwrap();
-            sqlx::query(
-                "INSERT INTO blocks(hash, height)
-            VALUES (?1, ?2)",
-            )
-            .bind(hash.as_slice())
-            .bind(height)
+        sqlx::query("UPDATE transactions SET rate = ?2 WHERE id_tx = ?1")
+            .bind(id_tx)
+            .bind(rate)
             .execute(&mut *connection)
             .await?;
-        }
         Ok(())
     }
 
-    pub async fn get_nfs(&self) -> Result<HashMap<[u8; 32], u64>> {
+    /// Count a lookup that found no rate for the transaction `id_tx`
+    pub async fn set_rate_unavailable(&self, id_tx: u32, now: u64) -> Result<()> {
         let mut connection = self.pool.acquire().await?;
+        sqlx::query(
+            "UPDATE transactions SET rate_attempts = rate_attempts + 1, rate_checked_at = ?2
+            WHERE id_tx = ?1",
+        )
+        .bind(id_tx)
+        .bind(now as i64)
+        .execute(&mut *connection)
+        .await?;
+        Ok(())
+    }
 
-        let nfs = sqlx::query("SELECT nf, value FROM received_notes WHERE spent = 0")
-            .map(|row: SqliteRow| {
-                let nf: Vec<u8> = row.get(0);
-                let value: u64 = row.get(1);
-                let nf: Hash = nf.try_into().unwrap();
-                (nf, value)
-            })
-            .fetch_all(&mut *connection)
+    /// Wait until a scan stores received notes
+    pub async fn transactions_stored(&self) {
+        self.transactions_stored.notified().await
+    }
+
+    /// Remove every block, transaction and note above `height` and
+    /// restore the notes spent after it. Addresses and labels are kept,
+    /// imported keys scanned past `height` are moved back to it.
+    /// The caller must make sure the hash of `height` is stored
+    /// (see `fetch_block_hash`) so that the next scan has an anchor.
+    pub async fn truncate_height(&self, height: u32) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+
+        sqlx::query("DELETE FROM received_notes WHERE height > ?1")
+            .bind(height)
+            .execute(&mut *db_tx)
             .await?;
+        sqlx::query("UPDATE received_notes SET spent = NULL, spent_id_tx = NULL WHERE spent > ?1")
+            .bind(height)
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query("DELETE FROM transactions WHERE height > ?1")
+            .bind(height)
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query("DELETE FROM blocks WHERE height > ?1")
+            .bind(height)
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query(
+            "UPDATE viewing_keys SET synced_height = ?1,
+            synced_hash = (SELECT hash FROM blocks WHERE height = ?1)
+            WHERE synced_height > ?1",
+        )
+        .bind(height)
+        .execute(&mut *db_tx)
+        .await?;
+        db_transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Rewind the scan of the key imported as `account` to `height`. The
+    /// other accounts are untouched, and so are the transactions which
+    /// may be shared with them.
+    pub async fn rewind_key(&self, account: u32, height: u32, hash: &Hash) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+
+        sqlx::query("DELETE FROM received_notes WHERE account = ?1 AND height > ?2")
+            .bind(account)
+            .bind(height)
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query(
+            "UPDATE received_notes SET spent = NULL, spent_id_tx = NULL
+            WHERE account = ?1 AND spent > ?2",
+        )
+        .bind(account)
+        .bind(height)
+        .execute(&mut *db_tx)
+        .await?;
+        sqlx::query(
+            "UPDATE viewing_keys SET synced_height = ?2, synced_hash = ?3 WHERE account = ?1",
+        )
+        .bind(account)
+        .bind(height)
+        .bind(hash.as_slice())
+        .execute(&mut *db_tx)
+        .await?;
+        db_transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn fetch_block_hash(&self, chain: &dyn ChainSource, height: u32) -> Result<()> {
+        let mut connection = self.pool.acquire().await?;
+        // Blocks stored by older versions get their time
+        if sqlx::query("SELECT 1 FROM blocks WHERE height = ?1 AND max_time IS NOT NULL")
+            .bind(height)
+            .fetch_optional(&mut *connection)
+            .await?
+            .is_none()
+        {
+            let hash = chain.block_hash(height).await?;
+            let time = chain.tree_state(height).await?.time;
+            sqlx::query(
+                "INSERT INTO blocks(hash, height, max_time)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT (height) DO UPDATE SET hash = ?1, max_time = ?3",
+            )
+            .bind(hash.as_slice())
+            .bind(height)
+            .bind(time)
+            .execute(&mut *connection)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Nullifiers of the unspent notes of the wallet key
+    pub async fn get_nfs(&self) -> Result<HashMap<[u8; 32], u64>> {
+        self.unspent_nfs(None).await
+    }
+
+    /// Nullifiers of the unspent notes of the key imported as `account`
+    pub async fn get_key_nfs(&self, account: u32) -> Result<HashMap<[u8; 32], u64>> {
+        self.unspent_nfs(Some(account)).await
+    }
+
+    async fn unspent_nfs(&self, account: Option<u32>) -> Result<HashMap<[u8; 32], u64>> {
+        let mut connection = self.pool.acquire().await?;
+
+        let nfs = sqlx::query(
+            "SELECT nf, value FROM received_notes WHERE spent IS NULL AND nf IS NOT NULL
+            AND CASE WHEN ?1 IS NULL
+            THEN account NOT IN (SELECT account FROM viewing_keys)
+            ELSE account = ?1 END",
+        )
+        .bind(account)
+        .map(|row: SqliteRow| {
+            let nf: Vec<u8> = row.get(0);
+            let value: u64 = row.get(1);
+            let nf: Hash = nf.try_into().unwrap();
+            (nf, value)
+        })
+        .fetch_all(&mut *connection)
+        .await?;
 
         let mut nf_map = HashMap::new();
         for (nf, value) in nfs {
@@ -389,21 +1197,159 @@ impl Db {
         Ok(nf_map)
     }
 
-    async fn next_diversifier(&self, connection: &mut SqliteConnection) -> Result<(u64, String)> {
-        let di = sqlx::query("SELECT MAX(diversifier_index) FROM addresses")
-            .map(|r: SqliteRow| r.get::<Option<u64>, _>(0))
+    /// Last diversifier index used by the wallet key, handed out or in
+    /// the address pool
+    async fn max_wallet_diversifier(connection: &mut SqliteConnection) -> Result<Option<u64>> {
+        let di = sqlx::query(
+            "SELECT MAX(di) FROM (
+            SELECT diversifier_index AS di FROM addresses
+            WHERE account NOT IN (SELECT account FROM viewing_keys) AND NOT orphan
+            UNION ALL SELECT diversifier_index FROM address_pool)",
+        )
+        .map(|r: SqliteRow| r.get::<Option<u64>, _>(0))
+        .fetch_one(&mut *connection)
+        .await?;
+        Ok(di)
+    }
+
+    /// Take the pooled address with the lowest index and wake up the
+    /// task that refills the pool
+    async fn reserve_pooled_address(
+        &self,
+        connection: &mut SqliteConnection,
+    ) -> Result<Option<(u64, UnifiedAddress)>> {
+        let pooled = sqlx::query(
+            "DELETE FROM address_pool WHERE diversifier_index =
+            (SELECT MIN(diversifier_index) FROM address_pool WHERE NOT paid)
+            RETURNING diversifier_index, address",
+        )
+        .map(|r: SqliteRow| (r.get::<i64, _>(0) as u64, r.get::<String, _>(1)))
+        .fetch_optional(&mut *connection)
+        .await?;
+        let Some((di, address)) = pooled else {
+            return Ok(None);
+        };
+        self.address_reserved.notify_one();
+        let ua = UnifiedAddress::decode(&self.network, &address).map_err(|e| anyhow!(e))?;
+        Ok(Some((di, ua)))
+    }
+
+    /// Derive addresses of the wallet key with the default receivers until
+    /// the pool has `size` of them. Returns the depth of the pool.
+    pub async fn fill_address_pool(&self, size: u32) -> Result<u32> {
+        loop {
+            // One derivation at a time, so that address creation waits for
+            // at most one of them
+            let _guard = self.address_creation_lock.lock().await;
+            let mut connection = self.pool.acquire().await?;
+            let depth = self.address_pool_depth_with(&mut connection).await?;
+            if depth >= size {
+                return Ok(depth);
+            }
+            let di = Self::max_wallet_diversifier(&mut connection).await?;
+            let di = di.map(|di| di + 1).unwrap_or_default();
+            let options = AddressOptions::default();
+            let (ua, di) = self
+                .unused_address(&mut connection, &self.key, di, &options)
+                .await?;
+            sqlx::query("INSERT INTO address_pool(diversifier_index, address) VALUES (?1, ?2)")
+                .bind(di as i64)
+                .bind(ua.encode(&self.network))
+                .execute(&mut *connection)
+                .await?;
+        }
+    }
+
+    pub async fn address_pool_depth(&self) -> Result<u32> {
+        let mut connection = self.pool.acquire().await?;
+        self.address_pool_depth_with(&mut connection).await
+    }
+
+    async fn address_pool_depth_with(&self, connection: &mut SqliteConnection) -> Result<u32> {
+        let (depth,): (u32,) = sqlx::query_as("SELECT COUNT(*) FROM address_pool WHERE NOT paid")
             .fetch_one(&mut *connection)
-            .await?
-            .map(|di| di + 1)
-            .unwrap_or_default();
-        let (ua, ndi) = self
-            .ufvk
-            .find_address(di.into(), UnifiedAddressRequest::AllAvailableKeys)?;
-        let ua = ua.encode(&self.network);
-        let ndi: u64 = ndi.try_into().unwrap();
+            .await?;
+        Ok(depth)
+    }
+
+    /// Wait until an address is taken from the pool
+    pub async fn address_reserved(&self) {
+        self.address_reserved.notified().await
+    }
+
+    /// Next address of the key of `id_account` with the receivers of
+    /// `options`: its imported key, or the wallet key shared by the other
+    /// accounts
+    async fn next_diversifier(
+        &self,
+        connection: &mut SqliteConnection,
+        id_account: u32,
+        options: &AddressOptions,
+    ) -> Result<(u64, UnifiedAddress)> {
+        let imported = sqlx::query("SELECT vk FROM viewing_keys WHERE account = ?1")
+            .bind(id_account)
+            .map(|r: SqliteRow| r.get::<String, _>(0))
+            .fetch_optional(&mut *connection)
+            .await?;
+        let (key, di) = match imported {
+            Some(vk) => {
+                let di = sqlx::query(
+                    "SELECT MAX(diversifier_index) FROM addresses
+                    WHERE account = ?1 AND NOT orphan",
+                )
+                .bind(id_account)
+                .map(|r: SqliteRow| r.get::<Option<u64>, _>(0))
+                .fetch_one(&mut *connection)
+                .await?;
+                (ViewingKey::decode(&self.network, &vk)?, di)
+            }
+            None => {
+                if *options == AddressOptions::default() {
+                    if let Some(address) = self.reserve_pooled_address(connection).await? {
+                        return Ok(address);
+                    }
+                    ADDRESS_POOL_MISSES.inc();
+                }
+                let di = Self::max_wallet_diversifier(connection).await?;
+                (self.key.clone(), di)
+            }
+        };
+        let di = di.map(|di| di + 1).unwrap_or_default();
+        let (ua, ndi) = self.unused_address(connection, &key, di, options).await?;
         Ok((ndi, ua))
     }
 
+    /// Address of `key` at the first diversifier index from `di` whose
+    /// receivers were never paid. Payments at indices that were not handed
+    /// out leave orphan receivers, which must not be given to a new payer.
+    async fn unused_address(
+        &self,
+        connection: &mut SqliteConnection,
+        key: &ViewingKey,
+        mut di: u64,
+        options: &AddressOptions,
+    ) -> Result<(UnifiedAddress, u64)> {
+        loop {
+            let (ua, ndi) = key.find_address(di.into(), options)?;
+            let ndi: u64 = ndi.try_into()?;
+            let mut paid = false;
+            for pool in [1, 2] {
+                let Some(receiver) = key.receiver(&self.network, pool, ndi) else {
+                    continue;
+                };
+                paid |= sqlx::query("SELECT 1 FROM receivers WHERE receiver_address = ?1")
+                    .bind(receiver)
+                    .fetch_optional(&mut *connection)
+                    .await?
+                    .is_some();
+            }
+            if !paid {
+                return Ok((ua, ndi));
+            }
+            di = ndi + 1;
+        }
+    }
+
     pub async fn create(&self) -> Result<bool> {
         let mut connection = self.pool.acquire().await?;
 
@@ -422,7 +1368,13 @@ impl Db {
             account INTEGER NOT NULL,
             sub_account INTEGER NOT NULL,
             address TEXT NOT NULL,
-            diversifier_index INTEGER NOT NULL)",
+            diversifier_index INTEGER NOT NULL,
+            address_type TEXT NOT NULL DEFAULT 'unified',
+            transparent BOOL NOT NULL DEFAULT FALSE,
+            orphan BOOL NOT NULL DEFAULT FALSE,
+            full_diversifier_index BLOB,
+            expires_at INTEGER,
+            active BOOL NOT NULL DEFAULT TRUE)",
         )
         .execute(&mut *connection)
         .await?;
@@ -442,28 +1394,23 @@ impl Db {
             id_tx INTEGER PRIMARY KEY,
             txid BLOB NOT NULL UNIQUE,
             height INTEGER NOT NULL,
+            timestamp INTEGER,
             value INTEGER NOT NULL)",
         )
         .execute(&mut *connection)
         .await?;
 
+        sqlx::query(RECEIVED_NOTES_TABLE)
+            .execute(&mut *connection)
+            .await?;
+
         sqlx::query(
-            "CREATE TABLE IF NOT EXISTS received_notes (
-            id_note INTEGER PRIMARY KEY,
-            address TEXT NOT NULL,
-            account INTEGER,
-            sub_account INTEGER,
-            id_tx INTEGER NOT NULL,
-            position INTEGER NOT NULL,
-            height INTEGER NOT NULL,
-            diversifier BLOB NOT NULL,
-            value INTEGER NOT NULL,
-            rcm BLOB NOT NULL,
-            nf BLOB NOT NULL UNIQUE,
-            rho BLOB,
-            memo TEXT,
-            spent INTEGER,
-            CONSTRAINT tx_output UNIQUE (position))",
+            "CREATE TABLE IF NOT EXISTS viewing_keys (
+            account INTEGER PRIMARY KEY,
+            vk TEXT NOT NULL,
+            birth_height INTEGER NOT NULL,
+            synced_height INTEGER NOT NULL,
+            synced_hash BLOB NOT NULL)",
         )
         .execute(&mut *connection)
         .await?;
@@ -476,15 +1423,351 @@ impl Db {
             panic!("Old database schema. This version is not compatible with it.");
         }
 
+        if sqlx::query("SELECT 1 FROM pragma_table_info('received_notes') WHERE name = 'pool'")
+            .fetch_optional(&mut *connection)
+            .await?
+            .is_none()
+        {
+            Self::migrate_note_pool(&mut connection).await?;
+        }
+
+        if sqlx::query("SELECT 1 FROM pragma_table_info('addresses') WHERE name = 'address_type'")
+            .fetch_optional(&mut *connection)
+            .await?
+            .is_none()
+        {
+            sqlx::query(
+                "ALTER TABLE addresses ADD COLUMN address_type TEXT NOT NULL DEFAULT 'unified'",
+            )
+            .execute(&mut *connection)
+            .await?;
+            sqlx::query("ALTER TABLE addresses ADD COLUMN transparent BOOL NOT NULL DEFAULT FALSE")
+                .execute(&mut *connection)
+                .await?;
+        }
+
+        // Orphan addresses were received by the wallet without being handed
+        // out. Their index is in `full_diversifier_index`, 11 bytes little
+        // endian, and in `diversifier_index` only when it fits (-1 otherwise).
+        if sqlx::query("SELECT 1 FROM pragma_table_info('addresses') WHERE name = 'orphan'")
+            .fetch_optional(&mut *connection)
+            .await?
+            .is_none()
+        {
+            sqlx::query("ALTER TABLE addresses ADD COLUMN orphan BOOL NOT NULL DEFAULT FALSE")
+                .execute(&mut *connection)
+                .await?;
+            sqlx::query("ALTER TABLE addresses ADD COLUMN full_diversifier_index BLOB")
+                .execute(&mut *connection)
+                .await?;
+
+            // Payments to an unknown address used to get a sub-account of
+            // their own with the diversifier index cut to 32 bits. The key
+            // does not derive them again: they are orphans.
+            let received = sqlx::query(
+                "SELECT a.id_address, a.diversifier_index, r.pool, r.receiver_address
+                FROM addresses a JOIN receivers r ON a.id_address = r.id_address
+                WHERE a.address = r.receiver_address
+                AND a.account NOT IN (SELECT account FROM viewing_keys)",
+            )
+            .map(|r: SqliteRow| {
+                let id_address: u32 = r.get(0);
+                let diversifier_index: i64 = r.get(1);
+                let pool: u8 = r.get::<u32, _>(2) as u8;
+                let receiver: String = r.get(3);
+                (id_address, diversifier_index as u64, pool, receiver)
+            })
+            .fetch_all(&mut *connection)
+            .await?;
+            for (id_address, diversifier_index, pool, receiver) in received {
+                let derived = self.key.receiver(&self.network, pool, diversifier_index);
+                if derived.as_ref() != Some(&receiver) {
+                    sqlx::query(
+                        "UPDATE addresses SET orphan = TRUE, diversifier_index = -1
+                        WHERE id_address = ?1",
+                    )
+                    .bind(id_address)
+                    .execute(&mut *connection)
+                    .await?;
+                }
+            }
+        }
+
+        // Notes of an incoming viewing key have no nullifier
+        if sqlx::query(
+            "SELECT 1 FROM pragma_table_info('received_notes')
+            WHERE name = 'nf' AND \"notnull\" = 1",
+        )
+        .fetch_optional(&mut *connection)
+        .await?
+        .is_some()
+        {
+            Self::migrate_nullable_nf(&mut connection).await?;
+        }
+
+        // Block time of the transactions, and expiry of the addresses
+        Self::add_column(&mut connection, "transactions", "timestamp", "INTEGER").await?;
+        Self::add_column(&mut connection, "addresses", "expires_at", "INTEGER").await?;
+        Self::add_column(
+            &mut connection,
+            "addresses",
+            "active",
+            "BOOL NOT NULL DEFAULT TRUE",
+        )
+        .await?;
+        Self::add_column(
+            &mut connection,
+            "received_notes",
+            "late",
+            "BOOL NOT NULL DEFAULT FALSE",
+        )
+        .await?;
+        // Transaction that spent the note, unknown for older spends
+        Self::add_column(&mut connection, "received_notes", "spent_id_tx", "INTEGER").await?;
+        // Fiat rate at the block time of the received transactions, and
+        // the lookups that found none
+        Self::add_column(&mut connection, "transactions", "rate", "REAL").await?;
+        Self::add_column(
+            &mut connection,
+            "transactions",
+            "rate_attempts",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        Self::add_column(
+            &mut connection,
+            "transactions",
+            "rate_checked_at",
+            "INTEGER",
+        )
+        .await?;
+        // Fee of the fully shielded transactions, unknown for the others
+        Self::add_column(&mut connection, "transactions", "fee", "INTEGER").await?;
+        // Highest block time up to the block, unknown for the blocks stored
+        // by older versions
+        Self::add_column(&mut connection, "blocks", "max_time", "INTEGER").await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS address_pool (
+            diversifier_index INTEGER PRIMARY KEY,
+            address TEXT NOT NULL)",
+        )
+        .execute(&mut *connection)
+        .await?;
+        // Pooled addresses paid before being handed out
+        Self::add_column(
+            &mut connection,
+            "address_pool",
+            "paid",
+            "BOOL NOT NULL DEFAULT FALSE",
+        )
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS properties (
+            name TEXT PRIMARY KEY,
+            value TEXT NOT NULL)",
+        )
+        .execute(&mut *connection)
+        .await?;
+
+        // `spent` holds the height of the spending transaction (NULL when
+        // unspent). Older versions stored 0 for unspent notes and 1 for
+        // spent ones. The height of those spends is unknown, the height of
+        // the note is the lowest it can be: a truncate only restores them
+        // when it also drops the note, which the rescan then finds again.
+        // The `spent_heights` property records that this ran.
+        if sqlx::query("SELECT 1 FROM properties WHERE name = 'spent_heights'")
+            .fetch_optional(&mut *connection)
+            .await?
+            .is_none()
+        {
+            sqlx::query("UPDATE received_notes SET spent = NULL WHERE spent = 0")
+                .execute(&mut *connection)
+                .await?;
+            sqlx::query("UPDATE received_notes SET spent = height WHERE spent = 1")
+                .execute(&mut *connection)
+                .await?;
+            sqlx::query("INSERT INTO properties(name, value) VALUES ('spent_heights', '1')")
+                .execute(&mut *connection)
+                .await?;
+        }
+
         let r = sqlx::query("SELECT 1 FROM addresses")
             .map(|r: SqliteRow| r.get::<u32, _>(0))
             .fetch_optional(&mut *connection)
             .await?;
 
-        Ok(r.is_some())
+        self.check_key(&mut connection).await?;
+
+        Ok(r.is_some())
+    }
+
+    /// Refuse a database created with another viewing key or network: the
+    /// stored fingerprint must match, and so must a sample of the
+    /// addresses derived again from their diversifier index. Databases
+    /// from before the fingerprint get it once their addresses check out.
+    async fn check_key(&self, connection: &mut SqliteConnection) -> Result<()> {
+        let fingerprint = self.key.fingerprint(&self.network);
+        let stored = sqlx::query("SELECT value FROM properties WHERE name = 'fingerprint'")
+            .map(|r: SqliteRow| r.get::<String, _>(0))
+            .fetch_optional(&mut *connection)
+            .await?;
+        if let Some(stored) = &stored {
+            if *stored != fingerprint {
+                anyhow::bail!(
+                    "The database was created with another viewing key or network \
+                    (fingerprint {stored}, the {:?} key VK has {fingerprint}). \
+                    Use the original VK or a new DB_PATH.",
+                    self.network
+                );
+            }
+        }
+
+        // The addresses at the lowest and at the highest diversifier indices
+        let sample = sqlx::query(
+            "WITH wallet AS (
+            SELECT a.account, a.sub_account, a.diversifier_index, r.pool, r.receiver_address,
+            r.id_receiver
+            FROM addresses a JOIN receivers r ON a.id_address = r.id_address
+            WHERE a.account NOT IN (SELECT account FROM viewing_keys) AND NOT a.orphan)
+            SELECT * FROM (SELECT * FROM wallet ORDER BY diversifier_index, id_receiver LIMIT ?1)
+            UNION
+            SELECT * FROM (SELECT * FROM wallet
+            ORDER BY diversifier_index DESC, id_receiver DESC LIMIT ?1)",
+        )
+        .bind(ADDRESS_CHECK_SAMPLE / 2)
+        .map(|r: SqliteRow| {
+            let account: u32 = r.get(0);
+            let sub_account: u32 = r.get(1);
+            let diversifier_index: i64 = r.get(2);
+            let pool: u8 = r.get::<u32, _>(3) as u8;
+            let receiver: String = r.get(4);
+            (
+                account,
+                sub_account,
+                diversifier_index as u64,
+                pool,
+                receiver,
+            )
+        })
+        .fetch_all(&mut *connection)
+        .await?;
+        for (account, sub_account, diversifier_index, pool, receiver) in sample {
+            let derived = self.key.receiver(&self.network, pool, diversifier_index);
+            if derived.as_ref() != Some(&receiver) {
+                anyhow::bail!(
+                    "Address {account}/{sub_account} is {receiver} in the database but VK \
+                    derives {} at diversifier index {diversifier_index}. \
+                    The database was created with another viewing key.",
+                    derived.as_deref().unwrap_or("no receiver of this pool")
+                );
+            }
+        }
+
+        if stored.is_none() {
+            sqlx::query("INSERT INTO properties(name, value) VALUES ('fingerprint', ?1)")
+                .bind(&fingerprint)
+                .execute(&mut *connection)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn add_column(
+        connection: &mut SqliteConnection,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<()> {
+        if sqlx::query(&format!(
+            "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
+        ))
+        .bind(column)
+        .fetch_optional(&mut *connection)
+        .await?
+        .is_none()
+        {
+            sqlx::query(&format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+            ))
+            .execute(&mut *connection)
+            .await?;
+        }
+        Ok(())
+    }
+
+    // Sapling and Orchard note positions are independent counters, so
+    // the older schema that only had UNIQUE(position) could not hold notes
+    // of both pools at the same position. Rebuild the table keyed on
+    // (pool, position). Only Orchard notes have a rho, which is how we
+    // recover the pool of the existing rows.
+    async fn migrate_note_pool(connection: &mut SqliteConnection) -> Result<()> {
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+        sqlx::query("ALTER TABLE received_notes RENAME TO received_notes_old")
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query(RECEIVED_NOTES_TABLE)
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO received_notes
+            (id_note, address, account, sub_account, id_tx, pool, position, height,
+            diversifier, value, rcm, nf, rho, memo, spent)
+            SELECT id_note, address, account, sub_account, id_tx,
+            CASE WHEN rho IS NULL THEN 1 ELSE 2 END, position, height,
+            diversifier, value, rcm, nf, rho, memo, spent
+            FROM received_notes_old",
+        )
+        .execute(&mut *db_tx)
+        .await?;
+        sqlx::query("DROP TABLE received_notes_old")
+            .execute(&mut *db_tx)
+            .await?;
+        db_transaction.commit().await?;
+        Ok(())
+    }
+
+    // SQLite cannot drop a NOT NULL constraint, rebuild the table
+    async fn migrate_nullable_nf(connection: &mut SqliteConnection) -> Result<()> {
+        let mut db_transaction = connection.begin().await?;
+        let db_tx = db_transaction.acquire().await?;
+        sqlx::query("ALTER TABLE received_notes RENAME TO received_notes_old")
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query(RECEIVED_NOTES_TABLE)
+            .execute(&mut *db_tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO received_notes
+            (id_note, address, account, sub_account, id_tx, pool, position, height,
+            diversifier, value, rcm, nf, rho, memo, spent)
+            SELECT id_note, address, account, sub_account, id_tx, pool, position, height,
+            diversifier, value, rcm, nf, rho, memo, spent
+            FROM received_notes_old",
+        )
+        .execute(&mut *db_tx)
+        .await?;
+        sqlx::query("DROP TABLE received_notes_old")
+            .execute(&mut *db_tx)
+            .await?;
+        db_transaction.commit().await?;
+        Ok(())
     }
 
+    /// Store the events of a scan of the wallet key
     pub async fn store_events(&self, events: &[ScanEvent]) -> Result<()> {
+        self.store_account_events(None, events).await
+    }
+
+    /// Store the events of a scan of the key imported as `account`. Its
+    /// blocks only move the synced height of the key.
+    pub async fn store_key_events(&self, account: u32, events: &[ScanEvent]) -> Result<()> {
+        self.store_account_events(Some(account), events).await
+    }
+
+    async fn store_account_events(&self, account: Option<u32>, events: &[ScanEvent]) -> Result<()> {
         let mut connection = self.pool.acquire().await?;
         let mut db_transaction = connection.begin().await?;
         let db_tx = db_transaction.acquire().await?;
@@ -496,15 +1779,16 @@ impl Db {
                     let (id_tx, is_new) = self
                         .create_tx_if_not_exists(
                             received_note.height,
+                            received_note.time,
                             received_note.txid.as_slice(),
                             db_tx,
                         )
                         .await?;
                     if is_new {
-                        notify_txids.push(received_note.txid);
+                        notify_txids.push((received_note.txid, false));
                     }
 
-                    let (account, sub_account) = match sqlx::query(
+                    let (id_account, sub_account) = match sqlx::query(
                         "SELECT a.account, a.sub_account FROM addresses a
                         JOIN receivers r ON a.id_address = r.id_address
                         WHERE r.receiver_address = ?1",
@@ -519,70 +1803,44 @@ impl Db {
                     .await?
                     {
                         Some(x) => x,
-                        None => {
-                            let account = sqlx::query("SELECT MAX(account) FROM addresses")
-                                .map(|r: SqliteRow| {
-                                    let account: Option<u32> = r.get(0);
-                                    account.unwrap_or_default()
-                                })
-                                .fetch_one(&mut *db_tx)
-                                .await?;
-                            let sub_account = sqlx::query(
-                                "SELECT MAX(sub_account) FROM addresses WHERE account = ?1",
-                            )
-                            .bind(account)
-                            .map(|r: SqliteRow| {
-                                let sub_account: Option<u32> = r.get(0);
-                                sub_account.map(|x| x + 1).unwrap_or_default()
-                            })
-                            .fetch_optional(&mut *db_tx)
-                            .await?
-                            .unwrap_or_default();
-
-                            let r = sqlx::query(
-                                "INSERT INTO addresses
-                            (label, account, sub_account, address, diversifier_index)
-                            VALUES ('', ?1, ?2, ?3, ?4)",
-                            )
-                            .bind(account)
-                            .bind(sub_account)
-                            .bind(&received_note.address)
-                            .bind(received_note.diversifier_index.unwrap_or_default() as u32)
-                            .execute(&mut *db_tx)
-                            .await?;
-                            let id_address = r.last_insert_rowid() as u32;
-
-                            sqlx::query(
-                                "INSERT INTO receivers(pool, id_address, receiver_address)
-                                VALUES (?1, ?2, ?3)",
-                            )
-                            .bind(received_note.pool)
-                            .bind(id_address)
-                            .bind(&received_note.address)
-                            .execute(&mut *db_tx)
-                            .await?;
-
-                            (account, sub_account)
-                        }
+                        None => self.assign_receiver(db_tx, account, received_note).await?,
                     };
+                    // Paid after the expiry of the address
+                    let (late,): (bool,) = sqlx::query_as(
+                        "SELECT EXISTS (SELECT 1 FROM addresses
+                        WHERE account = ?1 AND sub_account = ?2 AND expires_at < ?3)",
+                    )
+                    .bind(id_account)
+                    .bind(sub_account)
+                    .bind(received_note.time)
+                    .fetch_one(&mut *db_tx)
+                    .await?;
+                    if late {
+                        let txid = received_note.txid;
+                        if let Some(tx) = notify_txids.iter_mut().find(|(t, _)| *t == txid) {
+                            tx.1 = true;
+                        }
+                    }
 
                     sqlx::query(
                         "INSERT INTO received_notes
-                        (address, account, sub_account, id_tx, position, height,
-                        diversifier, value, rcm, nf, rho, memo, spent)
-                        VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,'',0)",
+                        (address, account, sub_account, id_tx, pool, position, height,
+                        diversifier, value, rcm, nf, rho, memo, spent, late)
+                        VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,'',NULL,?13)",
                     )
                     .bind(&received_note.address)
-                    .bind(account)
+                    .bind(id_account)
                     .bind(sub_account)
                     .bind(id_tx)
+                    .bind(received_note.pool)
                     .bind(received_note.position)
                     .bind(received_note.height)
                     .bind(received_note.diversifier.as_slice())
                     .bind(received_note.value as i64)
                     .bind(received_note.rcm.as_slice())
-                    .bind(received_note.nf.as_slice())
+                    .bind(received_note.nf.map(|nf| nf.to_vec()))
                     .bind(received_note.rho.map(|r| r.to_vec()))
+                    .bind(late)
                     .execute(&mut *db_tx)
                     .await?;
                     sqlx::query("UPDATE transactions SET value = value + ?2 WHERE txid = ?1")
@@ -592,19 +1850,25 @@ impl Db {
                         .await?;
                 }
                 ScanEvent::Spent(spent_note) => {
-                    let (_, is_new) = self.create_tx_if_not_exists(
-                        spent_note.height,
-                        spent_note.txid.as_slice(),
-                        db_tx,
-                    )
-                    .await?;
+                    let (id_tx, is_new) = self
+                        .create_tx_if_not_exists(
+                            spent_note.height,
+                            spent_note.time,
+                            spent_note.txid.as_slice(),
+                            db_tx,
+                        )
+                        .await?;
                     if is_new {
-                        notify_txids.push(spent_note.txid);
+                        notify_txids.push((spent_note.txid, false));
                     }
-                    sqlx::query("UPDATE received_notes SET spent = TRUE WHERE nf = ?1")
-                        .bind(spent_note.nf.as_slice())
-                        .execute(&mut *db_tx)
-                        .await?;
+                    sqlx::query(
+                        "UPDATE received_notes SET spent = ?2, spent_id_tx = ?3 WHERE nf = ?1",
+                    )
+                    .bind(spent_note.nf.as_slice())
+                    .bind(spent_note.height)
+                    .bind(id_tx)
+                    .execute(&mut *db_tx)
+                    .await?;
                     sqlx::query("UPDATE transactions SET value = value - ?2 WHERE txid = ?1")
                         .bind(spent_note.txid.as_slice())
                         .bind(spent_note.value as i64)
@@ -612,32 +1876,69 @@ impl Db {
                         .await?;
                 }
                 ScanEvent::Memo(memo_note) => {
-                    sqlx::query("UPDATE received_notes SET memo = ?2 WHERE nf = ?1")
-                        .bind(memo_note.nf.as_slice())
-                        .bind(&memo_note.memo)
-                        .execute(&mut *db_tx)
-                        .await?;
-                }
-                ScanEvent::Block(height, hash) => {
                     sqlx::query(
-                        "INSERT INTO blocks(height, hash)
-                        VALUES (?1, ?2)",
+                        "UPDATE received_notes SET memo = ?3 WHERE pool = ?1 AND position = ?2",
                     )
-                    .bind(*height)
-                    .bind(hash.as_slice())
+                    .bind(memo_note.pool)
+                    .bind(memo_note.position)
+                    .bind(&memo_note.memo)
                     .execute(&mut *db_tx)
                     .await?;
                 }
+                ScanEvent::Fee(txid, fee) => {
+                    sqlx::query("UPDATE transactions SET fee = ?2 WHERE txid = ?1")
+                        .bind(txid.as_slice())
+                        .bind(*fee as i64)
+                        .execute(&mut *db_tx)
+                        .await?;
+                }
+                ScanEvent::Block(height, hash, max_time) => match account {
+                    None => {
+                        sqlx::query(
+                            "INSERT INTO blocks(height, hash, max_time)
+                            VALUES (?1, ?2, ?3)",
+                        )
+                        .bind(*height)
+                        .bind(hash.as_slice())
+                        .bind(*max_time)
+                        .execute(&mut *db_tx)
+                        .await?;
+                    }
+                    Some(account) => {
+                        // Kept for the balances at a time, unless the scan
+                        // of the wallet stored the block
+                        sqlx::query(
+                            "INSERT OR IGNORE INTO blocks(height, hash, max_time)
+                            VALUES (?1, ?2, ?3)",
+                        )
+                        .bind(*height)
+                        .bind(hash.as_slice())
+                        .bind(*max_time)
+                        .execute(&mut *db_tx)
+                        .await?;
+                        sqlx::query(
+                            "UPDATE viewing_keys SET synced_height = ?2, synced_hash = ?3
+                            WHERE account = ?1",
+                        )
+                        .bind(account)
+                        .bind(*height)
+                        .bind(hash.as_slice())
+                        .execute(&mut *db_tx)
+                        .await?;
+                    }
+                },
             }
         }
         db_transaction.commit().await?;
+        if events.iter().any(|e| matches!(e, ScanEvent::Received(_))) {
+            self.transactions_stored.notify_one();
+        }
 
         // Once committed, we can notify our listeners of the new received
         // txs
-        for txid in notify_txids {
-            // notify_tx(&txid, &self.notify_tx_url).await?;
+        for (txid, late) in notify_txids {
             if let Some(notifier) = &self.notifier {
-                notifier.notify_tx(&txid).await?;
+                notifier.notify_tx(&txid, late).await?;
             }
         }
 
@@ -647,6 +1948,7 @@ impl Db {
     pub async fn create_tx_if_not_exists(
         &self,
         height: u32,
+        time: u32,
         txid: &[u8],
         db_tx: &mut SqliteConnection,
     ) -> Result<(u32, bool)> {
@@ -659,12 +1961,15 @@ impl Db {
         {
             Some(id_tx) => (id_tx, false),
             None => {
-                let r =
-                    sqlx::query("INSERT INTO transactions(txid, height, value) VALUES (?1, ?2, 0)")
-                        .bind(txid)
-                        .bind(height)
-                        .execute(db_tx)
-                        .await?;
+                let r = sqlx::query(
+                    "INSERT INTO transactions(txid, height, timestamp, value)
+                    VALUES (?1, ?2, ?3, 0)",
+                )
+                .bind(txid)
+                .bind(height)
+                .bind(time)
+                .execute(db_tx)
+                .await?;
                 let id_tx = r.last_insert_rowid();
 
                 (id_tx as u32, true)
@@ -674,7 +1979,672 @@ impl Db {
         Ok(result)
     }
 
-    pub fn ufvk(&self) -> &UnifiedFullViewingKey {
-        &self.ufvk
+    /// Viewing key of the wallet, shared by the accounts that were not
+    /// imported
+    pub fn key(&self) -> &ViewingKey {
+        &self.key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VK: &str = "uviewregtest10lkfv9ck80w7hc50x02fkzwl004glax8gtyg6n3edgy5ld34xvutln5zwlezpmtadv9v2jge0damef7egg8tk93xncq73k0fdzpfrecpzmres8ucz82m8h9ephp53vasten7xrf95h9egdhyg2fqu2qz3hgyy0k6tny6d28m5duuzk72ma0nfr2y5cxqwjscspsdm5qkaafc9edtpzapmfxgzcdkqr60atx32g6q8fxhhh9n0hueslvzy04xyx5353nmmxx2k7uxwdv6t9y626f0d03lgufgkct3gkyxp4u24xdz9l5jsa5ne8cw9s5cjqernqj7xqmwzuc7lad6c7ayqk2ry3e66qea5pmq32a9v4spfswmtsvklljmd0fc4pk8f32g7snzxyrlmnkguch3execr9kqx02a6dc2ryuzvrg8vrrfxjkve6tpyk4vfz9j2zkuws9g5e06wm744yzsye3w74qwjrn5t2rzqfn6zmr8fgkjea8c";
+
+    async fn open_db(name: &str) -> Result<Db> {
+        let path = std::env::temp_dir().join(format!("zcash-walletd-{name}.db"));
+        let _ = std::fs::remove_file(&path);
+        let key = ViewingKey::decode(&Network::Regtest, VK).unwrap();
+        Db::new(Network::Regtest, path.to_str().unwrap(), &key, None).await
+    }
+
+    async fn test_db(name: &str) -> Result<Db> {
+        let db = open_db(name).await?;
+        db.create().await?;
+        db.new_account("", &AddressOptions::default()).await?;
+        Ok(db)
+    }
+
+    fn received_note(pool: u8, position: u32, height: u32, address: &str, tag: u8) -> ReceivedNote {
+        ReceivedNote {
+            txid: [tag; 32],
+            pool,
+            position,
+            height,
+            time: 1_700_000_000 + height,
+            address: address.to_string(),
+            diversifier: [0u8; 11],
+            diversifier_index: Some(0u32.into()),
+            value: 1000 * (tag as u64 + 1),
+            rcm: [tag; 32],
+            nf: Some([tag; 32]),
+            rho: if pool == 2 { Some([tag; 32]) } else { None },
+        }
+    }
+
+    async fn receivers(db: &Db) -> Result<Vec<(u8, String)>> {
+        let receivers = sqlx::query("SELECT pool, receiver_address FROM receivers ORDER BY pool")
+            .map(|r: SqliteRow| (r.get::<u32, _>(0) as u8, r.get::<String, _>(1)))
+            .fetch_all(&db.pool)
+            .await?;
+        Ok(receivers)
+    }
+
+    #[tokio::test]
+    async fn notes_of_both_pools_at_same_position() -> Result<()> {
+        let db = test_db("same-position").await?;
+        let receivers = receivers(&db).await?;
+        assert_eq!(receivers.len(), 2);
+
+        let events = receivers
+            .iter()
+            .enumerate()
+            .map(|(i, (pool, address))| {
+                ScanEvent::Received(received_note(*pool, 5, 10, address, i as u8))
+            })
+            .collect::<Vec<_>>();
+        db.store_events(&events).await?;
+
+        let notes = sqlx::query("SELECT pool, position FROM received_notes ORDER BY pool")
+            .map(|r: SqliteRow| (r.get::<u32, _>(0), r.get::<u32, _>(1)))
+            .fetch_all(&db.pool)
+            .await?;
+        assert_eq!(notes, vec![(1, 5), (2, 5)]);
+
+        // The same note of the same pool is still rejected
+        let (pool, address) = &receivers[0];
+        let duplicate = [ScanEvent::Received(received_note(*pool, 5, 11, address, 9))];
+        assert!(db.store_events(&duplicate).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn truncate_keeps_addresses_and_restores_spends() -> Result<()> {
+        let db = test_db("truncate").await?;
+        let (pool, address) = receivers(&db).await?.remove(0);
+
+        let note = received_note(pool, 1, 10, &address, 0);
+        let nf = note.nf.unwrap();
+        db.store_events(&[
+            ScanEvent::Received(note),
+            ScanEvent::Block(10, [10; 32], 10),
+        ])
+        .await?;
+        db.store_events(&[
+            ScanEvent::Received(received_note(pool, 2, 20, &address, 1)),
+            ScanEvent::Spent(crate::scan::SpentNote {
+                height: 20,
+                time: 1_700_000_020,
+                pool,
+                nf,
+                txid: [1; 32],
+                value: 1000,
+            }),
+            ScanEvent::Block(20, [20; 32], 20),
+        ])
+        .await?;
+        assert_eq!(db.get_nfs().await?.len(), 1);
+
+        db.truncate_height(10).await?;
+
+        assert_eq!(db.get_synced_height().await?, 10);
+        assert!(db.get_block_hash(20).await?.is_none());
+        let nfs = db.get_nfs().await?;
+        assert_eq!(nfs.len(), 1);
+        assert!(nfs.contains_key(&nf));
+        assert_eq!(db.get_addresses().await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_spent_flags_once() -> Result<()> {
+        let db = test_db("migrate-spent").await?;
+        let (pool, address) = receivers(&db).await?.remove(0);
+        db.store_events(&[
+            ScanEvent::Received(received_note(pool, 1, 10, &address, 0)),
+            ScanEvent::Block(10, [10; 32], 10),
+        ])
+        .await?;
+        let spent = || async {
+            let (spent,): (Option<u32>,) = sqlx::query_as("SELECT spent FROM received_notes")
+                .fetch_one(&db.pool)
+                .await?;
+            anyhow::Ok(spent)
+        };
+
+        // A note flagged spent by an older version gets its own height
+        sqlx::query("DELETE FROM properties WHERE name = 'spent_heights'")
+            .execute(&db.pool)
+            .await?;
+        sqlx::query("UPDATE received_notes SET spent = 1")
+            .execute(&db.pool)
+            .await?;
+        db.create().await?;
+        assert_eq!(spent().await?, Some(10));
+
+        // Once migrated, the heights are left alone
+        sqlx::query("UPDATE received_notes SET spent = 1")
+            .execute(&db.pool)
+            .await?;
+        db.create().await?;
+        assert_eq!(spent().await?, Some(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_read_only_needs_the_current_schema() -> Result<()> {
+        let db = test_db("read-only").await?;
+        let key = db.key().clone();
+        let open = |name: &str| {
+            let path = std::env::temp_dir().join(format!("zcash-walletd-{name}.db"));
+            let path = path.to_str().unwrap().to_string();
+            let key = key.clone();
+            async move { Db::open_read_only(Network::Regtest, &path, &key).await }
+        };
+        let read_only = open("read-only").await?;
+        assert_eq!(read_only.get_addresses().await?.len(), 1);
+        assert!(read_only
+            .new_account("", &AddressOptions::default())
+            .await
+            .is_err());
+
+        sqlx::query("ALTER TABLE transactions DROP COLUMN fee")
+            .execute(&db.pool)
+            .await?;
+        let e = open("read-only").await.err().unwrap();
+        assert!(e.to_string().contains("transactions.fee"));
+
+        // A missing database is not created
+        let missing = std::env::temp_dir().join("zcash-walletd-read-only-missing.db");
+        let _ = std::fs::remove_file(&missing);
+        assert!(open("read-only-missing").await.is_err());
+        assert!(!missing.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn balances_at_height() -> Result<()> {
+        let db = test_db("balances-at").await?;
+        db.new_sub_account(0, "shop", &AddressOptions::default(), None)
+            .await?;
+        let sapling_receiver = |sub_account: u32| {
+            sqlx::query(
+                "SELECT r.receiver_address FROM receivers r
+                JOIN addresses a ON a.id_address = r.id_address
+                WHERE a.sub_account = ?1 AND r.pool = 1",
+            )
+            .bind(sub_account)
+            .map(|r: SqliteRow| r.get::<String, _>(0))
+            .fetch_one(&db.pool)
+        };
+        let base = sapling_receiver(0).await?;
+        let shop = sapling_receiver(1).await?;
+
+        let note = received_note(1, 1, 10, &base, 0);
+        let nf = note.nf.unwrap();
+        db.store_events(&[
+            ScanEvent::Received(note),
+            ScanEvent::Received(received_note(1, 2, 15, &shop, 1)),
+            ScanEvent::Block(10, [10; 32], 1_700_000_010),
+            ScanEvent::Block(15, [15; 32], 1_700_000_030),
+        ])
+        .await?;
+        // The next scan starts its running maximum from the time of the
+        // block before it, earlier than a block of the first scan
+        db.store_events(&[
+            ScanEvent::Spent(crate::scan::SpentNote {
+                height: 20,
+                time: 1_700_000_020,
+                pool: 1,
+                nf,
+                txid: [2; 32],
+                value: 1000,
+            }),
+            ScanEvent::Block(20, [20; 32], 1_700_000_020),
+        ])
+        .await?;
+
+        // Times are compared through their running maximum: block 20 is
+        // not picked before 1_700_000_030
+        assert_eq!(db.height_at_time(1_700_000_009).await?, None);
+        assert_eq!(db.height_at_time(1_700_000_025).await?, Some(10));
+        assert_eq!(db.height_at_time(1_700_000_030).await?, Some(20));
+
+        // Blocks stored without time by an older version
+        sqlx::query("UPDATE blocks SET max_time = NULL WHERE height = 15")
+            .execute(&db.pool)
+            .await?;
+        assert!(db.height_at_time(1_700_000_025).await.is_err());
+        assert_eq!(db.height_at_time(1_700_000_030).await?, Some(20));
+
+        let db = &db;
+        let balances = |height: u32| async move {
+            let accounts = db.get_balances_at(height).await?;
+            let balances: Vec<_> = accounts
+                .iter()
+                .flat_map(|a| a.sub_accounts.iter().map(|s| (s.address_index, s.balance)))
+                .collect();
+            Ok::<_, anyhow::Error>(balances)
+        };
+        assert!(balances(9).await?.is_empty());
+        assert_eq!(balances(10).await?, vec![(0, 1000)]);
+        assert_eq!(balances(19).await?, vec![(0, 1000), (1, 2000)]);
+        // Spent at 20
+        assert_eq!(balances(20).await?, vec![(1, 2000)]);
+
+        let accounts = db.get_balances_at(19).await?;
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].balance, 3000);
+        assert_eq!(accounts[0].sub_accounts[1].label, "shop");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_lookups_back_off() -> Result<()> {
+        let db = test_db("rate-lookups").await?;
+        let (pool, address) = receivers(&db).await?.remove(0);
+        db.store_events(&[ScanEvent::Received(received_note(pool, 1, 10, &address, 0))])
+            .await?;
+
+        let now = 1_800_000_000;
+        let unrated = db.get_unrated_transactions(now).await?;
+        assert_eq!(unrated.len(), 1);
+        let (id_tx, time) = unrated[0];
+        assert_eq!(time, 1_700_000_010);
+
+        // The delay doubles after each lookup without a rate
+        db.set_rate_unavailable(id_tx, now).await?;
+        assert!(db.get_unrated_transactions(now + 59).await?.is_empty());
+        assert_eq!(db.get_unrated_transactions(now + 60).await?.len(), 1);
+        db.set_rate_unavailable(id_tx, now).await?;
+        assert!(db.get_unrated_transactions(now + 60).await?.is_empty());
+        assert_eq!(db.get_unrated_transactions(now + 120).await?.len(), 1);
+
+        // Then the lookups stop
+        for _ in 2..RATE_MAX_ATTEMPTS {
+            db.set_rate_unavailable(id_tx, now).await?;
+        }
+        assert!(db
+            .get_unrated_transactions(u32::MAX as u64)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_scanned_payments() -> Result<()> {
+        use crate::testing::{receivers, MockLightwalletd, TestChain, TestTx};
+
+        let db = test_db("store-scanned").await?;
+        let address = db.get_addresses().await?.remove(0).address;
+        let (sapling, orchard) = receivers(&address);
+
+        let mut chain = TestChain::new(100, 7);
+        chain.add_block(vec![TestTx::new()
+            .sapling_output_with_memo(&sapling.unwrap(), 10_000, "invoice 1")
+            .orchard_output_with_memo(&orchard.unwrap(), 20_000, "invoice 2")]);
+        chain.add_empty_blocks(2);
+        let lwd = MockLightwalletd::new(chain);
+
+        let prev_hash = lwd.block_hash(100).await?;
+        let (mut sap_dec, mut orc_dec) = crate::scan::decoders(db.key(), &HashMap::new());
+        let events = crate::scan::scan(
+            &Network::Regtest,
+            &lwd,
+            101,
+            103,
+            &prev_hash,
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+        db.store_events(&events).await?;
+
+        assert_eq!(db.get_synced_height().await?, 103);
+        let transfers = db.get_transfers(103, 0, &[0], 1).await?;
+        let mut transfers: Vec<_> = transfers
+            .iter()
+            .map(|t| (t.amount, t.note.as_str(), t.height, t.confirmations))
+            .collect();
+        transfers.sort();
+        assert_eq!(
+            transfers,
+            vec![(10_000, "invoice 1", 101, 3), (20_000, "invoice 2", 101, 3)]
+        );
+        assert_eq!(db.get_nfs().await?.len(), 2);
+
+        // A tip reported below the notes, e.g. by a lagging server, and a
+        // threshold above the chain do not underflow
+        let transfers = db.get_transfers(100, 0, &[0], 1).await?;
+        assert!(transfers.iter().all(|t| t.confirmations == 0));
+        let accounts = db.get_accounts(5, 10).await?;
+        assert_eq!(accounts[0].unlocked_balance, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn assign_unknown_receivers() -> Result<()> {
+        use crate::{testing::receivers, AddressType};
+        use zip32::DiversifierIndex;
+
+        let db = test_db("unknown-receivers").await?;
+        let legacy = AddressOptions {
+            address_type: AddressType::Sapling,
+            transparent: false,
+        };
+        db.new_sub_account(0, "legacy", &legacy, None).await?;
+        let (index,): (i64,) =
+            sqlx::query_as("SELECT diversifier_index FROM addresses WHERE sub_account = 1")
+                .fetch_one(&db.pool)
+                .await?;
+        let orchard_at = |di: DiversifierIndex| {
+            let address = db
+                .key()
+                .to_uivk()
+                .orchard()
+                .as_ref()
+                .unwrap()
+                .address_at(di);
+            UnifiedAddress::from_receivers(Some(address), None, None)
+                .unwrap()
+                .encode(&Network::Regtest)
+        };
+
+        // The Orchard receiver of the legacy address goes to its sub-account
+        let mut generated = received_note(2, 1, 10, &orchard_at((index as u64).into()), 1);
+        generated.diversifier_index = Some((index as u64).into());
+        // No address was handed out at these indices
+        let mut orphan = received_note(2, 2, 10, &orchard_at(1000u32.into()), 2);
+        orphan.diversifier_index = Some(1000u32.into());
+        let large_index = DiversifierIndex::from([0xff; 11]);
+        let mut large = received_note(2, 3, 10, &orchard_at(large_index), 3);
+        large.diversifier_index = Some(large_index);
+        let (orphan_address, large_address) = (orphan.address.clone(), large.address.clone());
+        let events = [generated, orphan, large].map(ScanEvent::Received);
+        db.store_events(&events).await?;
+
+        let accounts = sqlx::query(
+            "SELECT address, account, sub_account FROM received_notes ORDER BY position",
+        )
+        .map(|r: SqliteRow| {
+            (
+                r.get::<String, _>(0),
+                r.get::<u32, _>(1),
+                r.get::<u32, _>(2),
+            )
+        })
+        .fetch_all(&db.pool)
+        .await?;
+        assert_eq!(accounts[0].1, 0);
+        assert_eq!(accounts[0].2, 1);
+        assert_eq!(accounts[1], (orphan_address.clone(), 1, 0));
+        assert_eq!(accounts[2], (large_address.clone(), 1, 1));
+        let (stored, full): (i64, Vec<u8>) = sqlx::query_as(
+            "SELECT diversifier_index, full_diversifier_index FROM addresses WHERE address = ?1",
+        )
+        .bind(&large_address)
+        .fetch_one(&db.pool)
+        .await?;
+        assert_eq!(stored, -1);
+        assert_eq!(full, vec![0xff; 11]);
+
+        // A paid index is skipped, orphans do not move the next index
+        let options = AddressOptions::default();
+        let (_, taken) = db.key().find_address((index as u64 + 1).into(), &options)?;
+        let mut paid = received_note(2, 4, 11, &orchard_at(taken), 4);
+        paid.diversifier_index = Some(taken);
+        db.store_events(&[ScanEvent::Received(paid)]).await?;
+        let next = db.new_sub_account(0, "", &options, None).await?;
+        assert!(receivers(&next.address).0.is_some());
+        let (next_index,): (i64,) = sqlx::query_as(
+            "SELECT diversifier_index FROM addresses WHERE account = 0 AND sub_account = ?1",
+        )
+        .bind(next.sub_account_index)
+        .fetch_one(&db.pool)
+        .await?;
+        let taken = u64::try_from(taken).unwrap();
+        assert!(next_index as u64 > taken && next_index < 1000);
+        let e = db
+            .new_sub_account(1, "", &AddressOptions::default(), None)
+            .await
+            .unwrap_err();
+        let e = e.downcast_ref::<WalletError>();
+        assert!(matches!(e, Some(WalletError::InvalidArgument(_))));
+
+        assert_eq!(db.reassign_receiver(&orphan_address, 0, 2).await?, 1);
+        let (account, sub_account): (u32, u32) =
+            sqlx::query_as("SELECT account, sub_account FROM received_notes WHERE address = ?1")
+                .bind(&orphan_address)
+                .fetch_one(&db.pool)
+                .await?;
+        assert_eq!((account, sub_account), (0, 2));
+        let (orphans,): (u32,) =
+            sqlx::query_as("SELECT COUNT(*) FROM addresses WHERE address = ?1")
+                .bind(&orphan_address)
+                .fetch_one(&db.pool)
+                .await?;
+        assert_eq!(orphans, 0);
+        let e = db
+            .reassign_receiver(&orphan_address, 0, 1)
+            .await
+            .unwrap_err();
+        let e = e.downcast_ref::<WalletError>();
+        assert!(matches!(e, Some(WalletError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn address_pool_reservation() -> Result<()> {
+        use crate::AddressType;
+
+        let db = test_db("address-pool").await?;
+        assert_eq!(db.fill_address_pool(3).await?, 3);
+        let (pooled,): (String,) =
+            sqlx::query_as("SELECT address FROM address_pool ORDER BY diversifier_index LIMIT 1")
+                .fetch_one(&db.pool)
+                .await?;
+
+        let sub_account = db
+            .new_sub_account(0, "", &AddressOptions::default(), None)
+            .await?;
+        assert_eq!(sub_account.address, pooled);
+        assert_eq!(db.address_pool_depth().await?, 2);
+
+        // A pooled address paid before being handed out is skipped
+        let (paid_index, paid_address): (i64, String) = sqlx::query_as(
+            "SELECT diversifier_index, address FROM address_pool
+            ORDER BY diversifier_index LIMIT 1",
+        )
+        .fetch_one(&db.pool)
+        .await?;
+        let receiver = db
+            .key()
+            .receiver(&Network::Regtest, 2, paid_index as u64)
+            .unwrap();
+        let mut paid = received_note(2, 1, 10, &receiver, 1);
+        paid.diversifier_index = Some((paid_index as u64).into());
+        db.store_events(&[ScanEvent::Received(paid)]).await?;
+        assert_eq!(db.address_pool_depth().await?, 1);
+        let sub_account = db
+            .new_sub_account(0, "", &AddressOptions::default(), None)
+            .await?;
+        assert_ne!(sub_account.address, paid_address);
+        assert_eq!(db.fill_address_pool(3).await?, 3);
+        let (paid,): (bool,) =
+            sqlx::query_as("SELECT paid FROM address_pool WHERE diversifier_index = ?1")
+                .bind(paid_index)
+                .fetch_one(&db.pool)
+                .await?;
+        assert!(paid);
+
+        // Other receiver sets are derived after the pooled addresses
+        let orchard = AddressOptions {
+            address_type: AddressType::Orchard,
+            transparent: false,
+        };
+        db.new_sub_account(0, "", &orchard, None).await?;
+        let (max_pooled, max_used): (i64, i64) = sqlx::query_as(
+            "SELECT (SELECT MAX(diversifier_index) FROM address_pool),
+            (SELECT MAX(diversifier_index) FROM addresses)",
+        )
+        .fetch_one(&db.pool)
+        .await?;
+        assert!(max_used > max_pooled);
+        assert_eq!(db.fill_address_pool(3).await?, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_another_viewing_key() -> Result<()> {
+        let db = test_db("check-key").await?;
+        db.new_sub_account(0, "", &AddressOptions::default(), None)
+            .await?;
+        let path = std::env::temp_dir().join("zcash-walletd-check-key.db");
+        let reopen = |key: ViewingKey| {
+            let path = path.to_str().unwrap().to_string();
+            async move {
+                let db = Db::new(Network::Regtest, &path, &key, None).await?;
+                db.create().await
+            }
+        };
+        let other = ViewingKey::Full(crate::testing::ufvk_from_seed(1));
+        let e = reopen(other.clone()).await.unwrap_err();
+        assert!(e.to_string().contains("fingerprint"));
+        assert!(reopen(db.key().clone()).await?);
+
+        // Without a fingerprint, the addresses are checked
+        sqlx::query("DELETE FROM properties")
+            .execute(&db.pool)
+            .await?;
+        let e = reopen(other).await.unwrap_err();
+        assert!(e.to_string().contains("diversifier index"));
+        assert!(reopen(db.key().clone()).await?);
+        let (fingerprint,): (String,) =
+            sqlx::query_as("SELECT value FROM properties WHERE name = 'fingerprint'")
+                .fetch_one(&db.pool)
+                .await?;
+        assert_eq!(fingerprint, db.key().fingerprint(&Network::Regtest));
+
+        // The incoming key of VK opens the same database
+        assert!(reopen(ViewingKey::Incoming(db.key().to_uivk())).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_received_addresses() -> Result<()> {
+        let db = open_db("migrate-orphans").await?;
+        sqlx::query(
+            "CREATE TABLE addresses (
+            id_address INTEGER PRIMARY KEY,
+            label TEXT NOT NULL,
+            account INTEGER NOT NULL,
+            sub_account INTEGER NOT NULL,
+            address TEXT NOT NULL,
+            diversifier_index INTEGER NOT NULL)",
+        )
+        .execute(&db.pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE receivers (
+            id_receiver INTEGER PRIMARY KEY,
+            pool INTEGER NOT NULL,
+            id_address INTEGER NOT NULL,
+            receiver_address TEXT NOT NULL)",
+        )
+        .execute(&db.pool)
+        .await?;
+        // A generated address and a payment received at an address of
+        // another key, stored with a made up diversifier index
+        let generated = db.key().receiver(&Network::Regtest, 2, 0).unwrap();
+        let other = ViewingKey::Full(crate::testing::ufvk_from_seed(1));
+        let received = other.receiver(&Network::Regtest, 2, 7).unwrap();
+        sqlx::query(
+            "INSERT INTO addresses(id_address, label, account, sub_account, address,
+            diversifier_index)
+            VALUES (1, '', 0, 0, 'u-generated', 0), (2, '', 0, 1, ?1, 7)",
+        )
+        .bind(&received)
+        .execute(&db.pool)
+        .await?;
+        sqlx::query(
+            "INSERT INTO receivers(pool, id_address, receiver_address)
+            VALUES (2, 1, ?1), (2, 2, ?2)",
+        )
+        .bind(&generated)
+        .bind(&received)
+        .execute(&db.pool)
+        .await?;
+
+        assert!(db.create().await?);
+
+        let addresses = sqlx::query(
+            "SELECT id_address, orphan, diversifier_index FROM addresses ORDER BY id_address",
+        )
+        .map(|r: SqliteRow| (r.get::<u32, _>(0), r.get::<bool, _>(1), r.get::<i64, _>(2)))
+        .fetch_all(&db.pool)
+        .await?;
+        assert_eq!(addresses, vec![(1, false, 0), (2, true, -1)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_notes_without_pool() -> Result<()> {
+        let db = open_db("migrate-pool").await?;
+        sqlx::query(
+            "CREATE TABLE received_notes (
+            id_note INTEGER PRIMARY KEY,
+            address TEXT NOT NULL,
+            account INTEGER,
+            sub_account INTEGER,
+            id_tx INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            diversifier BLOB NOT NULL,
+            value INTEGER NOT NULL,
+            rcm BLOB NOT NULL,
+            nf BLOB NOT NULL UNIQUE,
+            rho BLOB,
+            memo TEXT,
+            spent INTEGER,
+            CONSTRAINT tx_output UNIQUE (position))",
+        )
+        .execute(&db.pool)
+        .await?;
+        sqlx::query(
+            "INSERT INTO received_notes
+            (address, account, sub_account, id_tx, position, height,
+            diversifier, value, rcm, nf, rho, memo, spent)
+            VALUES ('zs', 0, 0, 1, 1, 10, x'00', 1, x'00', x'01', NULL, '', 0),
+            ('u', 0, 0, 1, 2, 12, x'00', 1, x'00', x'02', x'02', '', 1)",
+        )
+        .execute(&db.pool)
+        .await?;
+
+        db.create().await?;
+
+        let notes =
+            sqlx::query("SELECT pool, position, spent FROM received_notes ORDER BY position")
+                .map(|r: SqliteRow| {
+                    (
+                        r.get::<u32, _>(0),
+                        r.get::<u32, _>(1),
+                        r.get::<Option<u32>, _>(2),
+                    )
+                })
+                .fetch_all(&db.pool)
+                .await?;
+        // The legacy spend is placed at the height of the note
+        assert_eq!(notes, vec![(1, 1, None), (2, 2, Some(12))]);
+
+        Ok(())
     }
-}
\ No newline at end of file
+}
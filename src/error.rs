@@ -0,0 +1,89 @@
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+    Request,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Errors returned by the REST API. Each variant has a stable code,
+/// negative like the monero wallet RPC error codes, and an HTTP status.
+///
+/// Wallet methods return `anyhow::Error`, a `WalletError` inside it keeps
+/// its code when converted back.
+#[derive(Debug, Error)]
+pub enum WalletError {
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    InvalidAddress(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("{0}")]
+    NotSynced(String),
+    #[error("{0}")]
+    BackendUnavailable(String),
+    #[error("{0:#}")]
+    Internal(anyhow::Error),
+}
+
+pub const UNKNOWN_ERROR: i32 = -1;
+pub const INVALID_ADDRESS: i32 = -2;
+pub const BACKEND_UNAVAILABLE: i32 = -3;
+pub const DENIED: i32 = -7;
+pub const NOT_FOUND: i32 = -8;
+pub const INVALID_ARGUMENT: i32 = -12;
+pub const NOT_SYNCED: i32 = -13;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub code: i32,
+    pub message: String,
+}
+
+impl WalletError {
+    pub fn code(&self) -> i32 {
+        match self {
+            WalletError::Internal(_) => UNKNOWN_ERROR,
+            WalletError::InvalidAddress(_) => INVALID_ADDRESS,
+            WalletError::BackendUnavailable(_) => BACKEND_UNAVAILABLE,
+            WalletError::NotFound(_) => NOT_FOUND,
+            WalletError::InvalidArgument(_) => INVALID_ARGUMENT,
+            WalletError::NotSynced(_) => NOT_SYNCED,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            WalletError::NotFound(_) => Status::NotFound,
+            WalletError::InvalidAddress(_) | WalletError::InvalidArgument(_) => Status::BadRequest,
+            WalletError::NotSynced(_) | WalletError::BackendUnavailable(_) => {
+                Status::ServiceUnavailable
+            }
+            WalletError::Internal(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<anyhow::Error> for WalletError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<WalletError>() {
+            Ok(error) => error,
+            Err(error) => WalletError::Internal(error),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for WalletError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if let WalletError::Internal(error) = &self {
+            log::error!("{} failed: {error:#}", request.uri());
+        }
+        let body = ErrorResponse {
+            code: self.code(),
+            message: self.to_string(),
+        };
+        (self.status(), Json(body)).respond_to(request)
+    }
+}
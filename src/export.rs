@@ -0,0 +1,233 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletError;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(WalletError::InvalidArgument(format!("Unknown export format {s}")).into()),
+        }
+    }
+}
+
+/// Blocks to export, by height and by date (`YYYY-MM-DD`, UTC). Both
+/// bounds are inclusive, missing bounds are open.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct ExportRange {
+    pub from_height: Option<u32>,
+    pub to_height: Option<u32>,
+    pub from_date: Option<String>,
+    pub to_date: Option<String>,
+}
+
+impl ExportRange {
+    pub fn heights(&self) -> (u32, u32) {
+        (
+            self.from_height.unwrap_or(0),
+            self.to_height.unwrap_or(u32::MAX),
+        )
+    }
+
+    /// Unix times of the start of `from_date` and of the end of `to_date`
+    pub fn times(&self) -> Result<(u64, u64)> {
+        let from = match &self.from_date {
+            Some(date) => parse_date(date)?,
+            None => 0,
+        };
+        let to = match &self.to_date {
+            Some(date) => parse_date(date)? + SECONDS_PER_DAY - 1,
+            None => i64::MAX as u64,
+        };
+        Ok((from, to))
+    }
+}
+
+/// A note received (`in`) or spent (`out`) by the wallet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportRow {
+    pub r#type: String,
+    /// Empty for the spends stored by older versions
+    pub txid: String,
+    pub height: u32,
+    /// Block time, 0 when unknown
+    pub timestamp: u64,
+    pub account_index: u32,
+    pub address_index: u32,
+    pub label: String,
+    pub address: String,
+    pub pool: String,
+    pub amount: u64,
+    /// Fee of the transaction, known when it is fully shielded and paid
+    /// the wallet
+    pub fee: Option<u64>,
+    pub memo: String,
+    pub confirmations: u32,
+    pub confirmed: bool,
+    /// Fiat rate of ZEC at the block time, if known
+    pub rate: Option<f64>,
+}
+
+const CSV_HEADER: &str = "type,txid,height,timestamp,account_index,address_index,label,address,\
+    pool,amount,fee,memo,confirmations,confirmed,rate";
+
+pub fn to_csv(rows: &[ExportRow]) -> String {
+    let mut csv = String::new();
+    csv.push_str(CSV_HEADER);
+    csv.push('\n');
+    for row in rows {
+        let fields = [
+            row.r#type.clone(),
+            row.txid.clone(),
+            row.height.to_string(),
+            row.timestamp.to_string(),
+            row.account_index.to_string(),
+            row.address_index.to_string(),
+            csv_field(&row.label),
+            row.address.clone(),
+            row.pool.clone(),
+            row.amount.to_string(),
+            row.fee.map(|fee| fee.to_string()).unwrap_or_default(),
+            csv_field(&row.memo),
+            row.confirmations.to_string(),
+            row.confirmed.to_string(),
+            row.rate.map(|rate| rate.to_string()).unwrap_or_default(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quote fields with separators, quotes or line breaks. Fields that a
+/// spreadsheet would read as a formula get a `'` prefix.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Unix time of the start of a `YYYY-MM-DD` day, UTC
+pub fn parse_date(date: &str) -> Result<u64> {
+    let invalid =
+        || WalletError::InvalidArgument(format!("Invalid date {date}, expected YYYY-MM-DD"));
+    let parts: Vec<_> = date.split('-').collect();
+    let [y, m, d] = parts.as_slice() else {
+        return Err(invalid().into());
+    };
+    let y: i64 = y.parse().map_err(|_| invalid())?;
+    let m: i64 = m.parse().map_err(|_| invalid())?;
+    let d: i64 = d.parse().map_err(|_| invalid())?;
+    if y < 1970 || !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return Err(invalid().into());
+    }
+    // Days since the epoch of a proleptic Gregorian date, with years
+    // starting in March
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Ok(days as u64 * SECONDS_PER_DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() -> Result<()> {
+        assert_eq!(parse_date("1970-01-01")?, 0);
+        assert_eq!(parse_date("2000-03-01")?, 951_868_800);
+        assert_eq!(parse_date("2024-02-29")?, 1_709_164_800);
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("01/02/2024").is_err());
+
+        let range = ExportRange {
+            to_date: Some("1970-01-01".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(range.times()?, (0, 86_399));
+        Ok(())
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let row = ExportRow {
+            r#type: "in".to_string(),
+            txid: "00".to_string(),
+            height: 101,
+            timestamp: 0,
+            account_index: 0,
+            address_index: 1,
+            label: "shop, main".to_string(),
+            address: "zs1".to_string(),
+            pool: "sapling".to_string(),
+            amount: 1000,
+            fee: Some(10_000),
+            memo: "say \"hi\"".to_string(),
+            confirmations: 1,
+            confirmed: true,
+            rate: Some(30.5),
+        };
+        let csv = to_csv(&[row]);
+        let line = csv.lines().nth(1).unwrap();
+        assert_eq!(
+            line,
+            "in,00,101,0,0,1,\"shop, main\",zs1,sapling,1000,10000,\"say \"\"hi\"\"\",1,true,30.5"
+        );
+    }
+
+    #[test]
+    fn csv_formulas() {
+        let row = ExportRow {
+            r#type: "in".to_string(),
+            txid: "00".to_string(),
+            height: 101,
+            timestamp: 0,
+            account_index: 0,
+            address_index: 1,
+            label: "@SUM(A1)".to_string(),
+            address: "zs1".to_string(),
+            pool: "sapling".to_string(),
+            amount: 1000,
+            fee: None,
+            memo: "=HYPERLINK(\"http://evil\",\"refund\")".to_string(),
+            confirmations: 1,
+            confirmed: true,
+            rate: None,
+        };
+        let csv = to_csv(&[row]);
+        let line = csv.lines().nth(1).unwrap();
+        assert_eq!(
+            line,
+            "in,00,101,0,0,1,'@SUM(A1),zs1,sapling,1000,,\
+            \"'=HYPERLINK(\"\"http://evil\"\",\"\"refund\"\")\",1,true,"
+        );
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("\tx"), "'\tx");
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use zcash_keys::{
+    address::UnifiedAddress,
+    encoding::AddressCodec,
+    keys::{
+        ReceiverRequirement, UnifiedAddressRequest, UnifiedFullViewingKey,
+        UnifiedIncomingViewingKey,
+    },
+};
+use zip32::DiversifierIndex;
+
+use crate::{error::WalletError, network::Network, AddressOptions, AddressType, BalanceMode};
+
+/// Unified viewing key of a wallet or of an imported account
+#[derive(Clone)]
+pub enum ViewingKey {
+    Full(UnifiedFullViewingKey),
+    /// Without the nullifier keys, spends of the notes are not detected
+    Incoming(UnifiedIncomingViewingKey),
+}
+
+impl ViewingKey {
+    /// Decode a UFVK or a UIVK. The key must have a Sapling or an Orchard
+    /// component.
+    pub fn decode(network: &Network, encoding: &str) -> Result<Self> {
+        let key = match UnifiedFullViewingKey::decode(network, encoding) {
+            Ok(ufvk) => ViewingKey::Full(ufvk),
+            Err(_) => match UnifiedIncomingViewingKey::decode(network, encoding) {
+                Ok(uivk) => ViewingKey::Incoming(uivk),
+                Err(_) => {
+                    return Err(WalletError::InvalidArgument(
+                        "Invalid Unified Viewing Key".to_string(),
+                    )
+                    .into())
+                }
+            },
+        };
+        let uivk = key.to_uivk();
+        if uivk.sapling().is_none() && uivk.orchard().is_none() {
+            return Err(WalletError::InvalidArgument(
+                "The viewing key has neither a Sapling nor an Orchard component".to_string(),
+            )
+            .into());
+        }
+        Ok(key)
+    }
+
+    pub fn encode(&self, network: &Network) -> String {
+        match self {
+            ViewingKey::Full(ufvk) => ufvk.encode(network),
+            ViewingKey::Incoming(uivk) => uivk.encode(network),
+        }
+    }
+
+    pub fn to_uivk(&self) -> UnifiedIncomingViewingKey {
+        match self {
+            ViewingKey::Full(ufvk) => ufvk.to_unified_incoming_viewing_key(),
+            ViewingKey::Incoming(uivk) => uivk.clone(),
+        }
+    }
+
+    pub fn ufvk(&self) -> Option<&UnifiedFullViewingKey> {
+        match self {
+            ViewingKey::Full(ufvk) => Some(ufvk),
+            ViewingKey::Incoming(_) => None,
+        }
+    }
+
+    pub fn balance_mode(&self) -> BalanceMode {
+        match self {
+            ViewingKey::Full(_) => BalanceMode::Unspent,
+            ViewingKey::Incoming(_) => BalanceMode::ReceivedTotals,
+        }
+    }
+
+    /// Same incoming keys, whether given as a UFVK or a UIVK
+    pub fn same_as(&self, other: &ViewingKey, network: &Network) -> bool {
+        self.to_uivk().encode(network) == other.to_uivk().encode(network)
+    }
+
+    /// Address with the receivers of `options` at the first diversifier
+    /// index from `index` valid for all of them
+    pub fn find_address(
+        &self,
+        index: DiversifierIndex,
+        options: &AddressOptions,
+    ) -> Result<(UnifiedAddress, DiversifierIndex)> {
+        use ReceiverRequirement::{Allow, Omit, Require};
+        let (orchard, sapling) = match options.address_type {
+            AddressType::Unified => (Allow, Allow),
+            AddressType::Orchard => (Require, Omit),
+            AddressType::SaplingOrchard => (Require, Require),
+            AddressType::Sapling => (Omit, Require),
+        };
+        // The scanner only sees shielded outputs
+        if options.transparent {
+            return Err(WalletError::InvalidArgument(
+                "Payments to transparent receivers are not detected".to_string(),
+            )
+            .into());
+        }
+        let request = UnifiedAddressRequest::custom(orchard, sapling, Omit)
+            .map_err(|_| WalletError::InvalidArgument("Invalid set of receivers".to_string()))?;
+        let address = match self {
+            ViewingKey::Full(ufvk) => ufvk.find_address(index, request),
+            ViewingKey::Incoming(uivk) => uivk.find_address(index, request),
+        };
+        let address = address.map_err(|e| {
+            WalletError::InvalidArgument(format!("Cannot derive the requested receivers: {e}"))
+        })?;
+        Ok(address)
+    }
+
+    /// Receiver of `pool` at the diversifier index `index`, encoded as in
+    /// the `receivers` table: a Sapling address, or a unified address with
+    /// only the Orchard receiver
+    pub fn receiver(&self, network: &Network, pool: u8, index: u64) -> Option<String> {
+        let uivk = self.to_uivk();
+        match pool {
+            1 => {
+                let ivk = uivk.sapling().as_ref()?;
+                let address = ivk.address(index.into())?;
+                Some(address.encode(network))
+            }
+            2 => {
+                let ivk = uivk.orchard().as_ref()?;
+                let address = ivk.address_at(index);
+                let ua = UnifiedAddress::from_receivers(Some(address), None, None)?;
+                Some(ua.encode(network))
+            }
+            _ => None,
+        }
+    }
+
+    /// Hash of the network and of the incoming key, stored in the database
+    /// to detect a change of `VK`. A UFVK and its UIVK have the same
+    /// fingerprint.
+    pub fn fingerprint(&self, network: &Network) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{network:?}:{}", self.to_uivk().encode(network)));
+        hex::encode(hasher.finalize())
+    }
+}
@@ -2,20 +2,62 @@
 pub mod lwd_rpc;
 
 mod account;
+pub mod auth;
+mod cache;
+mod chain;
+pub mod coordinator;
 mod db;
+pub mod error;
+pub mod export;
+mod keys;
+pub mod lwd;
+pub mod metrics;
 pub mod monitor;
 mod network;
-mod notifier;
+mod node;
+pub mod notifier;
+pub mod rates;
+mod record;
 pub mod rpc;
 mod scan;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod transaction;
 
-use std::{path::Path, sync::Arc};
-use anyhow::{anyhow, Result};
-use figment::{providers::{Env, Format, Json, Serialized}, Figment};
+use crate::{
+    account::{AccountBalance, HistoricalBalance, LatePayment, SubAccount},
+    cache::{BlockCache, CachedSource},
+    chain::ChainSource,
+    coordinator::{ScanCoordinator, ScanJob, ScanJobState},
+    db::Db,
+    error::WalletError,
+    export::{ExportFormat, ExportRange},
+    keys::ViewingKey,
+    lwd::{LwdPool, LwdServerStatus},
+    lwd_rpc::compact_tx_streamer_client::CompactTxStreamerClient,
+    monitor::{MonitorTask, ScanStatus},
+    network::Network,
+    node::NodeSource,
+    notifier::{HttpNotifier, TxNotifier},
+    rates::RateProvider,
+    record::{RecordingSource, ReplaySource},
+    scan::{ScanError, WalletScan},
+    transaction::Transfer,
+};
+use anyhow::{anyhow, Context};
+use figment::{
+    providers::{Env, Format, Json, Serialized},
+    Figment,
+};
 use rocket::{Build, Rocket};
-use serde::{Serialize, Deserialize};
-use tonic::{transport::Channel, Request};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tonic::transport::Channel;
 use tracing::info;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{
@@ -24,8 +66,10 @@ use tracing_subscriber::{
     util::SubscriberInitExt as _,
     EnvFilter, Layer, Registry,
 };
-use crate::{account::{AccountBalance, SubAccount}, db::Db, lwd_rpc::{compact_tx_streamer_client::CompactTxStreamerClient, Empty}, monitor::MonitorTask, network::Network, notifier::{HttpNotifier, TxNotifier}, scan::{get_latest_height, Decoder, Orchard, Sapling, ScanError}, transaction::Transfer};
-use zcash_client_backend::keys::UnifiedFullViewingKey;
+use zcash_keys::{
+    address::{Address, UnifiedAddress},
+    encoding::AddressCodec,
+};
 
 pub type Hash = [u8; 32];
 pub type Client = CompactTxStreamerClient<Channel>;
@@ -35,16 +79,76 @@ pub struct WalletConfig {
     pub port: Option<u16>,
     pub db_path: String,
     pub confirmations: u32,
+    /// Comma separated list of lightwalletd URLs, in order of preference
+    #[serde(default)]
     pub lwd_url: String,
+    /// Timeout of each lightwalletd call in seconds
+    pub lwd_timeout: Option<u64>,
+    /// PEM file of additional TLS root certificates
+    pub lwd_tls_ca: Option<String>,
+    /// Number of blocks a server may lag behind the best tip
+    pub lwd_max_lag: Option<u32>,
+    /// JSON-RPC URL of zcashd or zebrad, used instead of lightwalletd
+    pub node_rpc_url: Option<String>,
+    pub node_rpc_user: Option<String>,
+    pub node_rpc_password: Option<String>,
+    /// Append the chain data used by the scanner to this fixture file
+    pub record_path: Option<String>,
+    /// Serve the chain from a recorded fixture instead of a backend
+    pub replay_path: Option<String>,
+    /// SQLite file of the local compact block cache, disabled if unset
+    pub block_cache_path: Option<String>,
+    /// Comma separated API keys with admin scope
+    pub api_keys: Option<String>,
+    /// Comma separated API keys that can only read the wallet
+    pub read_only_api_keys: Option<String>,
+    /// `user:password` for HTTP Digest authentication with admin scope
+    pub rpc_login: Option<String>,
+    /// `user:password` for HTTP Digest authentication, read only
+    pub read_only_rpc_login: Option<String>,
     pub notify_tx_url: String,
     pub poll_interval: u16,
+    pub min_poll_interval: Option<u16>,
     pub regtest: bool,
     pub orchard: bool,
     pub vk: String,
     pub birth_height: u32,
+    /// Number of addresses of each wallet derived in advance, 0 to disable
+    pub address_pool_size: Option<u32>,
+    /// CSV or JSON file of ZEC fiat rates, see `rates::FileRateProvider`
+    pub rate_file: Option<String>,
+    /// URL of a rate service, see `rates::HttpRateProvider`
+    pub rate_url: Option<String>,
+    /// Wallets hosted next to the default one, see `HostedWalletConfig`
+    #[serde(default)]
+    pub wallets: Vec<HostedWalletConfig>,
+}
+
+/// A wallet with its own viewing key and database, selected in the API
+/// by a `/wallets/<id>` prefix
+#[derive(Deserialize, Debug, Clone)]
+pub struct HostedWalletConfig {
+    pub id: String,
+    pub vk: String,
+    pub birth_height: u32,
+    pub db_path: String,
+    /// Overrides `notify_tx_url` for the transactions of this wallet
+    pub notify_tx_url: Option<String>,
+    /// Comma separated API keys with admin scope on this wallet only
+    pub api_keys: Option<String>,
+    /// Comma separated API keys that can only read this wallet
+    pub read_only_api_keys: Option<String>,
 }
 
 impl WalletConfig {
+    pub fn lwd_urls(&self) -> Vec<String> {
+        self.lwd_url
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect()
+    }
+
     pub fn network(&self) -> Network {
         if self.regtest {
             Network::Regtest
@@ -53,14 +157,46 @@ impl WalletConfig {
         }
     }
 }
+pub const DEFAULT_WALLET_ID: &str = "default";
+
+#[derive(Clone)]
+struct HostedWallet {
+    db: Arc<Db>,
+    birth_height: u32,
+}
+
+/// The daemon and a view on one of its wallets. Wallet methods apply to
+/// the default wallet, or to the one returned by `for_wallet`. Scans
+/// cover every wallet.
 #[derive(Clone)]
 pub struct ZcashWalletd {
     db: Arc<Db>,
+    birth_height: u32,
+    wallets: Arc<BTreeMap<String, HostedWallet>>,
     pub config: Arc<WalletConfig>,
+    status: Arc<Mutex<ScanStatus>>,
+    scans: Arc<ScanCoordinator>,
+    chain: Arc<dyn ChainSource>,
+    block_cache: Option<Arc<BlockCache>>,
+    rates: Option<Arc<dyn RateProvider>>,
 }
 
 impl ZcashWalletd {
     pub async fn init(rocket: Option<&Rocket<Build>>) -> anyhow::Result<Self> {
+        let config = Self::load_config(rocket)?;
+        let tx_notifier = match rocket {
+            Some(_) => {
+                let http = HttpNotifier::new(config.notify_tx_url.clone(), true)?;
+                Some(Arc::new(http) as Arc<dyn TxNotifier>)
+            }
+            None => None,
+        };
+        Self::new(config, tx_notifier).await
+    }
+
+    /// Configuration from the Rocket settings, the environment and the
+    /// `CONFIG_PATH` file
+    pub fn load_config(rocket: Option<&Rocket<Build>>) -> anyhow::Result<WalletConfig> {
         dotenv::dotenv().ok();
         // env_logger::init();
         let config_path = dotenv::var("CONFIG_PATH")
@@ -70,11 +206,10 @@ impl ZcashWalletd {
             .with(default_layer())
             .with(env_layer())
             .try_init();
-        
+
         let mut figment = match rocket {
-        Some(r) => r.figment().clone(),
-        None => Figment::new()
-            .merge(Serialized::default("port", None::<u16>)),
+            Some(r) => r.figment().clone(),
+            None => Figment::new().merge(Serialized::default("port", None::<u16>)),
         };
 
         figment = figment.merge(Env::raw());
@@ -84,110 +219,340 @@ impl ZcashWalletd {
             figment = figment.merge(Json::file(config_path));
         }
 
-        let config: WalletConfig = figment.extract().unwrap();
+        let config: WalletConfig = figment.extract()?;
         info!("Config {config:?}");
+        Ok(config)
+    }
+
+    /// Open the wallet databases and connect to the chain backend
+    pub async fn new(
+        config: WalletConfig,
+        tx_notifier: Option<Arc<dyn TxNotifier>>,
+    ) -> anyhow::Result<Self> {
         let network = config.network();
-        assert!(config.orchard);
+        if !config.orchard {
+            anyhow::bail!("Orchard must be enabled");
+        }
 
-        let ufvk = &config.vk;
-        let birth_height = config.birth_height;
-        let ufvk = UnifiedFullViewingKey::decode(&network, ufvk)
-            .map_err(|_| anyhow!("Invalid Unified Viewing Key"))?;
-        
-        let tx_notifier = match rocket {
-            Some(_) => {
-                let http = HttpNotifier::new(config.notify_tx_url.clone(), true)?;
-                Some(Arc::new(http) as Arc<dyn TxNotifier>)
+        let mut wallets = BTreeMap::new();
+        let db = Self::open_db(network, &config.db_path, &config.vk, tx_notifier.clone()).await?;
+        wallets.insert(
+            DEFAULT_WALLET_ID.to_string(),
+            HostedWallet {
+                db: Arc::new(db),
+                birth_height: config.birth_height,
+            },
+        );
+        for hosted in config.wallets.iter() {
+            if wallets.contains_key(&hosted.id) {
+                anyhow::bail!("Duplicate wallet id {}", hosted.id);
+            }
+            let notifier = match &hosted.notify_tx_url {
+                Some(url) => {
+                    let http = HttpNotifier::new(url.clone(), true)?;
+                    Some(Arc::new(http) as Arc<dyn TxNotifier>)
+                }
+                None => tx_notifier.clone(),
+            };
+            let db = Self::open_db(network, &hosted.db_path, &hosted.vk, notifier).await?;
+            wallets.insert(
+                hosted.id.clone(),
+                HostedWallet {
+                    db: Arc::new(db),
+                    birth_height: hosted.birth_height,
+                },
+            );
+        }
+
+        let mut chain: Arc<dyn ChainSource> = match (&config.replay_path, &config.node_rpc_url) {
+            (Some(path), _) => Arc::new(ReplaySource::open(path)?),
+            (None, Some(url)) => Arc::new(NodeSource::new(&config, url)?),
+            (None, None) => Arc::new(LwdPool::new(&config)?),
+        };
+        if let Some(path) = &config.record_path {
+            info!("Recording chain data to {path}");
+            chain = Arc::new(RecordingSource::new(chain, path)?);
+        }
+        let block_cache = match &config.block_cache_path {
+            Some(path) => {
+                let cache = Arc::new(BlockCache::open(path).await?);
+                let cached = Arc::new(CachedSource::new(chain, cache.clone()));
+                let mut from = u32::MAX;
+                for wallet in wallets.values() {
+                    from = from.min(wallet.db.get_synced_height().await?);
+                }
+                cached.spawn_prefetch(from, Duration::from_secs(config.poll_interval as u64));
+                chain = cached;
+                Some(cache)
             }
             None => None,
         };
-        let db = Db::new(network, &config.db_path, &ufvk, tx_notifier).await?;
-        let db_exists = db.create().await?;
+        for wallet in wallets.values() {
+            wallet
+                .db
+                .fetch_block_hash(&*chain, wallet.birth_height)
+                .await?;
+        }
+        let default = wallets[DEFAULT_WALLET_ID].clone();
+        let rates = rates::from_config(&config)?;
+
+        let walletd = Self {
+            db: default.db,
+            birth_height: default.birth_height,
+            wallets: Arc::new(wallets),
+            config: Arc::new(config),
+            status: Arc::new(Mutex::new(ScanStatus::default())),
+            scans: Arc::new(ScanCoordinator::default()),
+            chain,
+            block_cache,
+            rates,
+        };
+        // Resume the scans of the keys imported before a restart
+        for (id, wallet) in walletd.wallets.iter() {
+            let view = walletd.view(wallet);
+            view.spawn_catch_up();
+            view.spawn_address_pool(id);
+            view.spawn_rates();
+        }
+        Ok(walletd)
+    }
+
+    async fn open_db(
+        network: Network,
+        db_path: &str,
+        vk: &str,
+        tx_notifier: Option<Arc<dyn TxNotifier>>,
+    ) -> anyhow::Result<Db> {
+        let key = ViewingKey::decode(&network, vk)?;
+        if key.ufvk().is_none() {
+            tracing::warn!(
+                "{db_path}: incoming viewing key only, spends are not detected \
+                and balances are received totals"
+            );
+        }
+
+        let db = Db::new(network, db_path, &key, tx_notifier).await?;
+        let db_exists = db
+            .create()
+            .await
+            .with_context(|| format!("Cannot open the database {db_path}"))?;
         if !db_exists {
-            db.new_account("").await?;
+            db.new_account("", &AddressOptions::default()).await?;
         }
-        let mut client = CompactTxStreamerClient::connect(config.lwd_url.clone()).await?;
-        db.fetch_block_hash(&mut client, birth_height).await?;
+        Ok(db)
+    }
 
-        Ok (
-            Self {
-                db: Arc::new(db),
-                config: Arc::new(config),
-            }
-        )
+    /// View on the wallet `id`
+    pub fn for_wallet(&self, id: &str) -> anyhow::Result<Self> {
+        let wallet = self
+            .wallets
+            .get(id)
+            .ok_or(WalletError::NotFound(format!("Unknown wallet {id}")))?;
+        Ok(self.view(wallet))
     }
 
-    pub fn monitor_task(&self) {
-        let poll_secs = self.config.poll_interval as u64;
-        let _handle = MonitorTask::spawn(self.clone(), poll_secs);
+    pub fn wallet_ids(&self) -> Vec<String> {
+        self.wallets.keys().cloned().collect()
     }
 
-    pub async fn create_account(&self, label: Option<String>) -> anyhow::Result<CreateAccountResponse> {
+    fn view(&self, wallet: &HostedWallet) -> Self {
+        Self {
+            db: wallet.db.clone(),
+            birth_height: wallet.birth_height,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn chain(&self) -> &dyn ChainSource {
+        &*self.chain
+    }
+
+    /// Wait for the next rewind of a hosted wallet
+    pub(crate) async fn rewound(&self) {
+        self.scans.rewound().await
+    }
+
+    pub fn monitor_task(&self) -> MonitorTask {
+        let min_poll = Duration::from_secs(
+            self.config
+                .min_poll_interval
+                .unwrap_or(DEFAULT_MIN_POLL_INTERVAL) as u64,
+        );
+        let max_poll = Duration::from_secs(self.config.poll_interval as u64);
+        MonitorTask::spawn(self.clone(), min_poll, max_poll.max(min_poll))
+    }
+
+    pub async fn create_account(
+        &self,
+        label: Option<String>,
+        options: AddressOptions,
+    ) -> anyhow::Result<CreateAccountResponse> {
         let name = label.unwrap_or("".to_string());
-        let account = self.db.new_account(&name).await?;
-        
-        Ok(
-            CreateAccountResponse {
-                account_index: account.account_index,
-                address: account.address
-            }
-        )
+        let account = self.db.new_account(&name, &options).await?;
+        let receivers = AddressReceivers::decode(&self.config.network(), &account.address);
+
+        Ok(CreateAccountResponse {
+            account_index: account.account_index,
+            address: account.address,
+            receivers,
+        })
     }
 
+    /// New address of `account_index`. An address with `expires_at` (unix
+    /// time) becomes inactive after it if unpaid, later payments to it are
+    /// flagged as late.
     pub async fn create_address(
         &self,
         account_index: u32,
-        label: Option<String>
+        label: Option<String>,
+        options: AddressOptions,
+        expires_at: Option<u64>,
     ) -> anyhow::Result<CreateAddressResponse> {
         let name = label.unwrap_or("".to_string());
-        let sub_account = self.db.new_sub_account(account_index, &name).await?;
+        let sub_account = self
+            .db
+            .new_sub_account(account_index, &name, &options, expires_at)
+            .await?;
+        let receivers = AddressReceivers::decode(&self.config.network(), &sub_account.address);
 
-        Ok(
-            CreateAddressResponse {
-                address: sub_account.address.clone(),
-                address_index: sub_account.sub_account_index
-            }
-        )
+        Ok(CreateAddressResponse {
+            address: sub_account.address.clone(),
+            address_index: sub_account.sub_account_index,
+            receivers,
+            expires_at,
+        })
     }
 
-    pub async fn get_accounts(&self, _tag: Option<String>) -> anyhow::Result<GetAccountsResponse> {
-        let mut client = CompactTxStreamerClient::connect(self.config.lwd_url.clone())
+    /// Add a UFVK or UIVK as a new account. Its notes from `birth_height`
+    /// are found by a background scan, the key then joins the scans at the
+    /// chain tip.
+    pub async fn import_viewing_key(
+        &self,
+        key: &str,
+        birth_height: u32,
+        label: Option<String>,
+    ) -> anyhow::Result<ImportViewingKeyResponse> {
+        let key = ViewingKey::decode(&self.config.network(), key)?;
+        let latest_height = self.latest_height().await?;
+        if birth_height > latest_height {
+            return Err(WalletError::InvalidArgument(format!(
+                "Birth height {birth_height} is above the chain tip {latest_height}"
+            ))
+            .into());
+        }
+        let birth_hash = self
+            .chain
+            .block_hash(birth_height)
             .await
-            .map_err(from_tonic)?;
-        let latest_height = get_latest_height(&mut client).await?;
-        let sub_accounts = self.db.get_accounts(latest_height, self.config.confirmations).await?;
+            .map_err(|e| WalletError::BackendUnavailable(format!("{e:#}")))?;
+        let name = label.unwrap_or("".to_string());
+        let account = self
+            .db
+            .import_viewing_key(&key, &name, birth_height, &birth_hash)
+            .await?;
+        info!(
+            "Imported viewing key as account {} from {birth_height}",
+            account.account_index
+        );
+        self.spawn_catch_up();
+
+        Ok(ImportViewingKeyResponse {
+            account_index: account.account_index,
+            address: account.address,
+            birth_height,
+            balance_mode: key.balance_mode(),
+        })
+    }
+
+    /// Move the payments to `address`, a receiver the wallet did not hand
+    /// out, to the sub-account `address_index` of `account_index`
+    pub async fn reassign_address(
+        &self,
+        address: &str,
+        account_index: u32,
+        address_index: u32,
+    ) -> anyhow::Result<ReassignAddressResponse> {
+        let notes = self
+            .db
+            .reassign_receiver(address, account_index, address_index)
+            .await?;
+        Ok(ReassignAddressResponse { notes })
+    }
+
+    /// Expired addresses that received payments after their expiry, to be
+    /// refunded
+    pub async fn get_late_payments(&self) -> anyhow::Result<GetLatePaymentsResponse> {
+        let payments = self.db.get_late_payments().await?;
+        Ok(GetLatePaymentsResponse { payments })
+    }
+
+    pub async fn get_accounts(&self, _tag: Option<String>) -> anyhow::Result<GetAccountsResponse> {
+        let latest_height = self.latest_height().await?;
+        let sub_accounts = self
+            .db
+            .get_accounts(latest_height, self.config.confirmations)
+            .await?;
         let total_balance: u64 = sub_accounts.iter().map(|sa| sa.balance).sum();
         let total_unlocked_balance: u64 = sub_accounts.iter().map(|sa| sa.unlocked_balance).sum();
 
-        Ok(
-            GetAccountsResponse {
-                subaddress_accounts: sub_accounts,
-                total_balance,
-                total_unlocked_balance,
+        Ok(GetAccountsResponse {
+            subaddress_accounts: sub_accounts,
+            total_balance,
+            total_unlocked_balance,
+        })
+    }
+
+    /// Balances at a past `height`, or at the highest scanned block mined
+    /// at or before `timestamp`
+    pub async fn get_balance_at(
+        &self,
+        height: Option<u32>,
+        timestamp: Option<u64>,
+    ) -> anyhow::Result<GetBalanceAtResponse> {
+        let synced_height = self.db.get_synced_height().await?;
+        let height = match (height, timestamp) {
+            (Some(height), None) => height,
+            (None, Some(timestamp)) => match self.db.height_at_time(timestamp).await? {
+                Some(height) => height,
+                None => self.birth_height.saturating_sub(1),
+            },
+            _ => {
+                return Err(WalletError::InvalidArgument(
+                    "Pass either a height or a timestamp".to_string(),
+                )
+                .into())
             }
-        )    
+        };
+        if height > synced_height {
+            return Err(WalletError::NotSynced(format!(
+                "The wallet is synced to {synced_height}, below {height}"
+            ))
+            .into());
+        }
+        let accounts = self.db.get_balances_at(height).await?;
+        let total_balance = accounts.iter().map(|a| a.balance).sum();
+
+        Ok(GetBalanceAtResponse {
+            height,
+            total_balance,
+            accounts,
+        })
     }
 
     pub async fn get_addresses(&self) -> anyhow::Result<GetAddressesResponse> {
         let addresses = self.db.get_addresses().await?;
 
-        Ok(
-            GetAddressesResponse {
-                addresses
-            }
-        )
+        Ok(GetAddressesResponse { addresses })
     }
 
     pub async fn get_transaction(
         &self,
         txid: String,
-        account_index: u32
-    ) -> anyhow::Result<GetTransactionByIdResponse>{
-        let mut client = CompactTxStreamerClient::connect(self.config.lwd_url.clone())
-            .await
-            .map_err(from_tonic)?;
-        let latest_height = get_latest_height(&mut client).await?;
-        let transfers = self.db
+        account_index: u32,
+    ) -> anyhow::Result<GetTransactionByIdResponse> {
+        let latest_height = self.latest_height().await?;
+        let transfers = self
+            .db
             .get_transfers_by_txid(
                 latest_height,
                 &txid,
@@ -195,28 +560,38 @@ impl ZcashWalletd {
                 self.config.confirmations,
             )
             .await?;
-        
-        Ok(
-            GetTransactionByIdResponse {
-                transfer: transfers[0].clone(),
-                transfers,
+        let Some(transfer) = transfers.first().cloned() else {
+            let synced_height = self.db.get_synced_height().await?;
+            if synced_height < latest_height {
+                return Err(WalletError::NotSynced(format!(
+                    "Transaction {txid} not found, the wallet is synced to {synced_height} of {latest_height}"
+                )).into());
             }
-        )
+            return Err(WalletError::NotFound(format!("Transaction {txid} not found")).into());
+        };
+
+        Ok(GetTransactionByIdResponse {
+            transfer,
+            transfers,
+        })
     }
 
     pub async fn get_transfers(
         &self,
         account_index: u32,
         r#in: bool,
-        subaddr_indices: Vec<u32>
+        subaddr_indices: Vec<u32>,
     ) -> anyhow::Result<GetTransfersResponse> {
-        assert!(r#in);
-        
-        let mut client = CompactTxStreamerClient::connect(self.config.lwd_url.clone())
-            .await
-            .map_err(from_tonic)?;
-        let latest_height = get_latest_height(&mut client).await?;
-        let transfers = self.db
+        if !r#in {
+            return Err(WalletError::InvalidArgument(
+                "Only incoming transfers are supported".to_string(),
+            )
+            .into());
+        }
+
+        let latest_height = self.latest_height().await?;
+        let transfers = self
+            .db
             .get_transfers(
                 latest_height,
                 account_index,
@@ -224,127 +599,630 @@ impl ZcashWalletd {
                 self.config.confirmations,
             )
             .await?;
-        Ok(
-            GetTransfersResponse { r#in: transfers }
-        )
+        Ok(GetTransfersResponse { r#in: transfers })
     }
 
-    pub async fn get_height(&self) -> anyhow::Result<GetHeightResponse> {
-        let mut client = CompactTxStreamerClient::connect(self.config.lwd_url.clone())
-            .await
-            .map_err(from_tonic)?;
-        let latest_height = get_latest_height(&mut client).await?;
-        Ok(
-            GetHeightResponse {
-                height: latest_height,
+    /// Incoming and outgoing notes of every account in `range`, as CSV or
+    /// JSON. Confirmations are counted from the synced height.
+    pub async fn export(
+        &self,
+        range: &ExportRange,
+        format: ExportFormat,
+    ) -> anyhow::Result<String> {
+        Self::export_db(&self.db, self.config.confirmations, range, format).await
+    }
+
+    /// Export of the wallet `wallet_id`, or of the default wallet, read
+    /// from its database alone: the database is opened read only, the
+    /// chain backend is not contacted and no background task is started
+    pub async fn export_offline(
+        config: &WalletConfig,
+        wallet_id: Option<&str>,
+        range: &ExportRange,
+        format: ExportFormat,
+    ) -> anyhow::Result<String> {
+        let (db_path, vk) = match wallet_id.filter(|&id| id != DEFAULT_WALLET_ID) {
+            Some(id) => {
+                let hosted = config
+                    .wallets
+                    .iter()
+                    .find(|w| w.id == id)
+                    .ok_or(WalletError::NotFound(format!("Unknown wallet {id}")))?;
+                (&hosted.db_path, &hosted.vk)
             }
-        )
+            None => (&config.db_path, &config.vk),
+        };
+        if !Path::new(db_path).exists() {
+            anyhow::bail!("No database at {db_path}");
+        }
+        let network = config.network();
+        let key = ViewingKey::decode(&network, vk)?;
+        let db = Db::open_read_only(network, db_path, &key)
+            .await
+            .with_context(|| format!("Cannot open the database {db_path}"))?;
+        Self::export_db(&db, config.confirmations, range, format).await
+    }
+
+    async fn export_db(
+        db: &Db,
+        confirmations: u32,
+        range: &ExportRange,
+        format: ExportFormat,
+    ) -> anyhow::Result<String> {
+        let synced_height = db.get_synced_height().await?;
+        let rows = db
+            .export_rows(
+                range.heights(),
+                range.times()?,
+                synced_height,
+                confirmations,
+            )
+            .await?;
+        let export = match format {
+            ExportFormat::Csv => export::to_csv(&rows),
+            ExportFormat::Json => serde_json::to_string_pretty(&rows)?,
+        };
+        Ok(export)
+    }
+
+    pub async fn get_height(&self) -> anyhow::Result<GetHeightResponse> {
+        let latest_height = self.latest_height().await?;
+        Ok(GetHeightResponse {
+            height: latest_height,
+        })
     }
 
     pub async fn get_wallet_height(&self) -> anyhow::Result<GetHeightResponse> {
         let synced_height = self.db.get_synced_height().await?;
-        Ok(
-            GetHeightResponse {
-                height: synced_height,
-            }
-        )
+        Ok(GetHeightResponse {
+            height: synced_height,
+        })
     }
 
     pub async fn sync_info(&self) -> anyhow::Result<SyncInfoResponse> {
-        let mut client = CompactTxStreamerClient::connect(self.config.lwd_url.clone())
+        let info = self
+            .chain
+            .chain_info()
             .await
-            .map_err(from_tonic)?;
-        let rep = client
-            .get_lightd_info(Request::new(Empty {}))
+            .map_err(|e| WalletError::BackendUnavailable(format!("{e:#}")))?;
+        Ok(SyncInfoResponse {
+            target_height: info.height,
+            height: info.estimated_height,
+        })
+    }
+
+    pub async fn status(&self) -> anyhow::Result<StatusResponse> {
+        let wallet_height = self.db.get_synced_height().await?;
+        let chain_tip = self.probe_lightwalletd().await.ok();
+        let blocks_remaining = chain_tip.map(|tip| tip.saturating_sub(wallet_height));
+        let viewing_keys = self
+            .db
+            .get_viewing_keys()
+            .await?
+            .into_iter()
+            .map(|key| ViewingKeyStatus {
+                account_index: key.account,
+                birth_height: key.birth_height,
+                synced_height: key.synced_height,
+            })
+            .collect();
+        let status = self.status.lock().unwrap();
+        let eta_secs = match (blocks_remaining, status.scan_rate) {
+            (Some(remaining), Some(rate)) if rate > 0.0 => Some((remaining as f64 / rate) as u64),
+            _ => None,
+        };
+
+        Ok(StatusResponse {
+            wallet_height,
+            chain_tip,
+            blocks_remaining,
+            scan_rate: status.scan_rate,
+            eta_secs,
+            last_scan_time: status.last_scan_time,
+            last_scan_error: status.last_scan_error.clone(),
+            lightwalletd_connected: chain_tip.is_some(),
+            lightwalletd_servers: self.chain.servers(),
+            balance_mode: self.db.key().balance_mode(),
+            viewing_keys,
+        })
+    }
+
+    /// The wallet is ready when its database answers and lightwalletd
+    /// is reachable
+    pub async fn is_ready(&self) -> bool {
+        self.db.get_synced_height().await.is_ok() && self.probe_lightwalletd().await.is_ok()
+    }
+
+    async fn probe_lightwalletd(&self) -> anyhow::Result<u32> {
+        tokio::time::timeout(LWD_PROBE_TIMEOUT, self.latest_height()).await?
+    }
+
+    async fn latest_height(&self) -> anyhow::Result<u32> {
+        let height = self
+            .chain
+            .latest_height()
             .await
-            .map_err(from_tonic)?
-            .into_inner();
-        Ok(
-            SyncInfoResponse {
-                target_height: rep.block_height as u32,
-                height: rep.estimated_height as u32,
-            }
-        )
+            .map_err(|e| WalletError::BackendUnavailable(format!("{e:#}")))?;
+        Ok(height)
     }
 
+    /// Scan up to the chain tip, sharing the run with any concurrent
+    /// request
     pub async fn request_scan(&self) -> anyhow::Result<()> {
+        match self.submit_scan().wait().await {
+            ScanJobState::Failed { error } => Err(anyhow!(error)),
+            _ => Ok(()),
+        }
+    }
+
+    /// With `wait`, a failed scan is returned as an error
+    pub async fn request_scan_job(&self, wait: bool) -> anyhow::Result<RequestScanResponse> {
+        let job = self.submit_scan();
+        let job_id = job.id;
+        let state = if wait { job.wait().await } else { job.state() };
+        if let ScanJobState::Failed { error } = &state {
+            anyhow::bail!("Scan job {job_id} failed: {error}");
+        }
+
+        Ok(RequestScanResponse { job_id, state })
+    }
+
+    pub fn get_scan_job(&self, job_id: u64) -> anyhow::Result<RequestScanResponse> {
+        let state = self
+            .scans
+            .job_state(job_id)
+            .ok_or(WalletError::NotFound(format!("Unknown scan job {job_id}")))?;
+
+        Ok(RequestScanResponse { job_id, state })
+    }
+
+    fn submit_scan(&self) -> ScanJob {
+        let (job, start_driver) = self.scans.submit();
+        if start_driver {
+            let wallet = self.clone();
+            tokio::spawn(async move {
+                while let Some((_, tx)) = wallet.scans.next_job() {
+                    let _ = tx.send(ScanJobState::Running);
+                    // In its own task, so that a panic fails the job and
+                    // the driver goes on with the next one
+                    let scan = wallet.clone();
+                    let state = match tokio::spawn(async move { scan.run_scan().await }).await {
+                        Ok(Ok(blocks)) => ScanJobState::Done { blocks },
+                        Ok(Err(e)) => ScanJobState::Failed {
+                            error: format!("{e:#}"),
+                        },
+                        Err(e) => ScanJobState::Failed {
+                            error: format!("Scan aborted: {e}"),
+                        },
+                    };
+                    let _ = tx.send(state);
+                }
+            });
+        }
+        job
+    }
+
+    async fn run_scan(&self) -> anyhow::Result<u32> {
+        let _guard = self.scans.lock.lock().await;
+        let started = Instant::now();
+        let res = self.scan_blocks().await;
+        let mut status = self.status.lock().unwrap();
+        match &res {
+            Ok(blocks) => status.record_success(*blocks, started.elapsed()),
+            Err(e) => status.record_error(e),
+        }
+        res
+    }
+
+    /// Scan every wallet up to the chain tip in a single pass over the
+    /// blocks and return the number of blocks processed
+    async fn scan_blocks(&self) -> anyhow::Result<u32> {
         let network = self.config.network();
-        let ufvk = self.db.ufvk();
-        let start = self.db.get_synced_height().await?;
-        let prev_hash = self.db
-            .get_block_hash(start)
-            .await?
-            .ok_or(anyhow::anyhow!("Block Hash missing from db"))?;
-
-        let nfs = self.db.get_nfs().await?;
-        let mut sap_dec = ufvk.sapling().map(|fvk| {
-            let nk = fvk.fvk().vk.nk;
-            let ivk = fvk.to_ivk(zip32::Scope::External);
-            let pivk = sapling_crypto::keys::PreparedIncomingViewingKey::new(&ivk);
-            // TODO: Load nfs
-            Decoder::<Sapling>::new(nk, fvk.clone(), pivk, &nfs)
-        });
-        let mut orc_dec = ufvk.orchard().map(|fvk| {
-            let ivk = fvk.to_ivk(zip32::Scope::External);
-            let pivk = orchard::keys::PreparedIncomingViewingKey::new(&ivk);
-            // TODO: Load nfs
-            Decoder::<Orchard>::new(fvk.clone(), ivk, pivk, &nfs)
-        });
+        // Database and imported account of each scan
+        let mut targets = vec![];
+        let mut wallets = vec![];
+        for wallet in self.wallets.values() {
+            let db = &wallet.db;
+            let synced_height = db.get_synced_height().await?;
+            let synced_hash = db
+                .get_block_hash(synced_height)
+                .await?
+                .ok_or(anyhow::anyhow!("Block Hash missing from db"))?;
+            let nfs = db.get_nfs().await?;
+            let (sap_dec, orc_dec) = crate::scan::decoders(db.key(), &nfs);
+            targets.push((db.clone(), None));
+            wallets.push(WalletScan::new(
+                synced_height,
+                synced_hash,
+                sap_dec,
+                orc_dec,
+            ));
+
+            // Imported keys still catching up are left to `catch_up_keys`
+            for key in db.get_viewing_keys().await? {
+                if key.synced_height < synced_height {
+                    continue;
+                }
+                let nfs = db.get_key_nfs(key.account).await?;
+                let (sap_dec, orc_dec) = crate::scan::decoders(&key.key, &nfs);
+                targets.push((db.clone(), Some(key.account)));
+                wallets.push(WalletScan::new(
+                    key.synced_height,
+                    key.synced_hash,
+                    sap_dec,
+                    orc_dec,
+                ));
+            }
+        }
+        let start = wallets
+            .iter()
+            .map(|w| w.synced_height)
+            .min()
+            .unwrap_or_default();
+
+        let end = self.chain.latest_height().await?;
 
-        let mut client = CompactTxStreamerClient::connect(self.config.lwd_url.clone())
-            .await
-            .map_err(anyhow::Error::new)?;
-        let end = get_latest_height(&mut client).await?;
-        
         info!("Scan from {start} to {end}");
+        metrics::SYNCED_HEIGHT.set(start as i64);
+        metrics::CHAIN_TIP_LAG.set(end.saturating_sub(start) as i64);
         if start >= end {
-            return Ok(());
+            return Ok(0);
         }
 
-        let res = crate::scan::scan(
-            &network,
-            &mut client,
-            start + 1,
-            end,
-            &prev_hash,
-            &mut sap_dec,
-            &mut orc_dec,
-        )
-        .await;
+        let res = crate::scan::scan_wallets(&network, &*self.chain, end, &mut wallets).await;
         match res {
-            Err(error) =>
             // Rewind if we hit a chain reorg but don't error
-            {
-                match error {
-                    ScanError::Reorganization => {
-                        let synced_height = self.db.get_synced_height().await?;
-                        self.db.truncate_height(synced_height - SAFE_REORG_DISTANCE)
-                            .await
+            Err(ScanError::Reorganization) => {
+                metrics::REORGS.inc();
+                for wallet in self.wallets.values() {
+                    let synced_height = wallet.db.get_synced_height().await?;
+                    self.view(wallet)
+                        .rewind_locked(synced_height.saturating_sub(SAFE_REORG_DISTANCE))
+                        .await?;
+                }
+                Ok(0)
+            }
+            Err(ScanError::Other(error)) => Err(error),
+            Ok(()) => {
+                for ((db, account), scan) in targets.iter().zip(wallets.iter()) {
+                    match account {
+                        None => {
+                            db.store_events(&scan.events).await?;
+                            db.expire_addresses(monitor::unix_time()).await?;
+                        }
+                        Some(account) => db.store_key_events(*account, &scan.events).await?,
                     }
-                    ScanError::Other(error) => Err(error),
-                }?
+                    metrics::record_events(&scan.events);
+                }
+                metrics::BLOCKS_SCANNED.inc_by((end - start) as u64);
+                metrics::SYNCED_HEIGHT.set(end as i64);
+                metrics::CHAIN_TIP_LAG.set(0);
+                Ok(end - start)
             }
+        }
+    }
+
+    /// Scan the imported keys of this wallet that are behind its synced
+    /// height, in the background
+    fn spawn_catch_up(&self) {
+        let wallet = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = wallet.catch_up_keys().await {
+                log::warn!("Scan of the imported keys failed: {e:#}");
+            }
+        });
+    }
+
+    /// Record the fiat rates of the received transactions in the
+    /// background, outside of the scans: after each scan that stored
+    /// some, and every `RATE_CHECK_INTERVAL` for the retries
+    fn spawn_rates(&self) {
+        let Some(rates) = self.rates.clone() else {
+            return;
+        };
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = Self::stamp_rates(&db, &*rates).await {
+                    log::warn!("Cannot record the fiat rates: {e:#}");
+                }
+                tokio::select! {
+                    _ = db.transactions_stored() => {}
+                    _ = tokio::time::sleep(RATE_CHECK_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    /// Keep the address pool of this wallet filled, in the background
+    fn spawn_address_pool(&self, wallet_id: &str) {
+        let size = self
+            .config
+            .address_pool_size
+            .unwrap_or(DEFAULT_ADDRESS_POOL_SIZE);
+        if size == 0 {
+            return;
+        }
+        let db = self.db.clone();
+        let depth = metrics::ADDRESS_POOL_DEPTH.with_label_values(&[wallet_id]);
+        tokio::spawn(async move {
+            loop {
+                match db.fill_address_pool(size).await {
+                    Ok(filled) => depth.set(filled as i64),
+                    Err(e) => {
+                        log::warn!("Cannot fill the address pool: {e:#}");
+                        tokio::time::sleep(ADDRESS_POOL_RETRY_DELAY).await;
+                        continue;
+                    }
+                }
+                db.address_reserved().await;
+                if let Ok(remaining) = db.address_pool_depth().await {
+                    depth.set(remaining as i64);
+                }
+            }
+        });
+    }
+
+    /// Scan the imported keys from their synced height up to the synced
+    /// height of the wallet. Each batch holds the scan lock so the scans
+    /// at the tip run in between. A key only joins them once caught up,
+    /// so that spends are looked for after every note it received.
+    async fn catch_up_keys(&self) -> anyhow::Result<()> {
+        let network = self.config.network();
+        let mut batch_size = CATCH_UP_MIN_BATCH;
+        loop {
+            let _guard = self.scans.lock.lock().await;
+            let started = Instant::now();
+            let synced_height = self.db.get_synced_height().await?;
+            let keys: Vec<_> = self
+                .db
+                .get_viewing_keys()
+                .await?
+                .into_iter()
+                .filter(|key| key.synced_height < synced_height)
+                .collect();
+            let Some(start) = keys.iter().map(|key| key.synced_height).min() else {
+                return Ok(());
+            };
+            let end = synced_height.min(start + batch_size);
 
-            Ok(events) => {
-                self.db.store_events(&events).await?;
+            let mut scans = vec![];
+            for key in keys.iter() {
+                let nfs = self.db.get_key_nfs(key.account).await?;
+                let (sap_dec, orc_dec) = crate::scan::decoders(&key.key, &nfs);
+                scans.push(WalletScan::new(
+                    key.synced_height,
+                    key.synced_hash,
+                    sap_dec,
+                    orc_dec,
+                ));
+            }
+            info!("Scan imported keys from {start} to {end}");
+            match crate::scan::scan_wallets(&network, &*self.chain, end, &mut scans).await {
+                Err(ScanError::Reorganization) => {
+                    metrics::REORGS.inc();
+                    for key in keys.iter() {
+                        let height = key
+                            .synced_height
+                            .saturating_sub(SAFE_REORG_DISTANCE)
+                            .max(key.birth_height);
+                        let hash = self.chain.block_hash(height).await?;
+                        self.db.rewind_key(key.account, height, &hash).await?;
+                    }
+                }
+                Err(ScanError::Other(error)) => return Err(error),
+                Ok(()) => {
+                    for (key, scan) in keys.iter().zip(scans.iter()) {
+                        self.db.store_key_events(key.account, &scan.events).await?;
+                        metrics::record_events(&scan.events);
+                    }
+                }
+            }
+            // Size the next batch to hold the lock for about
+            // `CATCH_UP_BATCH_TIME`
+            let elapsed = started.elapsed();
+            batch_size = if elapsed < CATCH_UP_BATCH_TIME / 2 {
+                batch_size.saturating_mul(2).min(CATCH_UP_MAX_BATCH)
+            } else if elapsed > CATCH_UP_BATCH_TIME {
+                (batch_size / 2).max(CATCH_UP_MIN_BATCH)
+            } else {
+                batch_size
+            };
+        }
+    }
+
+    /// Record the fiat rate at the block time of the received transactions
+    /// that have none yet. Rates that the provider does not have are looked
+    /// up again later, a limited number of times. A failing provider is
+    /// retried on the next call.
+    async fn stamp_rates(db: &Db, rates: &dyn RateProvider) -> anyhow::Result<()> {
+        let now = monitor::unix_time();
+        for (id_tx, time) in db.get_unrated_transactions(now).await? {
+            match rates.rate_at(time).await {
+                Ok(Some(rate)) => db.set_rate(id_tx, rate).await?,
+                Ok(None) => db.set_rate_unavailable(id_tx, now).await?,
+                Err(e) => {
+                    tracing::warn!("Cannot get the rate at {time}: {e:#}");
+                    break;
+                }
             }
         }
         Ok(())
     }
+
+    /// Rewind the wallet to `height`, dropping the blocks, transactions and
+    /// notes above it. The height is clamped to the birth height and
+    /// requests above the synced height are ignored. Returns the new synced
+    /// height.
+    pub async fn rewind_to(&self, height: u32) -> anyhow::Result<RewindResponse> {
+        let _guard = self.scans.lock.lock().await;
+        self.rewind_locked(height).await
+    }
+
+    async fn rewind_locked(&self, height: u32) -> anyhow::Result<RewindResponse> {
+        let height = height.max(self.birth_height);
+        let synced_height = self.db.get_synced_height().await?;
+        if height >= synced_height {
+            return Ok(RewindResponse {
+                height: synced_height,
+            });
+        }
+
+        info!("Rewind from {synced_height} to {height}");
+        // The cached blocks above the height may be orphaned, they are
+        // fetched again by the next scan
+        self.chain.rewind(height).await?;
+        // Store the anchor first so that a backend failure leaves
+        // the database untouched
+        self.db.fetch_block_hash(&*self.chain, height).await?;
+        self.db.truncate_height(height).await?;
+        self.scans.notify_rewind();
+
+        Ok(RewindResponse { height })
+    }
+
+    /// Drop the cached compact blocks below `height`
+    pub async fn prune_block_cache(&self, height: u32) -> anyhow::Result<()> {
+        let cache = self
+            .block_cache
+            .as_ref()
+            .ok_or(anyhow!("The block cache is not enabled"))?;
+        cache.prune(height).await
+    }
+
+    /// Rescan the blockchain from the birth height
+    pub async fn rescan(&self) -> anyhow::Result<RewindResponse> {
+        self.rewind_to(self.birth_height).await
+    }
 }
 
 pub const SAFE_REORG_DISTANCE: u32 = 100u32;
+/// Time the scan of the imported keys holds the scan lock for, between
+/// two batches the scans at the tip can run
+const CATCH_UP_BATCH_TIME: Duration = Duration::from_secs(2);
+/// Bounds of the number of blocks of a catch up batch
+const CATCH_UP_MIN_BATCH: u32 = 100;
+const CATCH_UP_MAX_BATCH: u32 = 10_000;
+const DEFAULT_ADDRESS_POOL_SIZE: u32 = 100;
+const ADDRESS_POOL_RETRY_DELAY: Duration = Duration::from_secs(10);
+const RATE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const LWD_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MIN_POLL_INTERVAL: u16 = 2;
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateAccountResponse {
     pub account_index: u32,
     pub address: String,
+    pub receivers: AddressReceivers,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateAddressResponse {
     pub address: String,
     pub address_index: u32,
+    pub receivers: AddressReceivers,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetLatePaymentsResponse {
+    pub payments: Vec<LatePayment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReassignAddressResponse {
+    /// Number of notes moved
+    pub notes: u32,
+}
+
+/// Receivers of a new address
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressType {
+    /// Unified address with the Sapling and Orchard receivers of the key
+    #[default]
+    Unified,
+    Orchard,
+    SaplingOrchard,
+    /// Legacy `zs` address
+    Sapling,
+}
+
+impl AddressType {
+    /// Name stored in the `addresses` table
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddressType::Unified => "unified",
+            AddressType::Orchard => "orchard",
+            AddressType::SaplingOrchard => "sapling_orchard",
+            AddressType::Sapling => "sapling",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct AddressOptions {
+    pub address_type: AddressType,
+    /// Add a transparent receiver. Refused, since the wallet would not
+    /// detect payments to it. Addresses stored with one keep it.
+    pub transparent: bool,
+}
+
+impl AddressOptions {
+    /// The address is given as a Sapling address rather than a unified
+    /// address
+    pub fn is_legacy(&self) -> bool {
+        self.address_type == AddressType::Sapling && !self.transparent
+    }
+}
+
+/// Individual encodings of the receivers of an address, for payers that do
+/// not support unified addresses
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct AddressReceivers {
+    /// Unified address with only the Orchard receiver
+    pub orchard: Option<String>,
+    pub sapling: Option<String>,
+    pub transparent: Option<String>,
+}
+
+impl AddressReceivers {
+    pub(crate) fn decode(network: &Network, address: &str) -> Self {
+        match Address::decode(network, address) {
+            Some(Address::Unified(ua)) => AddressReceivers {
+                orchard: ua.orchard().and_then(|o| {
+                    let ua = UnifiedAddress::from_receivers(Some(*o), None, None)?;
+                    Some(ua.encode(network))
+                }),
+                sapling: ua.sapling().map(|s| s.encode(network)),
+                transparent: ua.transparent().map(|t| t.encode(network)),
+            },
+            Some(Address::Sapling(s)) => AddressReceivers {
+                sapling: Some(s.encode(network)),
+                ..Default::default()
+            },
+            _ => AddressReceivers::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportViewingKeyResponse {
+    pub account_index: u32,
+    pub address: String,
+    pub birth_height: u32,
+    pub balance_mode: BalanceMode,
+}
+
+/// What the balances count. A wallet or an account with a Unified
+/// Incoming Viewing Key cannot detect spends, its balance is the total
+/// of the notes received.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceMode {
+    Unspent,
+    ReceivedTotals,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -354,9 +1232,16 @@ pub struct GetAccountsResponse {
     pub total_unlocked_balance: u64,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct GetBalanceAtResponse {
+    pub height: u32,
+    pub total_balance: u64,
+    pub accounts: Vec<HistoricalBalance>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetAddressesResponse {
-    pub addresses: Vec<SubAccount>,    
+    pub addresses: Vec<SubAccount>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -392,39 +1277,46 @@ pub struct GetHeightResponse {
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct SyncInfoResponse {
-    pub target_height: u32,
-    pub height: u32,
+pub struct StatusResponse {
+    pub wallet_height: u32,
+    pub chain_tip: Option<u32>,
+    pub blocks_remaining: Option<u32>,
+    /// Blocks per second
+    pub scan_rate: Option<f64>,
+    pub eta_secs: Option<u64>,
+    pub last_scan_time: Option<u64>,
+    pub last_scan_error: Option<String>,
+    pub lightwalletd_connected: bool,
+    pub lightwalletd_servers: Vec<LwdServerStatus>,
+    /// Balance mode of the wallet key
+    pub balance_mode: BalanceMode,
+    /// Scan progress of the imported viewing keys
+    pub viewing_keys: Vec<ViewingKeyStatus>,
 }
 
-pub async fn notify_tx(txid: &[u8], notify_tx_url: &str) -> Result<()> {
-    let mut txid = txid.to_vec();
-    txid.reverse();
-    let txid = hex::encode(&txid);
-    info!("Notify tx {}", &txid);
-
-    let url = notify_tx_url.to_string() + &txid;
-    // TODO: Remove self signed certificate accept
-    let res = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
-        .build()?
-        .get(url)
-        .send()
-        .await;
-    if let Err(e) = res {
-        log::warn!("Failed to notify new tx: {e}",);
-    }
+#[derive(Serialize, Deserialize)]
+pub struct ViewingKeyStatus {
+    pub account_index: u32,
+    pub birth_height: u32,
+    pub synced_height: u32,
+}
 
-    Ok(())
+#[derive(Serialize, Deserialize)]
+pub struct RequestScanResponse {
+    pub job_id: u64,
+    #[serde(flatten)]
+    pub state: ScanJobState,
 }
 
-#[allow(dead_code)]
-fn to_tonic<E: ToString>(e: E) -> tonic::Status {
-    tonic::Status::internal(e.to_string())
+#[derive(Serialize, Deserialize)]
+pub struct RewindResponse {
+    pub height: u32,
 }
 
-fn from_tonic<E: ToString>(e: E) -> anyhow::Error {
-    anyhow::anyhow!(e.to_string())
+#[derive(Serialize, Deserialize)]
+pub struct SyncInfoResponse {
+    pub target_height: u32,
+    pub height: u32,
 }
 
 type BoxedLayer<S> = Box<dyn Layer<S> + Send + Sync + 'static>;
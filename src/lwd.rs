@@ -0,0 +1,395 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+use tonic::Request;
+use tracing::{info, warn};
+
+use crate::{
+    chain::{BlockStream, ChainInfo, ChainSource},
+    lwd_rpc::{
+        compact_tx_streamer_client::CompactTxStreamerClient, BlockId, BlockRange, ChainSpec, Empty,
+        TreeState, TxFilter,
+    },
+    metrics::LWD_LATENCY,
+    Client, Hash, WalletConfig,
+};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_LAG: u32 = 10;
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LwdServerStatus {
+    pub url: String,
+    pub healthy: bool,
+    pub height: Option<u32>,
+    pub error: Option<String>,
+}
+
+struct Server {
+    url: String,
+    channel: Channel,
+}
+
+/// Shared connections to one or more lightwalletd servers.
+///
+/// Calls go to the first healthy server in configuration order. A server
+/// is unhealthy when it does not answer, when its tip lags the best tip
+/// by more than `max_lag` blocks or when its block hash disagrees with
+/// the majority at a height that every server has.
+pub struct LwdPool {
+    servers: Vec<Server>,
+    status: Mutex<Vec<LwdServerStatus>>,
+    current: AtomicUsize,
+    max_lag: u32,
+    last_check: Mutex<Option<Instant>>,
+    /// Set while a health check runs
+    checking: AtomicBool,
+}
+
+impl LwdPool {
+    pub fn new(config: &WalletConfig) -> Result<Self> {
+        let timeout = Duration::from_secs(config.lwd_timeout.unwrap_or(DEFAULT_TIMEOUT_SECS));
+        let ca = config
+            .lwd_tls_ca
+            .as_ref()
+            .map(std::fs::read)
+            .transpose()?
+            .map(Certificate::from_pem);
+
+        let servers = config
+            .lwd_urls()
+            .into_iter()
+            .map(|url| {
+                let mut endpoint = Endpoint::from_shared(url.clone())?.timeout(timeout);
+                if endpoint.uri().scheme_str() == Some("https") {
+                    let mut tls = ClientTlsConfig::new();
+                    if let Some(host) = endpoint.uri().host() {
+                        tls = tls.domain_name(host.to_string());
+                    }
+                    if let Some(ca) = &ca {
+                        tls = tls.ca_certificate(ca.clone());
+                    }
+                    endpoint = endpoint.tls_config(tls)?;
+                }
+                let channel = endpoint.connect_lazy()?;
+                Ok(Server { url, channel })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if servers.is_empty() {
+            return Err(anyhow!("No lightwalletd server configured"));
+        }
+
+        let status = servers
+            .iter()
+            .map(|s| LwdServerStatus {
+                url: s.url.clone(),
+                healthy: true,
+                height: None,
+                error: None,
+            })
+            .collect();
+
+        Ok(LwdPool {
+            servers,
+            status: Mutex::new(status),
+            current: AtomicUsize::new(0),
+            max_lag: config.lwd_max_lag.unwrap_or(DEFAULT_MAX_LAG),
+            last_check: Mutex::new(None),
+            checking: AtomicBool::new(false),
+        })
+    }
+
+    /// Client of the preferred server. The underlying connection is
+    /// shared by every caller.
+    pub async fn client(&self) -> Result<Client> {
+        self.check_health_if_due().await;
+        let index = self.current.load(Ordering::Relaxed);
+        Ok(CompactTxStreamerClient::new(
+            self.servers[index].channel.clone(),
+        ))
+    }
+
+    async fn with_client<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(Client) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let client = self.client().await?;
+        f(client).await.inspect_err(|e| self.report_failure(e))
+    }
+
+    /// Mark the preferred server as failed and move to the next healthy one
+    pub fn report_failure(&self, error: &anyhow::Error) {
+        let mut status = self.status.lock().unwrap();
+        let index = self.current.load(Ordering::Relaxed);
+        status[index].healthy = false;
+        status[index].error = Some(format!("{error:#}"));
+        self.select(&status);
+    }
+
+    /// Probe the servers when the last check is old enough. Only one
+    /// caller probes, the others go on with the current server.
+    async fn check_health_if_due(&self) {
+        {
+            let mut last_check = self.last_check.lock().unwrap();
+            let all_failed = self.status.lock().unwrap().iter().all(|s| !s.healthy);
+            let due = last_check
+                .map(|t| all_failed || t.elapsed() >= HEALTH_CHECK_INTERVAL)
+                .unwrap_or(true);
+            if !due || self.checking.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            *last_check = Some(Instant::now());
+        }
+        self.check_health().await;
+        self.checking.store(false, Ordering::Release);
+    }
+
+    pub async fn check_health(&self) {
+        let mut status = vec![];
+        for server in self.servers.iter() {
+            let mut client = CompactTxStreamerClient::new(server.channel.clone());
+            let s = match get_latest_block(&mut client).await {
+                Ok(block_id) => LwdServerStatus {
+                    url: server.url.clone(),
+                    healthy: true,
+                    height: Some(block_id.height as u32),
+                    error: None,
+                },
+                Err(e) => LwdServerStatus {
+                    url: server.url.clone(),
+                    healthy: false,
+                    height: None,
+                    error: Some(format!("{e:#}")),
+                },
+            };
+            status.push(s);
+        }
+
+        if let Some(best) = status.iter().filter_map(|s| s.height).max() {
+            for s in status.iter_mut() {
+                if let Some(height) = s.height {
+                    if best - height > self.max_lag {
+                        s.healthy = false;
+                        s.error = Some(format!("{} blocks behind the best tip", best - height));
+                    }
+                }
+            }
+        }
+
+        if status.iter().filter(|s| s.healthy).count() > 1 {
+            self.check_block_hashes(&mut status).await;
+        }
+
+        for (old, new) in self.status.lock().unwrap().iter().zip(status.iter()) {
+            if old.healthy != new.healthy {
+                match &new.error {
+                    Some(error) => warn!("lightwalletd {} is unhealthy: {error}", new.url),
+                    None => info!("lightwalletd {} is healthy", new.url),
+                }
+            }
+        }
+        let mut current = self.status.lock().unwrap();
+        *current = status;
+        self.select(&current);
+    }
+
+    // All healthy servers have the block at the lowest of their tips.
+    // The hash held by the most servers wins, ties going to the server
+    // listed first.
+    async fn check_block_hashes(&self, status: &mut [LwdServerStatus]) {
+        let Some(height) = status
+            .iter()
+            .filter(|s| s.healthy)
+            .filter_map(|s| s.height)
+            .min()
+        else {
+            return;
+        };
+
+        let mut hashes = vec![];
+        for (i, s) in status.iter_mut().enumerate() {
+            if !s.healthy {
+                continue;
+            }
+            match self.server_block_hash(i, height).await {
+                Ok(hash) => hashes.push((i, hash)),
+                Err(e) => {
+                    s.healthy = false;
+                    s.error = Some(format!("{e:#}"));
+                }
+            }
+        }
+
+        let Some((_, majority)) = hashes
+            .iter()
+            .max_by_key(|(i, h)| {
+                let votes = hashes.iter().filter(|(_, other)| other == h).count();
+                (votes, std::cmp::Reverse(*i))
+            })
+            .cloned()
+        else {
+            return;
+        };
+        for (i, hash) in hashes {
+            if hash != majority {
+                status[i].healthy = false;
+                status[i].error = Some(format!("Block hash at height {height} disagrees"));
+            }
+        }
+    }
+
+    async fn server_block_hash(&self, index: usize, height: u32) -> Result<Vec<u8>> {
+        let mut client = CompactTxStreamerClient::new(self.servers[index].channel.clone());
+        let block = client
+            .get_block(Request::new(BlockId {
+                height: height as u64,
+                hash: vec![],
+            }))
+            .await?
+            .into_inner();
+        Ok(block.hash)
+    }
+
+    // Keep the current server if nothing is healthy, the next health
+    // check will retry all of them
+    fn select(&self, status: &[LwdServerStatus]) {
+        let previous = self.current.load(Ordering::Relaxed);
+        if let Some(index) = status.iter().position(|s| s.healthy) {
+            if index != previous {
+                warn!(
+                    "Switching lightwalletd from {} to {}",
+                    self.servers[previous].url, self.servers[index].url
+                );
+            }
+            self.current.store(index, Ordering::Relaxed);
+        }
+    }
+}
+
+#[async_trait]
+impl ChainSource for LwdPool {
+    async fn latest_block(&self) -> Result<BlockId> {
+        self.with_client(|mut client| async move { get_latest_block(&mut client).await })
+            .await
+    }
+
+    async fn chain_info(&self) -> Result<ChainInfo> {
+        self.with_client(|mut client| async move {
+            let _timer = LWD_LATENCY
+                .with_label_values(&["GetLightdInfo"])
+                .start_timer();
+            let rep = client
+                .get_lightd_info(Request::new(Empty {}))
+                .await?
+                .into_inner();
+            anyhow::Ok(ChainInfo {
+                height: rep.block_height as u32,
+                estimated_height: rep.estimated_height as u32,
+            })
+        })
+        .await
+    }
+
+    async fn block_range(&self, start: u32, end: u32) -> Result<BlockStream> {
+        self.with_client(|mut client| async move {
+            let _timer = LWD_LATENCY
+                .with_label_values(&["GetBlockRange"])
+                .start_timer();
+            let blocks = client
+                .get_block_range(Request::new(BlockRange {
+                    start: Some(BlockId {
+                        height: start as u64,
+                        hash: vec![],
+                    }),
+                    end: Some(BlockId {
+                        height: end as u64,
+                        hash: vec![],
+                    }),
+                    spam_filter_threshold: 0,
+                }))
+                .await?
+                .into_inner();
+            let blocks: BlockStream = Box::pin(blocks.map(|b| b.map_err(anyhow::Error::new)));
+            anyhow::Ok(blocks)
+        })
+        .await
+    }
+
+    async fn tree_state(&self, height: u32) -> Result<TreeState> {
+        self.with_client(|mut client| async move {
+            let _timer = LWD_LATENCY
+                .with_label_values(&["GetTreeState"])
+                .start_timer();
+            let tree_state = client
+                .get_tree_state(Request::new(BlockId {
+                    height: height as u64,
+                    hash: vec![],
+                }))
+                .await?
+                .into_inner();
+            anyhow::Ok(tree_state)
+        })
+        .await
+    }
+
+    async fn raw_transaction(&self, txid: &Hash) -> Result<Vec<u8>> {
+        let txid = *txid;
+        self.with_client(|mut client| async move {
+            let _timer = LWD_LATENCY
+                .with_label_values(&["GetTransaction"])
+                .start_timer();
+            let raw_tx = client
+                .get_transaction(Request::new(TxFilter {
+                    hash: txid.to_vec(),
+                    ..TxFilter::default()
+                }))
+                .await?
+                .into_inner();
+            anyhow::Ok(raw_tx.data)
+        })
+        .await
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Hash> {
+        self.with_client(|mut client| async move {
+            let _timer = LWD_LATENCY.with_label_values(&["GetBlock"]).start_timer();
+            let block = client
+                .get_block(Request::new(BlockId {
+                    height: height as u64,
+                    hash: vec![],
+                }))
+                .await?
+                .into_inner();
+            let hash: Hash = block
+                .hash
+                .try_into()
+                .map_err(|_| anyhow!("Invalid block hash at height {height}"))?;
+            anyhow::Ok(hash)
+        })
+        .await
+    }
+
+    fn servers(&self) -> Vec<LwdServerStatus> {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+pub async fn get_latest_block(client: &mut Client) -> Result<BlockId> {
+    let _timer = LWD_LATENCY
+        .with_label_values(&["GetLatestBlock"])
+        .start_timer();
+    let latest_block_id = client
+        .get_latest_block(Request::new(ChainSpec {}))
+        .await?
+        .into_inner();
+    Ok(latest_block_id)
+}
@@ -3,14 +3,55 @@ extern crate rocket;
 
 use anyhow::Result;
 
-use clap::Parser;
-use zcash_walletd::{rpc::*, ZcashWalletd};
+use clap::{Parser, Subcommand};
+use zcash_walletd::{
+    auth::ApiAuth,
+    export::{ExportFormat, ExportRange},
+    metrics::HttpMetrics,
+    rpc::*,
+    ZcashWalletd,
+};
 
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct Args {
+    /// Rescan every wallet from its birth height
     #[clap(short, long)]
     rescan: bool,
+    /// Rewind every wallet to the given height before starting
+    #[clap(long, conflicts_with = "rescan")]
+    rewind_to: Option<u32>,
+    /// Drop the cached compact blocks below the given height
+    #[clap(long)]
+    prune_cache: Option<u32>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the incoming and outgoing notes of the wallet and exit
+    Export {
+        /// csv or json
+        #[clap(long, default_value = "csv")]
+        format: ExportFormat,
+        #[clap(long)]
+        from_height: Option<u32>,
+        #[clap(long)]
+        to_height: Option<u32>,
+        /// First day to export, YYYY-MM-DD (UTC)
+        #[clap(long)]
+        from_date: Option<String>,
+        /// Last day to export, YYYY-MM-DD (UTC)
+        #[clap(long)]
+        to_date: Option<String>,
+        /// Hosted wallet to export, the default wallet if unset
+        #[clap(long)]
+        wallet: Option<String>,
+        /// Output file, stdout if unset
+        #[clap(short, long)]
+        output: Option<String>,
+    },
 }
 
 // They come from the config file
@@ -23,28 +64,91 @@ struct Args {
 
 #[rocket::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
     let rocket = rocket::build();
+    // The export only reads the database
+    if let Some(Command::Export {
+        format,
+        from_height,
+        to_height,
+        from_date,
+        to_date,
+        wallet,
+        output,
+    }) = args.command
+    {
+        let config = ZcashWalletd::load_config(Some(&rocket))?;
+        let range = ExportRange {
+            from_height,
+            to_height,
+            from_date,
+            to_date,
+        };
+        let export =
+            ZcashWalletd::export_offline(&config, wallet.as_deref(), &range, format).await?;
+        match output {
+            Some(path) => std::fs::write(path, export)?,
+            None => print!("{export}"),
+        }
+        return Ok(());
+    }
     let wallet = ZcashWalletd::init(Some(&rocket)).await?;
-    wallet.monitor_task();
-    
+    // The hosted wallets are scanned together, they are all rewound
+    for id in wallet.wallet_ids() {
+        let hosted = wallet.for_wallet(&id)?;
+        if args.rescan {
+            hosted.rescan().await?;
+        } else if let Some(height) = args.rewind_to {
+            hosted.rewind_to(height).await?;
+        }
+    }
+    if let Some(height) = args.prune_cache {
+        wallet.prune_block_cache(height).await?;
+    }
+    let auth = ApiAuth::new(&wallet.config)?;
+    if !auth.is_enabled() {
+        tracing::warn!(
+            "No API key or RPC login is configured, the API is open to anyone who can reach it"
+        );
+    }
+    let monitor = wallet.monitor_task();
+
     rocket
         .manage(wallet)
+        .manage(auth)
+        .attach(WalletRouter)
+        .attach(HttpMetrics)
         .mount(
             "/",
             routes![
                 create_account,
                 create_address,
+                import_viewing_key,
+                reassign_address,
+                get_late_payments,
                 get_accounts,
+                get_balance_at,
                 get_transaction,
                 get_transfers,
+                export,
                 get_fee_estimate,
                 get_height,
+                get_wallet_height,
                 sync_info,
                 request_scan,
+                get_scan_job,
+                rescan_blockchain,
+                rewind,
+                status,
+                liveness,
+                readiness,
+                metrics,
             ],
         )
+        .register("/", catchers![default_catcher])
         .launch()
         .await?;
+    monitor.shutdown().await;
 
     Ok(())
-}
\ No newline at end of file
+}
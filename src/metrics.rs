@@ -0,0 +1,199 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use prometheus::{
+    register_gauge, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Gauge, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+
+use crate::scan::ScanEvent;
+
+pub static SYNCED_HEIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("walletd_synced_height", "Height of the last scanned block").unwrap()
+});
+
+pub static CHAIN_TIP_LAG: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "walletd_chain_tip_lag",
+        "Number of blocks between the chain tip and the synced height"
+    )
+    .unwrap()
+});
+
+pub static BLOCKS_SCANNED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("walletd_blocks_scanned_total", "Number of blocks scanned").unwrap()
+});
+
+pub static OUTPUTS_SCANNED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_outputs_scanned_total",
+        "Number of shielded outputs trial decrypted",
+        &["pool"]
+    )
+    .unwrap()
+});
+
+pub static TRIAL_DECRYPTIONS_RATE: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "walletd_trial_decryptions_per_second",
+        "Trial decryptions per second during the last scan"
+    )
+    .unwrap()
+});
+
+pub static NOTES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_notes_received_total",
+        "Number of notes received",
+        &["pool"]
+    )
+    .unwrap()
+});
+
+pub static NOTES_SPENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_notes_spent_total",
+        "Number of notes spent",
+        &["pool"]
+    )
+    .unwrap()
+});
+
+pub static REORGS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "walletd_reorgs_total",
+        "Number of chain reorganizations handled"
+    )
+    .unwrap()
+});
+
+pub static ADDRESS_POOL_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "walletd_address_pool_depth",
+        "Number of addresses derived in advance, by wallet",
+        &["wallet"]
+    )
+    .unwrap()
+});
+
+pub static ADDRESS_POOL_MISSES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "walletd_address_pool_misses_total",
+        "Number of addresses derived on request because the pool was empty"
+    )
+    .unwrap()
+});
+
+pub static NOTIFICATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_notifications_total",
+        "Number of transaction notifications by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static BLOCK_CACHE_READS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "walletd_block_cache_reads_total",
+        "Number of compact blocks read by the scanner, by cache result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static LWD_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "walletd_lwd_request_duration_seconds",
+        "Latency of lightwalletd gRPC calls",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static NODE_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "walletd_node_request_duration_seconds",
+        "Latency of full node JSON-RPC calls",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "walletd_http_request_duration_seconds",
+        "Latency of the REST API",
+        &["route", "status"]
+    )
+    .unwrap()
+});
+
+pub fn pool_label(pool: u8) -> &'static str {
+    match pool {
+        1 => "sapling",
+        2 => "orchard",
+        _ => "unknown",
+    }
+}
+
+/// Count the notes received and spent in a batch of stored events
+pub fn record_events(events: &[ScanEvent]) {
+    for event in events {
+        match event {
+            ScanEvent::Received(note) => NOTES_RECEIVED
+                .with_label_values(&[pool_label(note.pool)])
+                .inc(),
+            ScanEvent::Spent(note) => NOTES_SPENT
+                .with_label_values(&[pool_label(note.pool)])
+                .inc(),
+            _ => {}
+        }
+    }
+}
+
+pub fn record_notification(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    NOTIFICATIONS.with_label_values(&[result]).inc();
+}
+
+pub fn gather() -> anyhow::Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+struct RequestStart(Instant);
+
+/// Rocket fairing that records the latency of every routed request
+pub struct HttpMetrics;
+
+#[rocket::async_trait]
+impl Fairing for HttpMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTP metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let start = req.local_cache(|| RequestStart(Instant::now()));
+        let route = req
+            .route()
+            .and_then(|r| r.name.as_deref())
+            .unwrap_or("unknown");
+        HTTP_LATENCY
+            .with_label_values(&[route, res.status().code.to_string().as_str()])
+            .observe(start.0.elapsed().as_secs_f64());
+    }
+}
@@ -1,20 +1,163 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::{task::JoinHandle, time};
+use tokio_util::sync::CancellationToken;
 
 use crate::ZcashWalletd;
 
-pub struct MonitorTask;
+/// Longest wait after a chain backend or scan error
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Watches the chain tip and scans when it moves.
+///
+/// The tip is polled quickly right after a new block and the delay
+/// doubles while the chain is idle, up to `max_poll`. Errors back off
+/// exponentially up to `MAX_ERROR_BACKOFF`. A rewind, after a reorg or
+/// on request, triggers a scan right away.
+pub struct MonitorTask {
+    cancel: CancellationToken,
+    handle: JoinHandle<()>,
+}
 
 impl MonitorTask {
-    pub fn spawn(wallet: ZcashWalletd, poll_secs: u64) -> JoinHandle<()> {
-        tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(poll_secs));
+    pub fn spawn(wallet: ZcashWalletd, min_poll: Duration, max_poll: Duration) -> Self {
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        let handle = tokio::spawn(async move {
+            let mut backoff = Backoff::new(min_poll, max_poll);
+            let mut last_tip: Option<(u64, Vec<u8>)> = None;
             loop {
-                if let Err(e) = wallet.request_scan().await {
-                    log::warn!("request_scan failed: {e:#}");
+                let delay = match Self::poll_tip(&wallet).await {
+                    Ok(tip) if last_tip.as_ref() != Some(&tip) => {
+                        match wallet.request_scan().await {
+                            Ok(()) => {
+                                last_tip = Some(tip);
+                                backoff.on_new_block()
+                            }
+                            Err(e) => {
+                                log::warn!("request_scan failed: {e:#}");
+                                backoff.on_error()
+                            }
+                        }
+                    }
+                    Ok(_) => backoff.on_idle(),
+                    Err(e) => {
+                        log::warn!("Failed to get the latest block: {e:#}");
+                        backoff.on_error()
+                    }
+                };
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    // The blocks above a rewind are scanned again without
+                    // waiting for the tip to move
+                    _ = wallet.rewound() => last_tip = None,
+                    _ = time::sleep(delay) => {}
                 }
-                interval.tick().await;
             }
-        })
+        });
+        Self { cancel, handle }
+    }
+
+    async fn poll_tip(wallet: &ZcashWalletd) -> anyhow::Result<(u64, Vec<u8>)> {
+        let block_id = wallet.chain().latest_block().await?;
+        Ok((block_id.height, block_id.hash))
+    }
+
+    /// Stop watching the chain and wait for the current scan to end
+    pub async fn shutdown(self) {
+        self.cancel.cancel();
+        let _ = self.handle.await;
+    }
+}
+
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    idle: Duration,
+    error: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            idle: min,
+            error: min,
+        }
+    }
+
+    pub fn on_new_block(&mut self) -> Duration {
+        self.idle = self.min;
+        self.error = self.min;
+        self.min
+    }
+
+    pub fn on_idle(&mut self) -> Duration {
+        self.error = self.min;
+        self.idle = (self.idle * 2).min(self.max);
+        self.idle
+    }
+
+    pub fn on_error(&mut self) -> Duration {
+        self.error = (self.error * 2).min(MAX_ERROR_BACKOFF.max(self.max));
+        self.error
+    }
+}
+
+/// Outcome of the most recent scans, reported by `/status`
+#[derive(Default, Debug)]
+pub struct ScanStatus {
+    /// Unix time of the last scan that completed without error
+    pub last_scan_time: Option<u64>,
+    pub last_scan_error: Option<String>,
+    /// Blocks per second measured over the last scan that made progress
+    pub scan_rate: Option<f64>,
+}
+
+impl ScanStatus {
+    pub fn record_success(&mut self, blocks: u32, elapsed: Duration) {
+        self.last_scan_time = Some(unix_time());
+        self.last_scan_error = None;
+        if blocks > 0 && !elapsed.is_zero() {
+            self.scan_rate = Some(blocks as f64 / elapsed.as_secs_f64());
+        }
+    }
+
+    pub fn record_error(&mut self, error: &anyhow::Error) {
+        self.last_scan_error = Some(format!("{error:#}"));
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_adapts_to_chain_activity() {
+        let s = Duration::from_secs;
+        let mut backoff = Backoff::new(s(2), s(60));
+        assert_eq!(backoff.on_idle(), s(4));
+        assert_eq!(backoff.on_idle(), s(8));
+        for _ in 0..10 {
+            backoff.on_idle();
+        }
+        assert_eq!(backoff.on_idle(), s(60));
+        assert_eq!(backoff.on_new_block(), s(2));
+        assert_eq!(backoff.on_idle(), s(4));
+
+        assert_eq!(backoff.on_error(), s(4));
+        assert_eq!(backoff.on_error(), s(8));
+        for _ in 0..10 {
+            backoff.on_error();
+        }
+        assert_eq!(backoff.on_error(), MAX_ERROR_BACKOFF);
+        assert_eq!(backoff.on_new_block(), s(2));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,290 @@
+use std::io::Read;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use zcash_primitives::{block::BlockHeader, transaction::Transaction};
+use zcash_protocol::consensus::{BlockHeight, BranchId};
+
+use crate::{
+    chain::{BlockStream, ChainInfo, ChainSource},
+    lwd_rpc::{
+        BlockId, CompactBlock, CompactOrchardAction, CompactSaplingOutput, CompactSaplingSpend,
+        CompactTx, TreeState,
+    },
+    metrics::NODE_LATENCY,
+    network::Network,
+    Hash, WalletConfig,
+};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// Number of blocks fetched ahead of the scanner
+const PREFETCH_BLOCKS: usize = 16;
+/// Size of the note plaintext prefix kept in compact outputs
+const COMPACT_NOTE_SIZE: usize = 52;
+
+/// Chain access through the JSON-RPC interface of zcashd or zebrad.
+/// Compact blocks are built locally from the full blocks.
+#[derive(Clone)]
+pub struct NodeSource {
+    client: reqwest::Client,
+    url: String,
+    user: Option<String>,
+    password: Option<String>,
+    network: Network,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct BlockchainInfo {
+    blocks: u32,
+    bestblockhash: String,
+    estimatedheight: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct BlockInfo {
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct NodeTreeState {
+    height: u64,
+    hash: String,
+    time: u32,
+    sapling: Option<NodeTree>,
+    orchard: Option<NodeTree>,
+}
+
+#[derive(Deserialize)]
+struct NodeTree {
+    commitments: Option<NodeCommitments>,
+}
+
+#[derive(Deserialize)]
+struct NodeCommitments {
+    #[serde(rename = "finalState")]
+    final_state: Option<String>,
+}
+
+impl NodeTree {
+    fn final_state(tree: Option<NodeTree>) -> String {
+        tree.and_then(|t| t.commitments)
+            .and_then(|c| c.final_state)
+            .unwrap_or_default()
+    }
+}
+
+impl NodeSource {
+    pub fn new(config: &WalletConfig, url: &str) -> Result<Self> {
+        let timeout = Duration::from_secs(config.lwd_timeout.unwrap_or(DEFAULT_TIMEOUT_SECS));
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(NodeSource {
+            client,
+            url: url.to_string(),
+            user: config.node_rpc_user.clone(),
+            password: config.node_rpc_password.clone(),
+            network: config.network(),
+        })
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let _timer = NODE_LATENCY.with_label_values(&[method]).start_timer();
+        let mut request = self.client.post(&self.url).json(&json!({
+            "jsonrpc": "1.0",
+            "id": "walletd",
+            "method": method,
+            "params": params,
+        }));
+        if let Some(user) = &self.user {
+            request = request.basic_auth(user, self.password.as_ref());
+        }
+        let rep: RpcResponse = request.send().await?.json().await?;
+        if let Some(error) = rep.error {
+            return Err(anyhow!(
+                "{method} failed: {} ({})",
+                error.message,
+                error.code
+            ));
+        }
+        let result = rep.result.ok_or(anyhow!("{method} returned no result"))?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn compact_block(&self, height: u32) -> Result<CompactBlock> {
+        let data: String = self
+            .call("getblock", json!([height.to_string(), 0]))
+            .await?;
+        let data = hex::decode(data)?;
+        to_compact_block(&self.network, height, &data)
+    }
+}
+
+#[async_trait]
+impl ChainSource for NodeSource {
+    async fn latest_block(&self) -> Result<BlockId> {
+        let info: BlockchainInfo = self.call("getblockchaininfo", json!([])).await?;
+        Ok(BlockId {
+            height: info.blocks as u64,
+            hash: from_display_hex(&info.bestblockhash)?.to_vec(),
+        })
+    }
+
+    async fn chain_info(&self) -> Result<ChainInfo> {
+        let info: BlockchainInfo = self.call("getblockchaininfo", json!([])).await?;
+        Ok(ChainInfo {
+            height: info.blocks,
+            estimated_height: info.estimatedheight.unwrap_or(info.blocks),
+        })
+    }
+
+    async fn block_range(&self, start: u32, end: u32) -> Result<BlockStream> {
+        let (tx, rx) = mpsc::channel(PREFETCH_BLOCKS);
+        let source = self.clone();
+        tokio::spawn(async move {
+            for height in start..=end {
+                let block = source.compact_block(height).await;
+                let failed = block.is_err();
+                if tx.send(block).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Box::pin(ReceiverStream::new(rx)))
+    }
+
+    async fn tree_state(&self, height: u32) -> Result<TreeState> {
+        let tree_state: NodeTreeState = self
+            .call("z_gettreestate", json!([height.to_string()]))
+            .await?;
+        Ok(TreeState {
+            network: String::new(),
+            height: tree_state.height,
+            hash: tree_state.hash,
+            time: tree_state.time,
+            sapling_tree: NodeTree::final_state(tree_state.sapling),
+            orchard_tree: NodeTree::final_state(tree_state.orchard),
+        })
+    }
+
+    async fn raw_transaction(&self, txid: &Hash) -> Result<Vec<u8>> {
+        let mut txid = *txid;
+        txid.reverse();
+        let data: String = self
+            .call("getrawtransaction", json!([hex::encode(txid), 0]))
+            .await?;
+        Ok(hex::decode(data)?)
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Hash> {
+        let block: BlockInfo = self
+            .call("getblock", json!([height.to_string(), 1]))
+            .await?;
+        from_display_hex(&block.hash)
+    }
+}
+
+fn from_display_hex(hash: &str) -> Result<Hash> {
+    let mut hash: Hash = hex::decode(hash)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid hash {hash}"))?;
+    hash.reverse();
+    Ok(hash)
+}
+
+pub fn to_compact_block(network: &Network, height: u32, data: &[u8]) -> Result<CompactBlock> {
+    let mut reader = data;
+    let header = BlockHeader::read(&mut reader)?;
+    let tx_count = read_compact_size(&mut reader)?;
+    let branch_id = BranchId::for_height(network, BlockHeight::from_u32(height));
+
+    let mut vtx = vec![];
+    for index in 0..tx_count {
+        let tx = Transaction::read(&mut reader, branch_id)?;
+        let mut ctx = CompactTx {
+            index,
+            hash: tx.txid().as_ref().to_vec(),
+            ..CompactTx::default()
+        };
+        if let Some(bundle) = tx.sapling_bundle() {
+            ctx.spends = bundle
+                .shielded_spends()
+                .iter()
+                .map(|s| CompactSaplingSpend {
+                    nf: s.nullifier().0.to_vec(),
+                })
+                .collect();
+            ctx.outputs = bundle
+                .shielded_outputs()
+                .iter()
+                .map(|o| CompactSaplingOutput {
+                    cmu: o.cmu().to_bytes().to_vec(),
+                    epk: o.ephemeral_key().0.to_vec(),
+                    ciphertext: o.enc_ciphertext()[..COMPACT_NOTE_SIZE].to_vec(),
+                })
+                .collect();
+        }
+        if let Some(bundle) = tx.orchard_bundle() {
+            ctx.actions = bundle
+                .actions()
+                .iter()
+                .map(|a| CompactOrchardAction {
+                    nullifier: a.nullifier().to_bytes().to_vec(),
+                    cmx: a.cmx().to_bytes().to_vec(),
+                    ephemeral_key: a.encrypted_note().epk_bytes.to_vec(),
+                    ciphertext: a.encrypted_note().enc_ciphertext[..COMPACT_NOTE_SIZE].to_vec(),
+                })
+                .collect();
+        }
+        vtx.push(ctx);
+    }
+
+    Ok(CompactBlock {
+        proto_version: 1,
+        height: height as u64,
+        hash: header.hash().0.to_vec(),
+        prev_hash: header.prev_block.0.to_vec(),
+        time: header.time,
+        header: vec![],
+        vtx,
+    })
+}
+
+fn read_compact_size<R: Read>(mut reader: R) -> Result<u64> {
+    let mut flag = [0u8; 1];
+    reader.read_exact(&mut flag)?;
+    let size = match flag[0] {
+        0xfd => {
+            let mut buf = [0u8; 2];
+            reader.read_exact(&mut buf)?;
+            u16::from_le_bytes(buf) as u64
+        }
+        0xfe => {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf)?;
+            u32::from_le_bytes(buf) as u64
+        }
+        0xff => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            u64::from_le_bytes(buf)
+        }
+        n => n as u64,
+    };
+    Ok(size)
+}
@@ -4,6 +4,8 @@ use async_trait::async_trait;
 use reqwest::Client;
 use tracing::warn;
 
+use crate::metrics::record_notification;
+
 fn txid_to_hex_le(txid_be: &[u8]) -> String {
     let mut v = txid_be.to_vec();
     v.reverse();
@@ -12,7 +14,8 @@ fn txid_to_hex_le(txid_be: &[u8]) -> String {
 
 #[async_trait]
 pub trait TxNotifier: Send + Sync + 'static {
-    async fn notify_tx(&self, txid: &[u8]) -> Result<()>;
+    /// `late` when the transaction paid an address after its expiry
+    async fn notify_tx(&self, txid: &[u8], late: bool) -> Result<()>;
 }
 
 pub struct HttpNotifier {
@@ -26,20 +29,34 @@ impl HttpNotifier {
         let client = Client::builder()
             .danger_accept_invalid_certs(accept_invalid_certs)
             .build()?;
-        Ok(Self { client, base_url: base_url.into() })
+        Ok(Self {
+            client,
+            base_url: base_url.into(),
+        })
     }
 }
 
 #[async_trait]
 impl TxNotifier for HttpNotifier {
-    async fn notify_tx(&self, txid: &[u8]) -> Result<()> {
+    async fn notify_tx(&self, txid: &[u8], late: bool) -> Result<()> {
         let hexid = txid_to_hex_le(txid);
-        let url = format!("{}{}", self.base_url, hexid);
+        let mut url = format!("{}{}", self.base_url, hexid);
+        if late {
+            url.push_str(if url.contains('?') {
+                "&late=true"
+            } else {
+                "?late=true"
+            });
+        }
 
         // Best-effort notify: warn but don't fail the pipeline
-        if let Err(e) = self.client.get(url).send().await {
-            warn!("Failed to notify new tx: {e}");
+        match self.client.get(url).send().await {
+            Ok(res) => record_notification(res.status().is_success()),
+            Err(e) => {
+                warn!("Failed to notify new tx: {e}");
+                record_notification(false);
+            }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
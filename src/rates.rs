@@ -0,0 +1,190 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use crate::{export::parse_date, WalletConfig};
+
+/// Source of the ZEC price in a fiat currency
+#[async_trait]
+pub trait RateProvider: Send + Sync + 'static {
+    /// Rate at unix `time`, `None` if the provider has none for it
+    async fn rate_at(&self, time: u64) -> Result<Option<f64>>;
+}
+
+/// Provider configured by `RATE_FILE` or `RATE_URL`, if any
+pub fn from_config(config: &WalletConfig) -> Result<Option<Arc<dyn RateProvider>>> {
+    let provider: Arc<dyn RateProvider> = match (&config.rate_file, &config.rate_url) {
+        (Some(_), Some(_)) => anyhow::bail!("Configure either a rate file or a rate URL"),
+        (Some(path), None) => Arc::new(FileRateProvider::open(path)?),
+        (None, Some(url)) => Arc::new(HttpRateProvider::new(url)?),
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(provider))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RateTime {
+    Unix(u64),
+    Date(String),
+}
+
+impl RateTime {
+    fn parse(s: &str) -> Self {
+        match s.parse() {
+            Ok(time) => RateTime::Unix(time),
+            Err(_) => RateTime::Date(s.to_string()),
+        }
+    }
+
+    fn unix_time(&self) -> Result<u64> {
+        match self {
+            RateTime::Unix(time) => Ok(*time),
+            RateTime::Date(date) => parse_date(date),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RatePoint {
+    time: RateTime,
+    rate: f64,
+}
+
+/// Rates read from a CSV file of `time,rate` lines or a JSON array of
+/// `{"time": .., "rate": ..}`. The time is a unix time or a `YYYY-MM-DD`
+/// day, and each rate holds until the next one.
+pub struct FileRateProvider {
+    /// Sorted by time
+    points: Vec<(u64, f64)>,
+}
+
+impl FileRateProvider {
+    pub fn open(path: &str) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read the rate file {path}"))?;
+        let json = Path::new(path).extension().is_some_and(|ext| ext == "json");
+        let points = if json {
+            Self::parse_json(&data)
+        } else {
+            Self::parse_csv(&data)
+        }
+        .with_context(|| format!("Invalid rate file {path}"))?;
+        Ok(Self::new(points))
+    }
+
+    pub fn new(mut points: Vec<(u64, f64)>) -> Self {
+        points.sort_by_key(|(time, _)| *time);
+        Self { points }
+    }
+
+    fn parse_json(data: &str) -> Result<Vec<(u64, f64)>> {
+        let points: Vec<RatePoint> = serde_json::from_str(data)?;
+        points
+            .iter()
+            .map(|p| Ok((p.time.unix_time()?, p.rate)))
+            .collect()
+    }
+
+    /// A first line that does not parse is a header
+    fn parse_csv(data: &str) -> Result<Vec<(u64, f64)>> {
+        let parse_line = |line: &str| -> Result<(u64, f64)> {
+            let (time, rate) = line.split_once(',').ok_or(anyhow!("Expected time,rate"))?;
+            let time = RateTime::parse(time.trim()).unix_time()?;
+            let rate = rate.trim().parse()?;
+            Ok((time, rate))
+        };
+        let mut points = vec![];
+        for (i, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_line(line) {
+                Ok(point) => points.push(point),
+                Err(_) if i == 0 => {}
+                Err(e) => return Err(e.context(format!("Line {}", i + 1))),
+            }
+        }
+        Ok(points)
+    }
+}
+
+#[async_trait]
+impl RateProvider for FileRateProvider {
+    async fn rate_at(&self, time: u64) -> Result<Option<f64>> {
+        let i = self.points.partition_point(|(t, _)| *t <= time);
+        Ok(i.checked_sub(1).map(|i| self.points[i].1))
+    }
+}
+
+/// Rates served over HTTP. `{time}` in the URL is replaced by the unix
+/// time and the response is `{"rate": <rate>}`, or a 404 or a null rate
+/// when there is none.
+pub struct HttpRateProvider {
+    client: Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct RateResponse {
+    rate: Option<f64>,
+}
+
+impl HttpRateProvider {
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()?;
+        Ok(Self {
+            client,
+            url: url.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl RateProvider for HttpRateProvider {
+    async fn rate_at(&self, time: u64) -> Result<Option<f64>> {
+        let url = self.url.replace("{time}", &time.to_string());
+        let res = self.client.get(url).send().await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let rep: RateResponse = res.error_for_status()?.json().await?;
+        Ok(rep.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_rates() -> Result<()> {
+        let csv = "time,usd\n1700000000,30.5\n2023-11-15, 31.25\n\n";
+        let rates = FileRateProvider::new(FileRateProvider::parse_csv(csv)?);
+        assert_eq!(rates.rate_at(1_699_999_999).await?, None);
+        assert_eq!(rates.rate_at(1_700_000_000).await?, Some(30.5));
+        assert_eq!(rates.rate_at(1_700_006_400).await?, Some(31.25));
+        assert!(FileRateProvider::parse_csv("1700000000,30.5\nnot a rate").is_err());
+
+        let json = r#"[{"time": "2023-11-15", "rate": 31.25},
+            {"time": 1700000000, "rate": 30.5}]"#;
+        let rates = FileRateProvider::new(FileRateProvider::parse_json(json)?);
+        assert_eq!(rates.rate_at(1_700_000_100).await?, Some(30.5));
+        assert_eq!(rates.rate_at(1_800_000_000).await?, Some(31.25));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn http_rates() -> Result<()> {
+        let url = crate::testing::spawn_rate_server(vec![(1_700_000_000, 30.5)])?;
+        let rates = HttpRateProvider::new(url)?;
+        assert_eq!(rates.rate_at(1_700_000_075).await?, Some(30.5));
+        assert_eq!(rates.rate_at(1_699_999_999).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+//! Recording of the chain data used by the scanner, and replay of the
+//! recordings as a chain source.
+//!
+//! A fixture is a sequence of length delimited protobuf records, one per
+//! response: latest blocks, compact blocks, tree states, raw
+//! transactions and block hashes.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use prost::Message;
+use tokio_stream::StreamExt;
+
+use crate::{
+    chain::{BlockStream, ChainInfo, ChainSource},
+    lwd::LwdServerStatus,
+    lwd_rpc::{BlockId, CompactBlock, TreeState},
+    Hash,
+};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Record {
+    #[prost(oneof = "Entry", tags = "1, 2, 3, 4, 5")]
+    pub entry: Option<Entry>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Entry {
+    #[prost(message, tag = "1")]
+    LatestBlock(BlockId),
+    #[prost(message, tag = "2")]
+    Block(CompactBlock),
+    #[prost(message, tag = "3")]
+    TreeState(TreeState),
+    #[prost(message, tag = "4")]
+    Transaction(RecordedTx),
+    #[prost(message, tag = "5")]
+    BlockHash(RecordedBlockHash),
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RecordedTx {
+    #[prost(bytes, tag = "1")]
+    pub txid: Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RecordedBlockHash {
+    #[prost(uint32, tag = "1")]
+    pub height: u32,
+    #[prost(bytes, tag = "2")]
+    pub hash: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    fn write(&self, entry: Entry) -> Result<()> {
+        let record = Record { entry: Some(entry) };
+        let mut buf = vec![];
+        record.encode_length_delimited(&mut buf)?;
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+/// Chain source that appends the responses of another one to a fixture
+/// file. Failed calls are not recorded.
+pub struct RecordingSource {
+    inner: Arc<dyn ChainSource>,
+    recorder: Recorder,
+}
+
+impl RecordingSource {
+    pub fn new(inner: Arc<dyn ChainSource>, path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RecordingSource {
+            inner,
+            recorder: Recorder {
+                file: Arc::new(Mutex::new(file)),
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl ChainSource for RecordingSource {
+    async fn latest_block(&self) -> Result<BlockId> {
+        let block_id = self.inner.latest_block().await?;
+        self.recorder.write(Entry::LatestBlock(block_id.clone()))?;
+        Ok(block_id)
+    }
+
+    async fn chain_info(&self) -> Result<ChainInfo> {
+        self.inner.chain_info().await
+    }
+
+    async fn block_range(&self, start: u32, end: u32) -> Result<BlockStream> {
+        let blocks = self.inner.block_range(start, end).await?;
+        let recorder = self.recorder.clone();
+        let blocks = blocks.map(move |block| {
+            let block = block?;
+            recorder.write(Entry::Block(block.clone()))?;
+            Ok(block)
+        });
+        Ok(Box::pin(blocks))
+    }
+
+    async fn tree_state(&self, height: u32) -> Result<TreeState> {
+        let tree_state = self.inner.tree_state(height).await?;
+        self.recorder.write(Entry::TreeState(tree_state.clone()))?;
+        Ok(tree_state)
+    }
+
+    async fn raw_transaction(&self, txid: &Hash) -> Result<Vec<u8>> {
+        let data = self.inner.raw_transaction(txid).await?;
+        self.recorder.write(Entry::Transaction(RecordedTx {
+            txid: txid.to_vec(),
+            data: data.clone(),
+        }))?;
+        Ok(data)
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Hash> {
+        let hash = self.inner.block_hash(height).await?;
+        self.recorder.write(Entry::BlockHash(RecordedBlockHash {
+            height,
+            hash: hash.to_vec(),
+        }))?;
+        Ok(hash)
+    }
+
+    async fn rewind(&self, height: u32) -> Result<()> {
+        self.inner.rewind(height).await
+    }
+
+    fn servers(&self) -> Vec<LwdServerStatus> {
+        self.inner.servers()
+    }
+}
+
+/// Chain source serving a fixture.
+///
+/// Latest blocks are returned in the order they were recorded, the last
+/// one is repeated once the others are consumed. When a height was
+/// recorded more than once, e.g. across a reorg, the last block wins.
+pub struct ReplaySource {
+    latest_blocks: Mutex<VecDeque<BlockId>>,
+    blocks: BTreeMap<u32, CompactBlock>,
+    block_hashes: HashMap<u32, Hash>,
+    tree_states: HashMap<u32, TreeState>,
+    transactions: HashMap<Hash, Vec<u8>>,
+}
+
+impl ReplaySource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> Result<Self> {
+        let mut latest_blocks = VecDeque::new();
+        let mut blocks = BTreeMap::new();
+        let mut block_hashes = HashMap::new();
+        let mut tree_states = HashMap::new();
+        let mut transactions = HashMap::new();
+        while !data.is_empty() {
+            let record = Record::decode_length_delimited(&mut data)?;
+            match record.entry {
+                Some(Entry::LatestBlock(block_id)) => latest_blocks.push_back(block_id),
+                Some(Entry::Block(block)) => {
+                    blocks.insert(block.height as u32, block);
+                }
+                Some(Entry::TreeState(tree_state)) => {
+                    tree_states.insert(tree_state.height as u32, tree_state);
+                }
+                Some(Entry::Transaction(tx)) => {
+                    let txid: Hash = tx
+                        .txid
+                        .try_into()
+                        .map_err(|_| anyhow!("Invalid txid in fixture"))?;
+                    transactions.insert(txid, tx.data);
+                }
+                Some(Entry::BlockHash(block_hash)) => {
+                    let hash: Hash = block_hash
+                        .hash
+                        .try_into()
+                        .map_err(|_| anyhow!("Invalid block hash in fixture"))?;
+                    block_hashes.insert(block_hash.height, hash);
+                }
+                None => {}
+            }
+        }
+        Ok(ReplaySource {
+            latest_blocks: Mutex::new(latest_blocks),
+            blocks,
+            block_hashes,
+            tree_states,
+            transactions,
+        })
+    }
+}
+
+fn not_recorded(what: &str) -> anyhow::Error {
+    anyhow!("{what} is not in the fixture")
+}
+
+#[async_trait]
+impl ChainSource for ReplaySource {
+    async fn latest_block(&self) -> Result<BlockId> {
+        let mut latest_blocks = self.latest_blocks.lock().unwrap();
+        let block_id = if latest_blocks.len() > 1 {
+            latest_blocks.pop_front()
+        } else {
+            latest_blocks.front().cloned()
+        };
+        block_id.ok_or_else(|| not_recorded("Latest block"))
+    }
+
+    async fn chain_info(&self) -> Result<ChainInfo> {
+        let height = self.latest_height().await?;
+        Ok(ChainInfo {
+            height,
+            estimated_height: height,
+        })
+    }
+
+    async fn block_range(&self, start: u32, end: u32) -> Result<BlockStream> {
+        let blocks = (start..=end)
+            .map(|height| {
+                self.blocks
+                    .get(&height)
+                    .cloned()
+                    .ok_or_else(|| not_recorded(&format!("Block {height}")))
+            })
+            .collect::<Vec<_>>();
+        Ok(Box::pin(tokio_stream::iter(blocks)))
+    }
+
+    async fn tree_state(&self, height: u32) -> Result<TreeState> {
+        self.tree_states
+            .get(&height)
+            .cloned()
+            .ok_or_else(|| not_recorded(&format!("Tree state {height}")))
+    }
+
+    async fn raw_transaction(&self, txid: &Hash) -> Result<Vec<u8>> {
+        self.transactions
+            .get(txid)
+            .cloned()
+            .ok_or_else(|| not_recorded(&format!("Transaction {}", hex::encode(txid))))
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Hash> {
+        if let Some(hash) = self.block_hashes.get(&height) {
+            return Ok(*hash);
+        }
+        if let Some(block) = self.blocks.get(&height) {
+            return block
+                .hash
+                .clone()
+                .try_into()
+                .map_err(|_| anyhow!("Invalid block hash in fixture"));
+        }
+        // Tree states have the hash in display order
+        let tree_state = self.tree_state(height).await?;
+        let mut hash: Hash = hex::decode(&tree_state.hash)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid block hash in fixture"))?;
+        hash.reverse();
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        network::Network,
+        scan::{scan, ScanEvent},
+        testing::{decoders, sapling_address, test_ufvk, MockLightwalletd, TestChain, TestTx},
+    };
+
+    #[tokio::test]
+    async fn replay_reproduces_scan() -> Result<()> {
+        let ufvk = test_ufvk();
+        let sapling = sapling_address(&ufvk, 0);
+        let mut chain = TestChain::new(100, 11);
+        chain.add_block(vec![
+            TestTx::new().sapling_output_with_memo(&sapling, 1_000, "hello")
+        ]);
+        chain.add_empty_blocks(1);
+        let lwd: Arc<dyn ChainSource> = Arc::new(MockLightwalletd::new(chain));
+
+        let path = std::env::temp_dir().join("zcash-walletd-replay.fixture");
+        let _ = std::fs::remove_file(&path);
+        let recording = RecordingSource::new(lwd.clone(), &path)?;
+
+        let prev_hash = lwd.block_hash(100).await?;
+        let end = recording.latest_height().await?;
+        let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+        let recorded = scan(
+            &Network::Regtest,
+            &recording,
+            101,
+            end,
+            &prev_hash,
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+
+        let replay = ReplaySource::open(&path)?;
+        assert_eq!(replay.latest_height().await?, end);
+        assert_eq!(replay.block_hash(101).await?, lwd.block_hash(101).await?);
+        let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+        let replayed = scan(
+            &Network::Regtest,
+            &replay,
+            101,
+            end,
+            &prev_hash,
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+
+        assert_eq!(format!("{recorded:?}"), format!("{replayed:?}"));
+        assert!(replayed
+            .iter()
+            .any(|e| matches!(e, ScanEvent::Memo(m) if m.memo == "hello")));
+        // Nothing past the recording
+        assert!(replay.tree_state(end).await.is_err());
+        Ok(())
+    }
+
+    /// Scan two blocks from `start` with the test key
+    async fn scan_from(
+        source: &dyn ChainSource,
+        start: u32,
+        prev_hash: &Hash,
+    ) -> Result<Vec<ScanEvent>> {
+        let (mut sap_dec, mut orc_dec) = decoders(&test_ufvk());
+        let events = scan(
+            &Network::Regtest,
+            source,
+            start,
+            start + 1,
+            prev_hash,
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+        Ok(events)
+    }
+
+    #[tokio::test]
+    async fn replay_follows_rewind() -> Result<()> {
+        let ufvk = test_ufvk();
+        let sapling = sapling_address(&ufvk, 0);
+        let mut chain = TestChain::new(100, 12);
+        chain.add_block(vec![TestTx::new().sapling_output(&sapling, 1_000)]);
+        chain.add_empty_blocks(1);
+        let mock = MockLightwalletd::new(chain);
+        let lwd: Arc<dyn ChainSource> = Arc::new(mock.clone());
+
+        let path = std::env::temp_dir().join("zcash-walletd-replay-rewind.fixture");
+        let _ = std::fs::remove_file(&path);
+        let recording = RecordingSource::new(lwd.clone(), &path)?;
+
+        let anchor = recording.block_hash(100).await?;
+        scan_from(&recording, 101, &anchor).await?;
+
+        // Block 102 is replaced, the wallet rewinds to 101 and scans the
+        // new branch from its hash
+        {
+            let mut chain = mock.chain();
+            chain.fork(101);
+            chain.add_block(vec![TestTx::new().sapling_output(&sapling, 2_000)]);
+            chain.add_empty_blocks(1);
+        }
+        let anchor = recording.block_hash(101).await?;
+        let recorded = scan_from(&recording, 102, &anchor).await?;
+
+        let replay = ReplaySource::open(&path)?;
+        assert_eq!(replay.block_hash(101).await?, anchor);
+        assert_eq!(replay.block_hash(102).await?, lwd.block_hash(102).await?);
+        let replayed = scan_from(&replay, 102, &replay.block_hash(101).await?).await?;
+        assert_eq!(format!("{recorded:?}"), format!("{replayed:?}"));
+        assert!(replayed
+            .iter()
+            .any(|e| matches!(e, ScanEvent::Received(n) if n.value == 2_000)));
+        Ok(())
+    }
+}
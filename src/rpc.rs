@@ -1,23 +1,102 @@
-use crate::{ZcashWalletd, info};
+use crate::auth::{AdminAccess, AuthChallenge, ReadAccess};
+use crate::error::{self, ErrorResponse, WalletError};
+use crate::export::{ExportFormat, ExportRange};
+use crate::{info, ZcashWalletd};
 use anyhow::Result;
-use rocket::response::Debug;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
 use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::{post, State};
+use rocket::{catch, get, post, Data, Request, State};
+use std::ops::Deref;
+
+/// Wallet selected by a `/wallets/<id>` prefix, with the URI the client
+/// sent
+pub struct WalletPrefix {
+    pub id: String,
+    pub uri: String,
+}
+
+/// Routes `/wallets/<id>/<method>` to `/<method>` on the wallet `id`
+pub struct WalletRouter;
+
+#[rocket::async_trait]
+impl Fairing for WalletRouter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Wallet router",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let uri = request.uri().to_string();
+        let Some((id, route)) = uri
+            .strip_prefix("/wallets/")
+            .and_then(|rest| rest.split_once('/'))
+        else {
+            return;
+        };
+        if let Ok(origin) = Origin::parse_owned(format!("/{route}")) {
+            let prefix = WalletPrefix {
+                id: id.to_string(),
+                uri: uri.clone(),
+            };
+            request.local_cache(|| Some(prefix));
+            request.set_uri(origin);
+        }
+    }
+}
+
+/// The wallet a request applies to, the default one without a prefix
+pub struct Wallet(ZcashWalletd);
+
+impl Deref for Wallet {
+    type Target = ZcashWalletd;
+
+    fn deref(&self) -> &ZcashWalletd {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Wallet {
+    type Error = WalletError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let wallet =
+            rocket::outcome::try_outcome!(request.guard::<&State<ZcashWalletd>>().await.map_error(
+                |(status, _)| (status, WalletError::Internal(anyhow::anyhow!("No wallet")))
+            ));
+        match request.local_cache(|| None::<WalletPrefix>) {
+            Some(prefix) => match wallet.for_wallet(&prefix.id) {
+                Ok(wallet) => Outcome::Success(Wallet(wallet)),
+                Err(error) => Outcome::Error((Status::NotFound, error.into())),
+            },
+            None => Outcome::Success(Wallet(wallet.inner().clone())),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateAccountRequest {
     label: Option<String>,
+    #[serde(flatten)]
+    options: crate::AddressOptions,
 }
 
 #[post("/create_account", data = "<request>")]
 pub async fn create_account(
+    _auth: AdminAccess,
     request: Json<CreateAccountRequest>,
-    wallet: &State<ZcashWalletd>,
-) -> Result<Json<crate::CreateAccountResponse>, Debug<anyhow::Error>> {
+    wallet: Wallet,
+) -> Result<Json<crate::CreateAccountResponse>, WalletError> {
     let request = request.into_inner();
     let name = request.label.unwrap_or("".to_string());
 
-    let rep = wallet.create_account(Some(name)).await?;
+    let rep = wallet.create_account(Some(name), request.options).await?;
 
     Ok(Json(rep))
 }
@@ -25,20 +104,116 @@ pub async fn create_account(
 pub struct CreateAddressRequest {
     account_index: u32,
     label: Option<String>,
+    /// Unix time after which the address is inactive if unpaid
+    expires_at: Option<u64>,
+    #[serde(flatten)]
+    options: crate::AddressOptions,
 }
 
 #[post("/create_address", data = "<request>")]
 pub async fn create_address(
+    _auth: AdminAccess,
     request: Json<CreateAddressRequest>,
-    wallet: &State<ZcashWalletd>,
-) -> Result<Json<crate::CreateAddressResponse>, Debug<anyhow::Error>> {
+    wallet: Wallet,
+) -> Result<Json<crate::CreateAddressResponse>, WalletError> {
     let request = request.into_inner();
     let name = request.label.unwrap_or("".to_string());
-    
-    let rep = wallet.create_address(request.account_index, Some(name)).await?;
+
+    let rep = wallet
+        .create_address(
+            request.account_index,
+            Some(name),
+            request.options,
+            request.expires_at,
+        )
+        .await?;
+
+    Ok(Json(rep))
+}
+#[derive(Serialize, Deserialize)]
+pub struct ImportViewingKeyRequest {
+    /// UFVK or UIVK
+    pub key: String,
+    pub birth_height: u32,
+    pub label: Option<String>,
+}
+
+#[post("/import_viewing_key", data = "<request>")]
+pub async fn import_viewing_key(
+    _auth: AdminAccess,
+    request: Json<ImportViewingKeyRequest>,
+    wallet: Wallet,
+) -> Result<Json<crate::ImportViewingKeyResponse>, WalletError> {
+    let request = request.into_inner();
+
+    let rep = wallet
+        .import_viewing_key(&request.key, request.birth_height, request.label)
+        .await?;
+
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReassignAddressRequest {
+    pub address: String,
+    pub account_index: u32,
+    pub address_index: u32,
+}
+
+#[post("/reassign_address", data = "<request>")]
+pub async fn reassign_address(
+    _auth: AdminAccess,
+    request: Json<ReassignAddressRequest>,
+    wallet: Wallet,
+) -> Result<Json<crate::ReassignAddressResponse>, WalletError> {
+    let request = request.into_inner();
+
+    let rep = wallet
+        .reassign_address(
+            &request.address,
+            request.account_index,
+            request.address_index,
+        )
+        .await?;
+
+    Ok(Json(rep))
+}
+
+#[get("/late_payments")]
+pub async fn get_late_payments(
+    _auth: ReadAccess,
+    wallet: Wallet,
+) -> Result<Json<crate::GetLatePaymentsResponse>, WalletError> {
+    let rep = wallet.get_late_payments().await?;
 
     Ok(Json(rep))
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportRequest {
+    #[serde(default)]
+    pub format: ExportFormat,
+    #[serde(flatten)]
+    pub range: ExportRange,
+}
+
+#[post("/export", data = "<request>")]
+pub async fn export(
+    _auth: ReadAccess,
+    request: Json<ExportRequest>,
+    wallet: Wallet,
+) -> Result<(ContentType, String), WalletError> {
+    let request = request.into_inner();
+
+    let rep = wallet.export(&request.range, request.format).await?;
+    let content_type = match request.format {
+        ExportFormat::Csv => ContentType::CSV,
+        ExportFormat::Json => ContentType::JSON,
+    };
+
+    Ok((content_type, rep))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetAccountsRequest {
     tag: Option<String>,
@@ -46,12 +221,33 @@ pub struct GetAccountsRequest {
 
 #[post("/get_accounts", data = "<_request>")]
 pub async fn get_accounts(
+    _auth: ReadAccess,
     _request: Json<GetAccountsRequest>,
-    wallet: &State<ZcashWalletd>,
-) -> Result<Json<crate::GetAccountsResponse>, Debug<anyhow::Error>> {
-    
+    wallet: Wallet,
+) -> Result<Json<crate::GetAccountsResponse>, WalletError> {
     let rep = wallet.get_accounts(None).await?;
-    
+
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetBalanceAtRequest {
+    pub height: Option<u32>,
+    pub timestamp: Option<u64>,
+}
+
+#[post("/balance_at", data = "<request>")]
+pub async fn get_balance_at(
+    _auth: ReadAccess,
+    request: Json<GetBalanceAtRequest>,
+    wallet: Wallet,
+) -> Result<Json<crate::GetBalanceAtResponse>, WalletError> {
+    let request = request.into_inner();
+
+    let rep = wallet
+        .get_balance_at(request.height, request.timestamp)
+        .await?;
+
     Ok(Json(rep))
 }
 
@@ -63,12 +259,15 @@ pub struct GetTransactionByIdRequest {
 
 #[post("/get_transfer_by_txid", data = "<request>")]
 pub async fn get_transaction(
+    _auth: ReadAccess,
     request: Json<GetTransactionByIdRequest>,
-    wallet: &State<ZcashWalletd>,
-) -> Result<Json<crate::GetTransactionByIdResponse>, Debug<anyhow::Error>> {
+    wallet: Wallet,
+) -> Result<Json<crate::GetTransactionByIdResponse>, WalletError> {
     let request = request.into_inner();
 
-    let rep = wallet.get_transaction(request.txid, request.account_index).await?;
+    let rep = wallet
+        .get_transaction(request.txid, request.account_index)
+        .await?;
     info!("{rep:?}");
     Ok(Json(rep))
 }
@@ -82,13 +281,15 @@ pub struct GetTransfersRequest {
 
 #[post("/get_transfers", data = "<request>")]
 pub async fn get_transfers(
+    _auth: ReadAccess,
     request: Json<GetTransfersRequest>,
-    wallet: &State<ZcashWalletd>,
-) -> Result<Json<crate::GetTransfersResponse>, Debug<anyhow::Error>> {
+    wallet: Wallet,
+) -> Result<Json<crate::GetTransfersResponse>, WalletError> {
     let request = request.into_inner();
-    assert!(request.r#in);
-    
-    let rep = wallet.get_transfers(request.account_index, true, request.subaddr_indices).await?;
+
+    let rep = wallet
+        .get_transfers(request.account_index, request.r#in, request.subaddr_indices)
+        .await?;
     Ok(Json(rep))
 }
 
@@ -97,8 +298,9 @@ pub struct GetFeeEstimateRequest {}
 
 #[post("/get_fee_estimate", data = "<_request>")]
 pub fn get_fee_estimate(
+    _auth: ReadAccess,
     _request: Json<GetFeeEstimateRequest>,
-) -> Result<Json<crate::GetFeeEstimateResponse>, Debug<anyhow::Error>> {
+) -> Result<Json<crate::GetFeeEstimateResponse>, WalletError> {
     let rep = crate::get_fee_estimate();
 
     Ok(Json(rep))
@@ -109,32 +311,175 @@ pub struct GetHeightRequest {}
 
 #[post("/get_height", data = "<_request>")]
 pub async fn get_height(
+    _auth: ReadAccess,
     _request: Json<GetHeightRequest>,
-    wallet: &State<ZcashWalletd>,
-) -> Result<Json<crate::GetHeightResponse>, Debug<anyhow::Error>> {
+    wallet: Wallet,
+) -> Result<Json<crate::GetHeightResponse>, WalletError> {
     let rep = wallet.get_height().await?;
 
     Ok(Json(rep))
 }
 
+#[post("/get_wallet_height", data = "<_request>")]
+pub async fn get_wallet_height(
+    _auth: ReadAccess,
+    _request: Json<GetHeightRequest>,
+    wallet: Wallet,
+) -> Result<Json<crate::GetHeightResponse>, WalletError> {
+    let rep = wallet.get_wallet_height().await?;
+
+    Ok(Json(rep))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SyncInfoRequest {}
 
 #[post("/sync_info", data = "<_request>")]
 pub async fn sync_info(
+    _auth: ReadAccess,
     _request: Json<SyncInfoRequest>,
-    wallet: &State<ZcashWalletd>,
-) -> Result<Json<crate::SyncInfoResponse>, Debug<anyhow::Error>> {
+    wallet: Wallet,
+) -> Result<Json<crate::SyncInfoResponse>, WalletError> {
     let rep = wallet.sync_info().await?;
 
     Ok(Json(rep))
 }
 
-#[post("/request_scan")]
+#[derive(Serialize, Deserialize)]
+pub struct RequestScanRequest {
+    pub wait: Option<bool>,
+}
+
+/// Without a body, or with `wait` unset, the call returns once the scan
+/// has finished
+#[post("/request_scan", data = "<request>")]
 pub async fn request_scan(
-    wallet: &State<ZcashWalletd>,
-) -> Result<(), Debug<anyhow::Error>> {
-    wallet.request_scan().await?;
+    _auth: ReadAccess,
+    request: Option<Json<RequestScanRequest>>,
+    wallet: Wallet,
+) -> Result<Json<crate::RequestScanResponse>, WalletError> {
+    let wait = request.and_then(|r| r.into_inner().wait).unwrap_or(true);
+
+    let rep = wallet.request_scan_job(wait).await?;
+
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetScanJobRequest {
+    pub job_id: u64,
+}
+
+#[post("/get_scan_job", data = "<request>")]
+pub async fn get_scan_job(
+    _auth: ReadAccess,
+    request: Json<GetScanJobRequest>,
+    wallet: Wallet,
+) -> Result<Json<crate::RequestScanResponse>, WalletError> {
+    let request = request.into_inner();
+
+    let rep = wallet.get_scan_job(request.job_id)?;
+
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RescanBlockchainRequest {}
+
+#[post("/rescan_blockchain", data = "<_request>")]
+pub async fn rescan_blockchain(
+    _auth: AdminAccess,
+    _request: Json<RescanBlockchainRequest>,
+    wallet: Wallet,
+) -> Result<Json<crate::RewindResponse>, WalletError> {
+    let rep = wallet.rescan().await?;
+
+    Ok(Json(rep))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RewindRequest {
+    pub height: u32,
+}
+
+#[post("/rewind", data = "<request>")]
+pub async fn rewind(
+    _auth: AdminAccess,
+    request: Json<RewindRequest>,
+    wallet: Wallet,
+) -> Result<Json<crate::RewindResponse>, WalletError> {
+    let request = request.into_inner();
+
+    let rep = wallet.rewind_to(request.height).await?;
+
+    Ok(Json(rep))
+}
+
+#[get("/status")]
+pub async fn status(
+    _auth: ReadAccess,
+    wallet: Wallet,
+) -> Result<Json<crate::StatusResponse>, WalletError> {
+    let rep = wallet.status().await?;
+
+    Ok(Json(rep))
+}
+
+#[get("/health/live")]
+pub fn liveness() -> Status {
+    Status::Ok
+}
+
+#[get("/health/ready")]
+pub async fn readiness(wallet: &State<ZcashWalletd>) -> Status {
+    if wallet.is_ready().await {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    }
+}
+
+#[get("/metrics")]
+pub fn metrics() -> Result<String, WalletError> {
+    let rep = crate::metrics::gather()?;
+
+    Ok(rep)
+}
+
+pub struct CatcherResponse {
+    status: Status,
+    body: ErrorResponse,
+    challenge: Option<String>,
+}
+
+impl<'r> Responder<'r, 'static> for CatcherResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = (self.status, Json(self.body)).respond_to(request)?;
+        if let Some(challenge) = self.challenge {
+            response.set_raw_header("WWW-Authenticate", challenge);
+        }
+        Ok(response)
+    }
+}
+
+/// JSON body for the errors raised by Rocket itself, e.g. an unknown
+/// route, a malformed request or a failed authentication
+#[catch(default)]
+pub fn default_catcher(status: Status, request: &Request) -> CatcherResponse {
+    let code = match status.code {
+        400 | 422 => error::INVALID_ARGUMENT,
+        401 | 403 => error::DENIED,
+        404 => error::NOT_FOUND,
+        _ => error::UNKNOWN_ERROR,
+    };
+    let challenge = request.local_cache(AuthChallenge::default).0.clone();
 
-    Ok(())
+    CatcherResponse {
+        status,
+        body: ErrorResponse {
+            code,
+            message: status.reason_lossy().to_string(),
+        },
+        challenge,
+    }
 }
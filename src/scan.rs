@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
 
 use anyhow::Result;
 use orchard::{
@@ -11,11 +14,10 @@ use orchard::{
 use sapling_crypto::{
     bundle::OutputDescription,
     note_encryption::{SaplingDomain, Zip212Enforcement},
-    zip32::DiversifiableFullViewingKey,
     NullifierDerivingKey, PaymentAddress,
 };
 use thiserror::Error;
-use tonic::{transport::Channel, Request};
+use tokio_stream::StreamExt;
 use tracing::info;
 use zcash_address::unified::{self, Encoding};
 use zcash_keys::encoding::AddressCodec;
@@ -30,172 +32,274 @@ use zcash_protocol::{
     consensus::{BlockHeight, BranchId, Parameters},
     memo::{Memo, MemoBytes},
 };
+use zip32::DiversifierIndex;
 
 use crate::{
-    lwd_rpc::{
-        compact_tx_streamer_client::CompactTxStreamerClient, BlockId, BlockRange, ChainSpec,
-        CompactOrchardAction, CompactSaplingOutput, TxFilter,
-    },
-    network::Network, Client, Hash,
+    chain::ChainSource,
+    keys::ViewingKey,
+    lwd_rpc::{CompactOrchardAction, CompactSaplingOutput},
+    metrics::{OUTPUTS_SCANNED, TRIAL_DECRYPTIONS_RATE},
+    network::Network,
+    Hash,
 };
 
-pub async fn get_latest_height(client: &mut CompactTxStreamerClient<Channel>) -> Result<u32> {
-    let latest_block_id = client
-        .get_latest_block(Request::new(ChainSpec {}))
-        .await?
-        .into_inner();
-    let latest_height = latest_block_id.height;
-    Ok(latest_height as u32)
+/// Decoders of a wallet and the events found for it during a scan
+pub struct WalletScan {
+    /// Last block already scanned for the wallet, and its hash
+    pub synced_height: u32,
+    pub synced_hash: Hash,
+    pub sap_dec: Option<Decoder<Sapling>>,
+    pub orc_dec: Option<Decoder<Orchard>>,
+    pub events: Vec<ScanEvent>,
+    new_txids: Vec<WalletTx>,
+}
+
+impl WalletScan {
+    pub fn new(
+        synced_height: u32,
+        synced_hash: Hash,
+        sap_dec: Option<Decoder<Sapling>>,
+        orc_dec: Option<Decoder<Orchard>>,
+    ) -> Self {
+        Self {
+            synced_height,
+            synced_hash,
+            sap_dec,
+            orc_dec,
+            events: vec![],
+            new_txids: vec![],
+        }
+    }
 }
 
 pub async fn scan(
     network: &Network,
-    client: &mut Client,
+    chain: &dyn ChainSource,
     start: u32,
     end: u32,
     prev_hash: &Hash,
     sap_dec: &mut Option<Decoder<Sapling>>,
     orc_dec: &mut Option<Decoder<Orchard>>,
 ) -> Result<Vec<ScanEvent>, ScanError> {
-    let tree_state = client
-        .get_tree_state(Request::new(BlockId {
-            height: start as u64,
-            hash: vec![],
-        }))
-        .await
-        .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
-        .into_inner();
-
-    let mut blocks = client
-        .get_block_range(Request::new(BlockRange {
-            start: Some(BlockId {
-                height: start as u64,
-                hash: vec![],
-            }),
-            end: Some(BlockId {
-                height: end as u64,
-                hash: vec![],
-            }),
-            spam_filter_threshold: 0,
-        }))
-        .await
-        .map_err(|e| ScanError::Other(anyhow::Error::new(e)))?
-        .into_inner();
-    let mut prev_hash = *prev_hash;
+    let mut wallets = [WalletScan::new(
+        start - 1,
+        *prev_hash,
+        sap_dec.take(),
+        orc_dec.take(),
+    )];
+    let res = scan_wallets(network, chain, end, &mut wallets).await;
+    let [wallet] = wallets;
+    *sap_dec = wallet.sap_dec;
+    *orc_dec = wallet.orc_dec;
+    res?;
+    Ok(wallet.events)
+}
+
+/// Scan the blocks up to `end` once for several wallets. Each wallet only
+/// gets the events of the blocks above its synced height, the scan
+/// starts after the lowest one.
+pub async fn scan_wallets(
+    network: &Network,
+    chain: &dyn ChainSource,
+    end: u32,
+    wallets: &mut [WalletScan],
+) -> Result<(), ScanError> {
+    let Some(start) = wallets.iter().map(|w| w.synced_height + 1).min() else {
+        return Ok(());
+    };
+    let started = Instant::now();
+    // Positions of the first outputs of `start` are the tree sizes at
+    // the end of the previous block
+    let tree_state = chain.tree_state(start - 1).await?;
+    let mut blocks = chain.block_range(start, end).await?;
     let mut sap_position = get_tree_size(&tree_state.sapling_tree).unwrap();
     let mut orc_position = get_tree_size(&tree_state.orchard_tree).unwrap();
 
-    let mut events = vec![];
-    let mut new_txids = vec![];
-    while let Ok(Some(block)) = blocks.message().await {
+    let mut trial_decryptions = 0u64;
+    let mut last_height = start - 1;
+    let mut last_hash = [0u8; 32];
+    // Block times do not always increase, their running maximum does
+    let mut max_time = tree_state.time;
+    let mut scanned = vec![];
+    while let Some(block) = blocks.next().await {
+        let block = block?;
         let height = block.height as u32;
+        let time = block.time;
+        last_height = height;
         let block_prev_hash: Hash = block.prev_hash.try_into().unwrap();
-        if prev_hash != block_prev_hash {
+        // Blocks must follow each other, and every wallet synced to the
+        // previous block must agree with the chain
+        if (height > start && block_prev_hash != last_hash)
+            || wallets
+                .iter()
+                .any(|w| w.synced_height + 1 == height && w.synced_hash != block_prev_hash)
+        {
             info!("Reorg at {} {}", block.height, hex::encode(block_prev_hash));
             return Err(ScanError::Reorganization);
         }
-        prev_hash = block.hash.try_into().unwrap();
+        last_hash = block.hash.try_into().unwrap();
+        max_time = max_time.max(time);
+        scanned.push((height, last_hash, max_time));
 
         for vtx in block.vtx.iter() {
-            let mut found = false;
-            if let Some(sap_dec) = sap_dec {
-                for i in vtx.spends.iter() {
-                    let nf: &Hash = i.nf.as_slice().try_into().unwrap();
-                    if let Some(value) = sap_dec.nfs.get(nf) {
-                        events.push(ScanEvent::Spent(SpentNote {
+            for w in wallets.iter_mut().filter(|w| w.synced_height < height) {
+                let mut found = false;
+                if let Some(sap_dec) = &mut w.sap_dec {
+                    for i in vtx.spends.iter() {
+                        let nf: &Hash = i.nf.as_slice().try_into().unwrap();
+                        if let Some(value) = sap_dec.nfs.get(nf) {
+                            w.events.push(ScanEvent::Spent(SpentNote {
+                                height,
+                                time,
+                                pool: 1,
+                                nf: *nf,
+                                txid: vtx.hash.clone().try_into().unwrap(),
+                                value: *value,
+                            }));
+                        }
+                    }
+                    trial_decryptions += vtx.outputs.len() as u64;
+                    OUTPUTS_SCANNED
+                        .with_label_values(&["sapling"])
+                        .inc_by(vtx.outputs.len() as u64);
+
+                    for (vout, o) in vtx.outputs.iter().enumerate() {
+                        if let Some(n) = sap_dec.try_compact_note_decryption(
+                            network,
                             height,
-                            nf: *nf,
-                            txid: vtx.hash.clone().try_into().unwrap(),
-                            value: *value,
-                        }));
+                            time,
+                            &vtx.hash,
+                            sap_position + vout as u32,
+                            o,
+                        )? {
+                            if let Some(nf) = n.nf {
+                                sap_dec.add_nf(nf, n.value);
+                            }
+                            w.events.push(ScanEvent::Received(n));
+                            found = true;
+                        }
                     }
                 }
 
-                for (vout, o) in vtx.outputs.iter().enumerate() {
-                    if let Some(n) = sap_dec.try_compact_note_decryption(
-                        network,
-                        height,
-                        &vtx.hash,
-                        sap_position + vout as u32,
-                        o,
-                    )? {
-                        sap_dec.add_nf(n.nf, n.value);
-                        events.push(ScanEvent::Received(n));
-                        found = true;
+                if let Some(orc_dec) = &mut w.orc_dec {
+                    trial_decryptions += vtx.actions.len() as u64;
+                    OUTPUTS_SCANNED
+                        .with_label_values(&["orchard"])
+                        .inc_by(vtx.actions.len() as u64);
+                    for (vout, a) in vtx.actions.iter().enumerate() {
+                        let nf: &Hash = a.nullifier.as_slice().try_into().unwrap();
+                        if let Some(value) = orc_dec.nfs.get(nf) {
+                            w.events.push(ScanEvent::Spent(SpentNote {
+                                height,
+                                time,
+                                pool: 2,
+                                nf: *nf,
+                                txid: vtx.hash.clone().try_into().unwrap(),
+                                value: *value,
+                            }));
+                        }
+                        if let Some(n) = orc_dec.try_compact_note_decryption(
+                            network,
+                            height,
+                            time,
+                            &vtx.hash,
+                            orc_position + vout as u32,
+                            a,
+                        )? {
+                            if let Some(nf) = n.nf {
+                                orc_dec.add_nf(nf, n.value);
+                            }
+                            w.events.push(ScanEvent::Received(n));
+                            found = true;
+                        }
                     }
                 }
-            }
 
-            if let Some(orc_dec) = orc_dec {
-                for (vout, a) in vtx.actions.iter().enumerate() {
-                    let nf: &Hash = a.nullifier.as_slice().try_into().unwrap();
-                    if let Some(value) = orc_dec.nfs.get(nf) {
-                        events.push(ScanEvent::Spent(SpentNote {
-                            height,
-                            nf: *nf,
-                            txid: vtx.hash.clone().try_into().unwrap(),
-                            value: *value,
-                        }));
-                    }
-                    if let Some(n) = orc_dec.try_compact_note_decryption(
-                        network,
+                if found {
+                    let txid: Hash = vtx.hash.clone().try_into().unwrap();
+                    w.new_txids.push(WalletTx {
                         height,
-                        &vtx.hash,
-                        orc_position + vout as u32,
-                        a,
-                    )? {
-                        orc_dec.add_nf(n.nf, n.value);
-                        events.push(ScanEvent::Received(n));
-                        found = true;
-                    }
+                        txid,
+                        sap_position,
+                        orc_position,
+                    });
                 }
             }
 
-            if found {
-                let txid: Hash = vtx.hash.clone().try_into().unwrap();
-                new_txids.push(WalletTx {
-                    height,
-                    txid,
-                    sap_position,
-                    orc_position,
-                });
-            }
-
             sap_position += vtx.outputs.len() as u32;
             orc_position += vtx.actions.len() as u32;
         }
     }
 
-    for wtx in new_txids.iter() {
-        let memos = scan_tx(network, client, wtx, sap_dec, orc_dec).await?;
-        for m in memos {
-            events.push(ScanEvent::Memo(m));
+    // A stream cut short by the server must not be recorded as synced
+    if last_height != end {
+        return Err(ScanError::Other(anyhow::anyhow!(
+            "Block range ended at {last_height} instead of {end}"
+        )));
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+        TRIAL_DECRYPTIONS_RATE.set(trial_decryptions as f64 / elapsed);
+    }
+
+    for w in wallets.iter_mut().filter(|w| w.synced_height < end) {
+        for wtx in std::mem::take(&mut w.new_txids) {
+            let (memos, fee) = scan_tx(network, chain, &wtx, &w.sap_dec, &w.orc_dec).await?;
+            for m in memos {
+                w.events.push(ScanEvent::Memo(m));
+            }
+            if let Some(fee) = fee {
+                w.events.push(ScanEvent::Fee(wtx.txid, fee));
+            }
+        }
+        // The last block, and the blocks where the wallet received or
+        // spent notes, between which the balance does not change
+        let active: HashSet<u32> = w
+            .events
+            .iter()
+            .filter_map(|e| match e {
+                ScanEvent::Received(n) => Some(n.height),
+                ScanEvent::Spent(n) => Some(n.height),
+                _ => None,
+            })
+            .collect();
+        for &(height, hash, max_time) in scanned
+            .iter()
+            .filter(|b| b.0 > w.synced_height && (b.0 == end || active.contains(&b.0)))
+        {
+            w.events.push(ScanEvent::Block(height, hash, max_time));
         }
     }
-    events.push(ScanEvent::Block(end, prev_hash));
 
-    Ok(events)
+    Ok(())
 }
 
+/// Memos of the notes of the wallet in the full transaction, and its
+/// fee when it is fully shielded
 pub async fn scan_tx(
     network: &Network,
-    client: &mut Client,
+    chain: &dyn ChainSource,
     wtx: &WalletTx,
     sap_dec: &Option<Decoder<Sapling>>,
     orc_dec: &Option<Decoder<Orchard>>,
-) -> Result<Vec<MemoNote>> {
+) -> Result<(Vec<MemoNote>, Option<u64>)> {
     let mut notes = vec![];
-    let raw_tx = client
-        .get_transaction(Request::new(TxFilter {
-            hash: wtx.txid.to_vec(),
-            ..TxFilter::default()
-        }))
-        .await?
-        .into_inner();
+    let raw_tx = chain.raw_transaction(&wtx.txid).await?;
     let branch_id = BranchId::for_height(network, BlockHeight::from_u32(wtx.height));
-    let tx = Transaction::read(&*raw_tx.data, branch_id)?;
+    let tx = Transaction::read(&*raw_tx, branch_id)?;
     let tx = tx.into_data();
 
+    // Without transparent or Sprout parts, the value balances of the
+    // shielded pools add up to the fee
+    let fee = if tx.transparent_bundle().is_none() && tx.sprout_bundle().is_none() {
+        let sapling = tx.sapling_bundle().map(|b| i64::from(*b.value_balance()));
+        let orchard = tx.orchard_bundle().map(|b| i64::from(*b.value_balance()));
+        u64::try_from(sapling.unwrap_or_default() + orchard.unwrap_or_default()).ok()
+    } else {
+        None
+    };
+
     if let Some(sap_dec) = sap_dec {
         if let Some(sapling_bundle) = tx.sapling_bundle() {
             for (vout, o) in sapling_bundle.shielded_outputs().iter().enumerate() {
@@ -218,7 +322,29 @@ pub async fn scan_tx(
             }
         }
     }
-    Ok(notes)
+    Ok((notes, fee))
+}
+
+/// Decoders of the external scope of `key`. Only a full viewing key
+/// gives the nullifiers of the notes.
+pub fn decoders(
+    key: &ViewingKey,
+    nfs: &HashMap<Hash, u64>,
+) -> (Option<Decoder<Sapling>>, Option<Decoder<Orchard>>) {
+    let uivk = key.to_uivk();
+    let sap_dec = uivk.sapling().as_ref().map(|ivk| {
+        let nk = key
+            .ufvk()
+            .and_then(|k| k.sapling())
+            .map(|fvk| fvk.fvk().vk.nk);
+        Decoder::<Sapling>::new(nk, ivk.clone(), ivk.prepare(), nfs)
+    });
+    let orc_dec = uivk.orchard().as_ref().map(|ivk| {
+        let nk = key.ufvk().and_then(|k| k.orchard()).cloned();
+        let pivk = orchard::keys::PreparedIncomingViewingKey::new(ivk);
+        Decoder::<Orchard>::new(nk, ivk.clone(), pivk, nfs)
+    });
+    (sap_dec, orc_dec)
 }
 
 pub fn get_tree_size(tree: &str) -> Result<u32> {
@@ -248,24 +374,32 @@ pub struct ReceivedNote {
     pub pool: u8,
     pub position: u32,
     pub height: u32,
+    /// Time of the block
+    pub time: u32,
     pub address: String,
     pub diversifier: [u8; 11],
-    pub diversifier_index: Option<u64>,
+    /// Full 88 bit index, valid for the receiver
+    pub diversifier_index: Option<DiversifierIndex>,
     pub value: u64,
     pub rcm: Hash,
-    pub nf: Hash,
+    /// None when the wallet only has the incoming viewing key
+    pub nf: Option<Hash>,
     pub rho: Option<Hash>,
 }
 
+/// Memo of the note at `position` in the commitment tree of `pool`
 #[derive(Debug)]
 pub struct MemoNote {
-    pub nf: Hash,
+    pub pool: u8,
+    pub position: u32,
     pub memo: String,
 }
 
 #[derive(Debug)]
 pub struct SpentNote {
     pub height: u32,
+    pub time: u32,
+    pub pool: u8,
     pub nf: Hash,
     pub txid: Hash,
     pub value: u64,
@@ -273,17 +407,22 @@ pub struct SpentNote {
 
 #[derive(Debug)]
 pub enum ScanEvent {
-    Block(u32, Hash),
+    /// Height, hash and highest block time up to the block, of the last
+    /// scanned block, the new synced height, and of the blocks with notes
+    /// received or spent by the wallet
+    Block(u32, Hash, u32),
     Received(ReceivedNote),
     Spent(SpentNote),
     Memo(MemoNote),
+    /// Fee of a fully shielded transaction that paid the wallet
+    Fee(Hash, u64),
 }
 
 impl Pool for Sapling {
     type Address = PaymentAddress;
     type PreparedIncomingViewingKey = sapling_crypto::keys::PreparedIncomingViewingKey;
     type NullifierKey = NullifierDerivingKey;
-    type DiversifierKey = DiversifiableFullViewingKey;
+    type DiversifierKey = sapling_crypto::zip32::IncomingViewingKey;
     type CompactOutput = CompactSaplingOutput;
     type Output = OutputDescription<[u8; 192]>;
 }
@@ -293,16 +432,18 @@ pub trait Decode<P: Pool> {
         &self,
         network: &Network,
         height: u32,
+        time: u32,
         txid: &[u8],
         position: u32,
         output: &P::CompactOutput,
     ) -> Result<Option<ReceivedNote>>;
     fn try_note_decryption(&self, position: u32, output: &P::Output) -> Result<Option<MemoNote>>;
-    fn decrypt_diversifier(&self, address: &P::Address) -> Result<Option<u64>>;
+    fn decrypt_diversifier(&self, address: &P::Address) -> Result<Option<DiversifierIndex>>;
 }
 
 pub struct Decoder<P: Pool> {
-    pub nk: P::NullifierKey,
+    /// Missing for a UIVK, notes then have no nullifier
+    pub nk: Option<P::NullifierKey>,
     pub dk: P::DiversifierKey,
     pub pivk: P::PreparedIncomingViewingKey,
     pub nfs: HashMap<Hash, u64>,
@@ -310,7 +451,7 @@ pub struct Decoder<P: Pool> {
 
 impl<P: Pool> Decoder<P> {
     pub fn new(
-        nk: P::NullifierKey,
+        nk: Option<P::NullifierKey>,
         dk: P::DiversifierKey,
         pivk: P::PreparedIncomingViewingKey,
         nfs: &HashMap<Hash, u64>,
@@ -333,6 +474,7 @@ impl Decode<Sapling> for Decoder<Sapling> {
         &self,
         network: &Network,
         height: u32,
+        time: u32,
         txid: &[u8],
         position: u32,
         output: &CompactSaplingOutput,
@@ -343,7 +485,7 @@ impl Decode<Sapling> for Decoder<Sapling> {
             let diversifier = pa.diversifier().0;
             let value = note.value().inner();
             let rcm = note.rcm().to_bytes();
-            let nf = note.nf(&self.nk, position as u64);
+            let nf = self.nk.as_ref().map(|nk| note.nf(nk, position as u64).0);
             let di = self.decrypt_diversifier(&pa)?;
 
             let note = ReceivedNote {
@@ -351,12 +493,13 @@ impl Decode<Sapling> for Decoder<Sapling> {
                 pool: 1,
                 position,
                 height,
+                time,
                 address,
                 diversifier,
                 diversifier_index: di,
                 value,
                 rcm,
-                nf: nf.to_vec().try_into().unwrap(),
+                nf,
                 rho: None,
             };
             return Ok(Some(note));
@@ -370,10 +513,10 @@ impl Decode<Sapling> for Decoder<Sapling> {
         output: &OutputDescription<[u8; 192]>,
     ) -> Result<Option<MemoNote>> {
         let domain = SaplingDomain::new(Zip212Enforcement::On);
-        if let Some((note, _pa, memo_bytes)) = try_note_decryption(&domain, &self.pivk, output) {
-            let nf = note.nf(&self.nk, position as u64);
+        if let Some((_, _, memo_bytes)) = try_note_decryption(&domain, &self.pivk, output) {
             let memo_note = MemoNote {
-                nf: nf.0,
+                pool: 1,
+                position,
                 memo: memo_text(&memo_bytes)?,
             };
             return Ok(Some(memo_note));
@@ -381,12 +524,8 @@ impl Decode<Sapling> for Decoder<Sapling> {
         Ok(None)
     }
 
-    fn decrypt_diversifier(&self, address: &PaymentAddress) -> Result<Option<u64>> {
-        if let Some((di, _)) = self.dk.decrypt_diversifier(address) {
-            let di: u64 = di.try_into()?;
-            return Ok(Some(di));
-        }
-        Ok(None)
+    fn decrypt_diversifier(&self, address: &PaymentAddress) -> Result<Option<DiversifierIndex>> {
+        Ok(self.dk.decrypt_diversifier(address))
     }
 }
 
@@ -406,6 +545,7 @@ impl Decode<Orchard> for Decoder<Orchard> {
         &self,
         network: &Network,
         height: u32,
+        time: u32,
         txid: &[u8],
         position: u32,
         action: &CompactOrchardAction,
@@ -425,7 +565,7 @@ impl Decode<Orchard> for Decoder<Orchard> {
             let diversifier = *address.diversifier().as_array();
             let value = note.value().inner();
             let rcm = *note.rseed().as_bytes();
-            let nf = note.nullifier(&self.nk);
+            let nf = self.nk.as_ref().map(|fvk| note.nullifier(fvk).to_bytes());
             let rho = note.rho().to_bytes();
             let di = self.decrypt_diversifier(&address)?;
 
@@ -434,12 +574,13 @@ impl Decode<Orchard> for Decoder<Orchard> {
                 pool: 2,
                 position,
                 height,
+                time,
                 address: ua,
                 diversifier,
                 diversifier_index: di,
                 value,
                 rcm,
-                nf: nf.to_bytes(),
+                nf,
                 rho: Some(rho),
             };
             return Ok(Some(note));
@@ -449,15 +590,14 @@ impl Decode<Orchard> for Decoder<Orchard> {
 
     fn try_note_decryption(
         &self,
-        _position: u32,
+        position: u32,
         action: &Action<Signature<SpendAuth>>,
     ) -> Result<Option<MemoNote>> {
         let domain = OrchardDomain::for_action(action);
-        if let Some((note, _address, memo_bytes)) = try_note_decryption(&domain, &self.pivk, action)
-        {
-            let nf = note.nullifier(&self.nk);
+        if let Some((_, _, memo_bytes)) = try_note_decryption(&domain, &self.pivk, action) {
             let memo_note = MemoNote {
-                nf: nf.to_bytes(),
+                pool: 2,
+                position,
                 memo: memo_text(&memo_bytes)?,
             };
             return Ok(Some(memo_note));
@@ -466,12 +606,8 @@ impl Decode<Orchard> for Decoder<Orchard> {
         Ok(None)
     }
 
-    fn decrypt_diversifier(&self, address: &Address) -> Result<Option<u64>> {
-        if let Some(di) = self.dk.diversifier_index(address) {
-            let di: u64 = di.try_into()?;
-            return Ok(Some(di));
-        }
-        Ok(None)
+    fn decrypt_diversifier(&self, address: &Address) -> Result<Option<DiversifierIndex>> {
+        Ok(self.dk.diversifier_index(address))
     }
 }
 
@@ -541,51 +677,334 @@ pub enum ScanError {
     Other(#[from] anyhow::Error),
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use crate::db::Db;
-
-//     use super::*;
-//     use anyhow::Result;
-
-//     const FVK: &str = "uview1s5ranpd74zd2pseylw0fmt0cnudf9765mwjjd9mqf8tvjq2nlw9vgypzqayfvs7aeedguwl4r7exz50nrw6llfs3n9xfd4sm2slaay7smysc4yjyuwu3z7n5ccvyw70qkw28yt6xwra6c8d20ewpjeqq4enmftyly3fmn78hwwkyffp2y4x2vk8050vcly8y5fuse5s9e5j4wmwuldemxahrp4zrgatj63mnpqlpacvcudqfsm5ee29pj8lr5wt93eyrx3fwa64m6505cge6n46c7eqw59e0n3m9rmsntcflfmu9wyjgfk2pmjf4npkml93vyq0fps2rh4mdwpz4ld059m6mamjht99j7sdypwx52lj6lvrfgwja4uf7qy2g8d6gkmvkh7u4dksq5gazxvye4gtwfgwmuygg2sqmkkf4fjd3ymf0mq99rhf0trsl0lpddw64r4n7jj7mxy6fcpj64vkx0pre2lla9p8nknrt2c33zy3vaczd";
-
-//     #[tokio::test]
-//     async fn test() -> Result<()> {
-//         let mut client = CompactTxStreamerClient::connect("https://zec.rocks".to_string()).await?;
-
-//         let prev_hash =
-//             hex::decode("5f03d35ae940bb840564c3b7af7ab72255096d3eca15c910c0e40d0000000000")
-//                 .unwrap();
-//         let ufvk = zcash_keys::keys::UnifiedFullViewingKey::decode(&Network::Main, FVK).unwrap();
-//         let mut sap_dec = ufvk.sapling().map(|fvk| {
-//             let nk = fvk.fvk().vk.nk;
-//             let ivk = fvk.to_ivk(zip32::Scope::External);
-//             let pivk = sapling_crypto::keys::PreparedIncomingViewingKey::new(&ivk);
-//             Decoder::<Sapling>::new(nk, fvk.clone(), pivk, &HashMap::new())
-//         });
-//         let mut orc_dec = ufvk.orchard().map(|fvk| {
-//             let ivk = fvk.to_ivk(zip32::Scope::External);
-//             let pivk = orchard::keys::PreparedIncomingViewingKey::new(&ivk);
-//             Decoder::<Orchard>::new(fvk.clone(), ivk, pivk, &HashMap::new())
-//         });
-
-//         let events = scan(
-//             &Network::Main,
-//             &mut client,
-//             2_890_000,
-//             2_900_000,
-//             &prev_hash.try_into().unwrap(),
-//             &mut sap_dec,
-//             &mut orc_dec,
-//         )
-//         .await?;
-
-//         println!("{events:?}");
-
-//         let db = Db::new(Network::Main, "zec-wallet-test.db", &ufvk, "").await?;
-//         db.store_events(&events).await?;
-
-//         Ok(())
-//     }
-// }
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        decoders, orchard_address, sapling_address, test_ufvk, MockLightwalletd, TestChain, TestTx,
+    };
+
+    fn block_hash(lwd: &MockLightwalletd, height: u32) -> Hash {
+        lwd.chain()
+            .block(height)
+            .unwrap()
+            .hash
+            .clone()
+            .try_into()
+            .unwrap()
+    }
+
+    fn received(events: &[ScanEvent]) -> Vec<&ReceivedNote> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                ScanEvent::Received(n) => Some(n),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn scan_finds_notes_and_memos() -> Result<()> {
+        let ufvk = test_ufvk();
+        let mut chain = TestChain::new(100, 1);
+        chain.add_block(vec![
+            TestTx::new().sapling_output_with_memo(&sapling_address(&ufvk, 0), 10_000, "first"),
+            TestTx::new().orchard_output_with_memo(&orchard_address(&ufvk, 0), 20_000, "second"),
+        ]);
+        chain.add_block(vec![TestTx::new()
+            .sapling_output(&sapling_address(&ufvk, 5), 30_000)
+            .orchard_output(&orchard_address(&ufvk, 5), 40_000)]);
+        let lwd = MockLightwalletd::new(chain);
+
+        let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+        let events = scan(
+            &Network::Regtest,
+            &lwd,
+            101,
+            102,
+            &block_hash(&lwd, 100),
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+
+        let notes = received(&events);
+        let summary: Vec<_> = notes
+            .iter()
+            .map(|n| (n.pool, n.position, n.value))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, 0, 10_000),
+                (2, 0, 20_000),
+                (1, 1, 30_000),
+                (2, 1, 40_000)
+            ]
+        );
+        assert_eq!(
+            notes[0].diversifier_index,
+            Some(DiversifierIndex::from(0u32))
+        );
+
+        let memos: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ScanEvent::Memo(m) => Some((m.pool, m.position, m.memo.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert!(memos.contains(&(1, notes[0].position, "first")));
+        assert!(memos.contains(&(2, notes[1].position, "second")));
+
+        match events.last() {
+            Some(ScanEvent::Block(height, hash, _)) => {
+                assert_eq!(*height, 102);
+                assert_eq!(*hash, block_hash(&lwd, 102));
+            }
+            e => panic!("Unexpected last event {e:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_with_incoming_viewing_key() -> Result<()> {
+        let ufvk = test_ufvk();
+        let mut chain = TestChain::new(100, 5);
+        let built = chain.add_block(vec![TestTx::new()
+            .sapling_output_with_memo(&sapling_address(&ufvk, 0), 1_000, "sapling")
+            .orchard_output(&orchard_address(&ufvk, 0), 2_000)]);
+        chain.add_block(vec![
+            TestTx::new().orchard_spend(&built[0].notes[1].nullifier(&ufvk))
+        ]);
+        let lwd = MockLightwalletd::new(chain);
+
+        let key = ViewingKey::Incoming(ufvk.to_unified_incoming_viewing_key());
+        let (mut sap_dec, mut orc_dec) = crate::scan::decoders(&key, &HashMap::new());
+        let events = scan(
+            &Network::Regtest,
+            &lwd,
+            101,
+            102,
+            &block_hash(&lwd, 100),
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+
+        let notes = received(&events);
+        let summary: Vec<_> = notes.iter().map(|n| (n.pool, n.value, n.nf)).collect();
+        assert_eq!(summary, vec![(1, 1_000, None), (2, 2_000, None)]);
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, ScanEvent::Memo(m) if m.memo == "sapling")));
+        // The spend goes unnoticed
+        assert!(!events.iter().any(|e| matches!(e, ScanEvent::Spent(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_positions_continue_from_tree_state() -> Result<()> {
+        let ufvk = test_ufvk();
+        let sapling = sapling_address(&ufvk, 0);
+        let mut chain = TestChain::new(100, 2);
+        chain.add_block(vec![
+            TestTx::new()
+                .sapling_output(&sapling, 1_000)
+                .sapling_output(&sapling, 2_000),
+            TestTx::new().sapling_output(&sapling, 3_000),
+        ]);
+        let built = chain.add_block(vec![TestTx::new().sapling_output(&sapling, 4_000)]);
+        let lwd = MockLightwalletd::new(chain);
+
+        // Start after the block with the first three notes
+        let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+        let events = scan(
+            &Network::Regtest,
+            &lwd,
+            102,
+            102,
+            &block_hash(&lwd, 101),
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+
+        let notes = received(&events);
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].position, 3);
+        assert_eq!(notes[0].nf, Some(built[0].notes[0].nullifier(&ufvk)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_block_positions_do_not_depend_on_the_start() -> Result<()> {
+        let ufvk = test_ufvk();
+        let sapling = sapling_address(&ufvk, 0);
+        let orchard = orchard_address(&ufvk, 0);
+        let mut chain = TestChain::new(100, 4);
+        chain.add_block(vec![
+            TestTx::new()
+                .sapling_output(&sapling, 1_000)
+                .orchard_output(&orchard, 2_000),
+            TestTx::new().sapling_output(&sapling, 3_000),
+        ]);
+        chain.add_block(vec![TestTx::new()
+            .sapling_output(&sapling, 4_000)
+            .orchard_output(&orchard, 5_000)]);
+        let lwd = MockLightwalletd::new(chain);
+
+        // Notes of block 102, scanned as the first block of the range
+        // and after block 101
+        let mut found = vec![];
+        for start in [101, 102] {
+            let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+            let events = scan(
+                &Network::Regtest,
+                &lwd,
+                start,
+                102,
+                &block_hash(&lwd, start - 1),
+                &mut sap_dec,
+                &mut orc_dec,
+            )
+            .await?;
+            let notes: Vec<_> = received(&events)
+                .into_iter()
+                .filter(|n| n.height == 102)
+                .map(|n| (n.pool, n.position, n.nf))
+                .collect();
+            found.push(notes);
+        }
+
+        assert_eq!(found[0].len(), 2);
+        assert_eq!(found[0], found[1]);
+        let positions: Vec<_> = found[1]
+            .iter()
+            .map(|(pool, position, _)| (*pool, *position))
+            .collect();
+        assert!(positions.contains(&(1, 2)));
+        assert!(positions.contains(&(2, 1)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocks_have_the_running_maximum_time() -> Result<()> {
+        let ufvk = test_ufvk();
+        let mut chain = TestChain::new(100, 6);
+        chain.add_empty_blocks(1);
+        chain.add_block(vec![
+            TestTx::new().sapling_output(&sapling_address(&ufvk, 0), 1_000)
+        ]);
+        chain.add_empty_blocks(1);
+        chain.add_block(vec![
+            TestTx::new().orchard_output(&orchard_address(&ufvk, 0), 2_000)
+        ]);
+        chain.add_empty_blocks(1);
+        chain.set_time(101, 1_700_000_500);
+        chain.set_time(102, 1_700_000_400);
+        chain.set_time(104, 1_700_000_450);
+        chain.set_time(105, 1_700_000_600);
+        let lwd = MockLightwalletd::new(chain);
+
+        let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+        let events = scan(
+            &Network::Regtest,
+            &lwd,
+            101,
+            105,
+            &block_hash(&lwd, 100),
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+        let blocks: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ScanEvent::Block(height, _, max_time) => Some((*height, *max_time)),
+                _ => None,
+            })
+            .collect();
+        // Only the blocks with received notes and the last block
+        assert_eq!(
+            blocks,
+            vec![
+                (102, 1_700_000_500),
+                (104, 1_700_000_500),
+                (105, 1_700_000_600)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_detects_spends() -> Result<()> {
+        let ufvk = test_ufvk();
+        let mut chain = TestChain::new(100, 3);
+        let built = chain.add_block(vec![TestTx::new()
+            .sapling_output(&sapling_address(&ufvk, 0), 1_000)
+            .orchard_output(&orchard_address(&ufvk, 0), 2_000)]);
+        let spend = chain.add_block(vec![TestTx::new()
+            .sapling_spend(&built[0].notes[0].nullifier(&ufvk))
+            .orchard_spend(&built[0].notes[1].nullifier(&ufvk))]);
+        let lwd = MockLightwalletd::new(chain);
+
+        let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+        let events = scan(
+            &Network::Regtest,
+            &lwd,
+            101,
+            102,
+            &block_hash(&lwd, 100),
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await?;
+
+        let spent: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ScanEvent::Spent(n) => Some((n.pool, n.height, n.value, n.txid)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            spent,
+            vec![
+                (1, 102, 1_000, spend[0].txid),
+                (2, 102, 2_000, spend[0].txid)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_detects_fork() -> Result<()> {
+        let ufvk = test_ufvk();
+        let mut chain = TestChain::new(100, 4);
+        chain.add_empty_blocks(2);
+        let lwd = MockLightwalletd::new(chain);
+        let orphaned = block_hash(&lwd, 101);
+        {
+            let mut chain = lwd.chain();
+            chain.fork(100);
+            chain.add_empty_blocks(2);
+        }
+        assert_ne!(block_hash(&lwd, 101), orphaned);
+
+        let (mut sap_dec, mut orc_dec) = decoders(&ufvk);
+        let res = scan(
+            &Network::Regtest,
+            &lwd,
+            102,
+            102,
+            &orphaned,
+            &mut sap_dec,
+            &mut orc_dec,
+        )
+        .await;
+        assert!(matches!(res, Err(ScanError::Reorganization)));
+        Ok(())
+    }
+}
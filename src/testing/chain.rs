@@ -0,0 +1,560 @@
+use std::{collections::HashMap, str::FromStr};
+
+use orchard::{
+    keys::{FullViewingKey, Scope, SpendingKey},
+    note::{ExtractedNoteCommitment, RandomSeed, Rho},
+    note_encryption::{OrchardDomain, OrchardNoteEncryption},
+    primitives::redpallas::{SigningKey, SpendAuth, VerificationKey},
+    value::{NoteValue as OrchardValue, ValueCommitTrapdoor as OrchardTrapdoor},
+};
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaCha20Rng,
+};
+use sapling_crypto::{
+    note_encryption::{sapling_note_encryption, SaplingDomain},
+    value::{NoteValue, ValueCommitTrapdoor, ValueCommitment},
+    PaymentAddress, Rseed,
+};
+use zcash_keys::keys::UnifiedFullViewingKey;
+use zcash_note_encryption::Domain;
+use zcash_primitives::transaction::Transaction;
+use zcash_protocol::{
+    consensus::{BlockHeight, BranchId},
+    memo::{Memo, MemoBytes},
+};
+
+use crate::{
+    lwd_rpc::{
+        CompactBlock, CompactOrchardAction, CompactSaplingOutput, CompactSaplingSpend, CompactTx,
+        RawTransaction, TreeState,
+    },
+    network::Network,
+    Hash,
+};
+
+const COMPACT_NOTE_SIZE: usize = 52;
+const BLOCK_TIME: u32 = 75;
+const GENESIS_TIME: u32 = 1_700_000_000;
+
+/// A transaction to add to the test chain
+#[derive(Default)]
+pub struct TestTx {
+    sapling_spends: Vec<Hash>,
+    sapling_outputs: Vec<(PaymentAddress, u64, MemoBytes)>,
+    orchard_spends: Vec<Hash>,
+    orchard_outputs: Vec<(orchard::Address, u64, MemoBytes)>,
+    fee: u64,
+}
+
+impl TestTx {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sapling_output(self, address: &PaymentAddress, value: u64) -> Self {
+        self.sapling_output_with_memo(address, value, "")
+    }
+
+    pub fn orchard_output(self, address: &orchard::Address, value: u64) -> Self {
+        self.orchard_output_with_memo(address, value, "")
+    }
+
+    /// An empty `memo` leaves the memo field empty instead of holding
+    /// an empty text
+    pub fn sapling_output_with_memo(
+        mut self,
+        address: &PaymentAddress,
+        value: u64,
+        memo: &str,
+    ) -> Self {
+        self.sapling_outputs
+            .push((*address, value, memo_bytes(memo)));
+        self
+    }
+
+    pub fn orchard_output_with_memo(
+        mut self,
+        address: &orchard::Address,
+        value: u64,
+        memo: &str,
+    ) -> Self {
+        self.orchard_outputs
+            .push((*address, value, memo_bytes(memo)));
+        self
+    }
+
+    /// Spend the sapling note with nullifier `nf`
+    pub fn sapling_spend(mut self, nf: &Hash) -> Self {
+        self.sapling_spends.push(*nf);
+        self
+    }
+
+    /// Spend the orchard note with nullifier `nf`
+    pub fn orchard_spend(mut self, nf: &Hash) -> Self {
+        self.orchard_spends.push(*nf);
+        self
+    }
+
+    /// Pay `fee`, in the Sapling value balance if the transaction has
+    /// Sapling outputs and in the Orchard one otherwise
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+}
+
+/// A note created by the test chain
+pub enum TestNote {
+    Sapling {
+        note: sapling_crypto::Note,
+        position: u32,
+    },
+    Orchard {
+        note: orchard::Note,
+    },
+}
+
+impl TestNote {
+    /// Nullifier of the note when received by `ufvk`
+    pub fn nullifier(&self, ufvk: &UnifiedFullViewingKey) -> Hash {
+        match self {
+            TestNote::Sapling { note, position } => {
+                let nk = ufvk.sapling().unwrap().fvk().vk.nk;
+                note.nf(&nk, *position as u64).0
+            }
+            TestNote::Orchard { note } => note.nullifier(ufvk.orchard().unwrap()).to_bytes(),
+        }
+    }
+}
+
+pub struct BuiltTx {
+    pub txid: Hash,
+    /// Notes in output order, sapling first
+    pub notes: Vec<TestNote>,
+}
+
+struct TestBlock {
+    block: CompactBlock,
+    sapling_tree_size: u32,
+    orchard_tree_size: u32,
+}
+
+/// A chain of compact blocks with shielded payments, the full transactions
+/// they come from and the matching commitment tree sizes.
+///
+/// Everything is derived from the seed, so that a test always sees the
+/// same blocks. After a `fork`, the new blocks get different hashes.
+pub struct TestChain {
+    network: Network,
+    rng: ChaCha20Rng,
+    start: u32,
+    blocks: Vec<TestBlock>,
+    transactions: HashMap<Hash, RawTransaction>,
+    /// Receives the dummy outputs of orchard actions
+    dummy_recipient: orchard::Address,
+}
+
+impl TestChain {
+    /// Chain with an empty block at `start`
+    pub fn new(start: u32, seed: u64) -> Self {
+        let mut rng = ChaCha20Rng::seed_from_u64(seed);
+        let dummy_recipient = loop {
+            let mut sk = [0u8; 32];
+            rng.fill_bytes(&mut sk);
+            if let Some(sk) = Option::<SpendingKey>::from(SpendingKey::from_bytes(sk)) {
+                break FullViewingKey::from(&sk).address_at(0u32, Scope::External);
+            }
+        };
+        let mut chain = TestChain {
+            network: Network::Regtest,
+            rng,
+            start,
+            blocks: vec![],
+            transactions: HashMap::new(),
+            dummy_recipient,
+        };
+        chain.add_block(vec![]);
+        chain
+    }
+
+    pub fn tip(&self) -> u32 {
+        self.start + self.blocks.len() as u32 - 1
+    }
+
+    pub fn add_block(&mut self, txs: Vec<TestTx>) -> Vec<BuiltTx> {
+        let height = self.start + self.blocks.len() as u32;
+        let (prev_hash, mut sapling_tree_size, mut orchard_tree_size) = match self.blocks.last() {
+            Some(b) => (
+                b.block.hash.clone(),
+                b.sapling_tree_size,
+                b.orchard_tree_size,
+            ),
+            None => (vec![0u8; 32], 0, 0),
+        };
+
+        let mut vtx = vec![];
+        let mut built = vec![];
+        for (index, tx) in txs.into_iter().enumerate() {
+            let (ctx, raw, notes) = self.build_tx(height, index as u64, tx, sapling_tree_size);
+            sapling_tree_size += ctx.outputs.len() as u32;
+            orchard_tree_size += ctx.actions.len() as u32;
+            let txid: Hash = ctx.hash.clone().try_into().unwrap();
+            self.transactions.insert(txid, raw);
+            built.push(BuiltTx { txid, notes });
+            vtx.push(ctx);
+        }
+
+        let block = CompactBlock {
+            proto_version: 1,
+            height: height as u64,
+            hash: self.random_bytes().to_vec(),
+            prev_hash,
+            time: GENESIS_TIME + (height - self.start) * BLOCK_TIME,
+            header: vec![],
+            vtx,
+        };
+        self.blocks.push(TestBlock {
+            block,
+            sapling_tree_size,
+            orchard_tree_size,
+        });
+        built
+    }
+
+    pub fn add_empty_blocks(&mut self, count: u32) {
+        for _ in 0..count {
+            self.add_block(vec![]);
+        }
+    }
+
+    /// Drop the blocks above `height`. Blocks added afterwards replace
+    /// them with new hashes.
+    pub fn fork(&mut self, height: u32) {
+        assert!(height >= self.start, "Cannot fork below the first block");
+        self.blocks.truncate((height - self.start + 1) as usize);
+    }
+
+    /// Change the time of the block at `height`, for blocks mined out of
+    /// time order
+    pub fn set_time(&mut self, height: u32, time: u32) {
+        let index = height
+            .checked_sub(self.start)
+            .expect("Block below the first one");
+        self.blocks[index as usize].block.time = time;
+    }
+
+    pub fn block(&self, height: u32) -> Option<&CompactBlock> {
+        let index = height.checked_sub(self.start)?;
+        self.blocks.get(index as usize).map(|b| &b.block)
+    }
+
+    /// Blocks from `start` to `end` inclusive, up to the tip
+    pub fn blocks(&self, start: u32, end: u32) -> Vec<CompactBlock> {
+        (start..=end.min(self.tip()))
+            .filter_map(|h| self.block(h).cloned())
+            .collect()
+    }
+
+    pub fn tree_state(&self, height: u32) -> Option<TreeState> {
+        let index = height.checked_sub(self.start)?;
+        let b = self.blocks.get(index as usize)?;
+        let mut hash = b.block.hash.clone();
+        hash.reverse();
+        Some(TreeState {
+            network: "regtest".to_string(),
+            height: height as u64,
+            hash: hex::encode(hash),
+            time: b.block.time,
+            sapling_tree: tree_of_size(b.sapling_tree_size),
+            orchard_tree: tree_of_size(b.orchard_tree_size),
+        })
+    }
+
+    /// Transactions stay available after a fork, like in a mempool
+    pub fn transaction(&self, txid: &[u8]) -> Option<RawTransaction> {
+        let txid: Hash = txid.try_into().ok()?;
+        self.transactions.get(&txid).cloned()
+    }
+
+    fn random_bytes(&mut self) -> Hash {
+        let mut bytes = [0u8; 32];
+        self.rng.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    /// Random canonical encoding of an element of the Pallas base or
+    /// scalar field, both slightly above 2^254
+    fn random_pallas_repr(&mut self) -> Hash {
+        let mut bytes = self.random_bytes();
+        bytes[31] &= 0x3f;
+        bytes
+    }
+
+    fn build_tx(
+        &mut self,
+        height: u32,
+        index: u64,
+        tx: TestTx,
+        sapling_position: u32,
+    ) -> (CompactTx, RawTransaction, Vec<TestNote>) {
+        let empty_memo = MemoBytes::empty();
+        let mut ctx = CompactTx {
+            index,
+            ..CompactTx::default()
+        };
+        let mut notes = vec![];
+
+        // Sapling, only the fields that are parsed need valid values.
+        // Proofs and signatures are zeros.
+        let mut sapling_spends = vec![];
+        for nf in tx.sapling_spends.iter() {
+            let cv = ValueCommitment::derive(
+                NoteValue::from_raw(0),
+                ValueCommitTrapdoor::random(&mut self.rng),
+            )
+            .to_bytes();
+            // Any point is a valid randomized key for parsing
+            let rk = ValueCommitment::derive(
+                NoteValue::from_raw(0),
+                ValueCommitTrapdoor::random(&mut self.rng),
+            )
+            .to_bytes();
+            sapling_spends.push([cv, *nf, rk]);
+            ctx.spends.push(CompactSaplingSpend { nf: nf.to_vec() });
+        }
+
+        let mut sapling_outputs = vec![];
+        for (vout, (address, value, memo)) in tx.sapling_outputs.iter().enumerate() {
+            let note = address.create_note(
+                NoteValue::from_raw(*value),
+                Rseed::AfterZip212(self.random_bytes()),
+            );
+            let cmu = note.cmu();
+            let cv = ValueCommitment::derive(
+                NoteValue::from_raw(*value),
+                ValueCommitTrapdoor::random(&mut self.rng),
+            );
+            let ne = sapling_note_encryption(None, note.clone(), *memo.as_array(), &mut self.rng);
+            let epk = SaplingDomain::epk_bytes(ne.epk()).0;
+            let enc_ciphertext = ne.encrypt_note_plaintext();
+            let out_ciphertext = ne.encrypt_outgoing_plaintext(&cv, &cmu, &mut self.rng);
+            ctx.outputs.push(CompactSaplingOutput {
+                cmu: cmu.to_bytes().to_vec(),
+                epk: epk.to_vec(),
+                ciphertext: enc_ciphertext[..COMPACT_NOTE_SIZE].to_vec(),
+            });
+            sapling_outputs.push((
+                cv.to_bytes(),
+                cmu.to_bytes(),
+                epk,
+                enc_ciphertext,
+                out_ciphertext,
+            ));
+            notes.push(TestNote::Sapling {
+                note,
+                position: sapling_position + vout as u32,
+            });
+        }
+
+        // Orchard, every action spends and outputs. Missing spends use a
+        // random nullifier and missing outputs pay a dummy recipient.
+        let action_count = tx.orchard_spends.len().max(tx.orchard_outputs.len());
+        let mut actions = vec![];
+        for i in 0..action_count {
+            let nf = match tx.orchard_spends.get(i) {
+                Some(nf) => *nf,
+                None => self.random_pallas_repr(),
+            };
+            let (recipient, value, memo, is_wallet_output) = match tx.orchard_outputs.get(i) {
+                Some((address, value, memo)) => (*address, *value, memo, true),
+                None => (self.dummy_recipient, 0, &empty_memo, false),
+            };
+            let rho = Rho::from_bytes(&nf).unwrap();
+            let note = loop {
+                let rseed = RandomSeed::from_bytes(self.random_bytes(), &rho);
+                if let Some(rseed) = Option::<RandomSeed>::from(rseed) {
+                    let note = orchard::Note::from_parts(
+                        recipient,
+                        OrchardValue::from_raw(value),
+                        rho,
+                        rseed,
+                    );
+                    if let Some(note) = Option::<orchard::Note>::from(note) {
+                        break note;
+                    }
+                }
+            };
+            let cmx = ExtractedNoteCommitment::from(note.commitment());
+            let rcv = OrchardTrapdoor::from_bytes(self.random_pallas_repr()).unwrap();
+            let cv_net = orchard::value::ValueCommitment::derive(
+                OrchardValue::from_raw(0) - note.value(),
+                rcv,
+            );
+            let rk = SigningKey::<SpendAuth>::try_from(self.random_pallas_repr()).unwrap();
+            let rk: [u8; 32] = (&VerificationKey::from(&rk)).into();
+            let ne = OrchardNoteEncryption::new(None, note, *memo.as_array());
+            let epk = OrchardDomain::epk_bytes(ne.epk()).0;
+            let enc_ciphertext = ne.encrypt_note_plaintext();
+            let out_ciphertext = ne.encrypt_outgoing_plaintext(&cv_net, &cmx, &mut self.rng);
+            ctx.actions.push(CompactOrchardAction {
+                nullifier: nf.to_vec(),
+                cmx: cmx.to_bytes().to_vec(),
+                ephemeral_key: epk.to_vec(),
+                ciphertext: enc_ciphertext[..COMPACT_NOTE_SIZE].to_vec(),
+            });
+            actions.push((
+                cv_net.to_bytes(),
+                nf,
+                rk,
+                cmx.to_bytes(),
+                epk,
+                enc_ciphertext,
+                out_ciphertext,
+            ));
+            if is_wallet_output {
+                notes.push(TestNote::Orchard { note });
+            }
+        }
+
+        // ZIP 225 v5 transaction
+        let branch_id = BranchId::for_height(&self.network, BlockHeight::from_u32(height));
+        let mut data = vec![];
+        data.extend((5u32 | 1 << 31).to_le_bytes());
+        data.extend(0x26A7270Au32.to_le_bytes());
+        data.extend(u32::from(branch_id).to_le_bytes());
+        data.extend(0u32.to_le_bytes()); // lock time
+        data.extend(0u32.to_le_bytes()); // expiry height
+        data.push(0); // transparent inputs
+        data.push(0); // transparent outputs
+
+        write_compact_size(&mut data, sapling_spends.len());
+        for spend in sapling_spends.iter() {
+            spend.iter().for_each(|field| data.extend(field));
+        }
+        write_compact_size(&mut data, sapling_outputs.len());
+        for (cv, cmu, epk, enc, out) in sapling_outputs.iter() {
+            data.extend(cv);
+            data.extend(cmu);
+            data.extend(epk);
+            data.extend(enc);
+            data.extend(out);
+        }
+        let has_sapling = !sapling_spends.is_empty() || !sapling_outputs.is_empty();
+        let (sapling_balance, orchard_balance) = if has_sapling {
+            (tx.fee as i64, 0)
+        } else {
+            (0, tx.fee as i64)
+        };
+        if has_sapling {
+            data.extend(sapling_balance.to_le_bytes()); // value balance
+            if !sapling_spends.is_empty() {
+                data.extend([0u8; 32]); // anchor
+            }
+            data.extend(vec![0u8; 192 * sapling_spends.len()]); // proofs
+            data.extend(vec![0u8; 64 * sapling_spends.len()]); // signatures
+            data.extend(vec![0u8; 192 * sapling_outputs.len()]); // proofs
+            data.extend([0u8; 64]); // binding signature
+        }
+
+        write_compact_size(&mut data, actions.len());
+        for (cv, nf, rk, cmx, epk, enc, out) in actions.iter() {
+            data.extend(cv);
+            data.extend(nf);
+            data.extend(rk);
+            data.extend(cmx);
+            data.extend(epk);
+            data.extend(enc);
+            data.extend(out);
+        }
+        if !actions.is_empty() {
+            data.push(0b11); // spends and outputs enabled
+            data.extend(orchard_balance.to_le_bytes()); // value balance
+            data.extend([0u8; 32]); // anchor
+            write_compact_size(&mut data, 0); // proof
+            data.extend(vec![0u8; 64 * actions.len()]); // signatures
+            data.extend([0u8; 64]); // binding signature
+        }
+
+        let txid = Transaction::read(&*data, branch_id)
+            .expect("Test transaction must parse")
+            .txid();
+        ctx.hash = txid.as_ref().to_vec();
+        let raw = RawTransaction {
+            data,
+            height: height as u64,
+        };
+        (ctx, raw, notes)
+    }
+}
+
+fn memo_bytes(memo: &str) -> MemoBytes {
+    if memo.is_empty() {
+        MemoBytes::empty()
+    } else {
+        MemoBytes::from(Memo::from_str(memo).expect("Memo too long"))
+    }
+}
+
+fn write_compact_size(data: &mut Vec<u8>, size: usize) {
+    match size {
+        0..=0xfc => data.push(size as u8),
+        0xfd..=0xffff => {
+            data.push(0xfd);
+            data.extend((size as u16).to_le_bytes());
+        }
+        _ => {
+            data.push(0xfe);
+            data.extend((size as u32).to_le_bytes());
+        }
+    }
+}
+
+/// Serialized commitment tree with `size` leaves, in the format of
+/// `z_gettreestate`. Only the shape matters, the nodes are zeros.
+fn tree_of_size(size: u32) -> String {
+    if size == 0 {
+        return String::new();
+    }
+    let mut data = vec![1u8];
+    data.extend([0u8; 32]);
+    // An odd size leaves the right leaf empty
+    let rest = if size % 2 == 1 {
+        data.push(0);
+        size - 1
+    } else {
+        data.push(1);
+        data.extend([0u8; 32]);
+        size - 2
+    };
+    // Parent i stands for 2^(i+1) leaves
+    let depth = 32 - rest.leading_zeros();
+    let parents = depth.saturating_sub(1) as usize;
+    write_compact_size(&mut data, parents);
+    for i in 0..parents {
+        if rest & (1 << (i + 1)) != 0 {
+            data.push(1);
+            data.extend([0u8; 32]);
+        } else {
+            data.push(0);
+        }
+    }
+    hex::encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::get_tree_size;
+
+    #[test]
+    fn tree_sizes_round_trip() {
+        for size in (0..300).chain([1 << 16, (1 << 16) + 1, 1_000_003]) {
+            assert_eq!(
+                get_tree_size(&tree_of_size(size)).unwrap(),
+                size,
+                "size {size}"
+            );
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Offline test support: a synthetic chain of shielded payments, a mock
+//! lightwalletd serving it and a wallet connected to the mock.
+
+pub mod chain;
+pub mod server;
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::json;
+use zcash_keys::{
+    address::Address,
+    keys::{UnifiedFullViewingKey, UnifiedSpendingKey},
+};
+
+pub use chain::{BuiltTx, TestChain, TestNote, TestTx};
+pub use server::MockLightwalletd;
+
+use crate::{
+    keys::ViewingKey,
+    network::Network,
+    notifier::TxNotifier,
+    scan::{Decoder, Orchard, Sapling},
+    Hash, WalletConfig, ZcashWalletd,
+};
+
+/// Regtest viewing key with sapling and orchard components
+pub const TEST_VK: &str = "uviewregtest10lkfv9ck80w7hc50x02fkzwl004glax8gtyg6n3edgy5ld34xvutln5zwlezpmtadv9v2jge0damef7egg8tk93xncq73k0fdzpfrecpzmres8ucz82m8h9ephp53vasten7xrf95h9egdhyg2fqu2qz3hgyy0k6tny6d28m5duuzk72ma0nfr2y5cxqwjscspsdm5qkaafc9edtpzapmfxgzcdkqr60atx32g6q8fxhhh9n0hueslvzy04xyx5353nmmxx2k7uxwdv6t9y626f0d03lgufgkct3gkyxp4u24xdz9l5jsa5ne8cw9s5cjqernqj7xqmwzuc7lad6c7ayqk2ry3e66qea5pmq32a9v4spfswmtsvklljmd0fc4pk8f32g7snzxyrlmnkguch3execr9kqx02a6dc2ryuzvrg8vrrfxjkve6tpyk4vfz9j2zkuws9g5e06wm744yzsye3w74qwjrn5t2rzqfn6zmr8fgkjea8c";
+
+pub const TEST_BIRTH_HEIGHT: u32 = 100;
+
+pub fn test_ufvk() -> UnifiedFullViewingKey {
+    UnifiedFullViewingKey::decode(&Network::Regtest, TEST_VK).unwrap()
+}
+
+/// Viewing key of the first account of a seed made of `seed` bytes
+pub fn ufvk_from_seed(seed: u8) -> UnifiedFullViewingKey {
+    UnifiedSpendingKey::from_seed(&Network::Regtest, &[seed; 32], zip32::AccountId::ZERO)
+        .unwrap()
+        .to_unified_full_viewing_key()
+}
+
+/// Regtest encodings of `ufvk` and of its incoming viewing key
+pub fn encode_keys(ufvk: &UnifiedFullViewingKey) -> (String, String) {
+    let uivk = ufvk.to_unified_incoming_viewing_key();
+    (
+        ufvk.encode(&Network::Regtest),
+        uivk.encode(&Network::Regtest),
+    )
+}
+
+/// Sapling address of `ufvk` at the first valid diversifier index from
+/// `index`
+pub fn sapling_address(ufvk: &UnifiedFullViewingKey, index: u32) -> sapling_crypto::PaymentAddress {
+    let (_, address) = ufvk.sapling().unwrap().find_address(index.into()).unwrap();
+    address
+}
+
+pub fn orchard_address(ufvk: &UnifiedFullViewingKey, index: u32) -> orchard::Address {
+    ufvk.orchard()
+        .unwrap()
+        .address_at(index, orchard::keys::Scope::External)
+}
+
+/// Sapling and orchard receivers of a unified address
+pub fn receivers(
+    address: &str,
+) -> (
+    Option<sapling_crypto::PaymentAddress>,
+    Option<orchard::Address>,
+) {
+    match Address::decode(&Network::Regtest, address) {
+        Some(Address::Unified(ua)) => (ua.sapling().cloned(), ua.orchard().cloned()),
+        Some(Address::Sapling(pa)) => (Some(pa), None),
+        _ => (None, None),
+    }
+}
+
+/// Scanner decoders of `ufvk`, without known nullifiers
+pub fn decoders(
+    ufvk: &UnifiedFullViewingKey,
+) -> (Option<Decoder<Sapling>>, Option<Decoder<Orchard>>) {
+    crate::scan::decoders(&ViewingKey::Full(ufvk.clone()), &HashMap::new())
+}
+
+/// Notifier that keeps the txids it is called with
+#[derive(Default)]
+pub struct RecordingNotifier {
+    txids: Mutex<Vec<Hash>>,
+    late_txids: Mutex<Vec<Hash>>,
+}
+
+impl RecordingNotifier {
+    pub fn txids(&self) -> Vec<Hash> {
+        self.txids.lock().unwrap().clone()
+    }
+
+    /// Transactions notified as late payments
+    pub fn late_txids(&self) -> Vec<Hash> {
+        self.late_txids.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl TxNotifier for RecordingNotifier {
+    async fn notify_tx(&self, txid: &[u8], late: bool) -> Result<()> {
+        let txid: Hash = txid.try_into()?;
+        self.txids.lock().unwrap().push(txid);
+        if late {
+            self.late_txids.lock().unwrap().push(txid);
+        }
+        Ok(())
+    }
+}
+
+/// Local stand-in for a rate service: serves the last of `rates` at or
+/// before the `time` of `/rate?time=<time>`. Returns the URL template for
+/// `RATE_URL`.
+pub fn spawn_rate_server(mut rates: Vec<(u64, f64)>) -> Result<String> {
+    rates.sort_by_key(|(time, _)| *time);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            // Read the whole request head before answering
+            let mut lines = BufReader::new(&stream).lines();
+            let request_line = lines.next().and_then(|line| line.ok()).unwrap_or_default();
+            for line in lines {
+                match line {
+                    Ok(line) if !line.is_empty() => {}
+                    _ => break,
+                }
+            }
+            let time = request_line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|path| path.split_once("time="))
+                .and_then(|(_, time)| time.parse::<u64>().ok());
+            let rate = time.and_then(|time| {
+                rates
+                    .iter()
+                    .rev()
+                    .find(|(t, _)| *t <= time)
+                    .map(|(_, rate)| *rate)
+            });
+            let (status, body) = match rate {
+                Some(rate) => ("200 OK", json!({ "rate": rate }).to_string()),
+                None => ("404 Not Found", String::new()),
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    });
+    Ok(format!("http://{address}/rate?time={{time}}"))
+}
+
+/// Wallet with a fresh database, scanning a mock lightwalletd
+pub struct TestWallet {
+    pub wallet: ZcashWalletd,
+    pub lwd: MockLightwalletd,
+    pub notifier: Arc<RecordingNotifier>,
+    pub ufvk: UnifiedFullViewingKey,
+}
+
+impl TestWallet {
+    /// `name` must be unique per test, it names the database file
+    pub async fn new(name: &str, seed: u64) -> Result<Self> {
+        Self::new_hosting(name, seed, &[]).await
+    }
+
+    /// Wallet that also hosts a wallet per `(id, viewing key)`
+    pub async fn new_hosting(
+        name: &str,
+        seed: u64,
+        hosted: &[(&str, &UnifiedFullViewingKey)],
+    ) -> Result<Self> {
+        Self::open(name, seed, TEST_VK, hosted, json!({})).await
+    }
+
+    /// Wallet with the settings of `config` added to the test configuration
+    pub async fn new_with_config(name: &str, seed: u64, config: serde_json::Value) -> Result<Self> {
+        Self::open(name, seed, TEST_VK, &[], config).await
+    }
+
+    /// Wallet configured with the UIVK of the test key
+    pub async fn new_incoming(name: &str, seed: u64) -> Result<Self> {
+        let (_, uivk) = encode_keys(&test_ufvk());
+        Self::open(name, seed, &uivk, &[], json!({})).await
+    }
+
+    async fn open(
+        name: &str,
+        seed: u64,
+        vk: &str,
+        hosted: &[(&str, &UnifiedFullViewingKey)],
+        extra: serde_json::Value,
+    ) -> Result<Self> {
+        let db_path = |name: &str| {
+            let path = std::env::temp_dir().join(format!("zcash-walletd-{name}.db"));
+            let _ = std::fs::remove_file(&path);
+            path.to_str().unwrap().to_string()
+        };
+        let wallets: Vec<_> = hosted
+            .iter()
+            .map(|(id, ufvk)| {
+                json!({
+                    "id": id,
+                    "vk": ufvk.encode(&Network::Regtest),
+                    "birth_height": TEST_BIRTH_HEIGHT,
+                    "db_path": db_path(&format!("{name}-{id}")),
+                })
+            })
+            .collect();
+
+        let lwd = MockLightwalletd::new(TestChain::new(TEST_BIRTH_HEIGHT, seed));
+        let url = lwd.spawn().await?;
+        let mut config = json!({
+            "db_path": db_path(name),
+            "confirmations": 1,
+            "lwd_url": url,
+            "notify_tx_url": "",
+            "poll_interval": 60,
+            "regtest": true,
+            "orchard": true,
+            "vk": vk,
+            "birth_height": TEST_BIRTH_HEIGHT,
+            "address_pool_size": 5,
+            "wallets": wallets,
+        });
+        if let serde_json::Value::Object(extra) = extra {
+            config.as_object_mut().unwrap().extend(extra);
+        }
+        let config: WalletConfig = serde_json::from_value(config)?;
+        let notifier = Arc::new(RecordingNotifier::default());
+        let wallet =
+            ZcashWalletd::new(config, Some(notifier.clone() as Arc<dyn TxNotifier>)).await?;
+
+        Ok(TestWallet {
+            wallet,
+            lwd,
+            notifier,
+            ufvk: test_ufvk(),
+        })
+    }
+
+    /// Mine a block with `txs` on the mock chain
+    pub fn mine(&self, txs: Vec<TestTx>) -> Vec<BuiltTx> {
+        self.lwd.chain().add_block(txs)
+    }
+}
@@ -0,0 +1,255 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use crate::{
+    chain::{BlockStream, ChainInfo, ChainSource},
+    lwd_rpc::{
+        compact_tx_streamer_server::{CompactTxStreamer, CompactTxStreamerServer},
+        Address, AddressList, Balance, BlockId, BlockRange, ChainSpec, CompactBlock, CompactTx,
+        Duration, Empty, Exclude, GetAddressUtxosArg, GetAddressUtxosReply,
+        GetAddressUtxosReplyList, LightdInfo, PingResponse, RawTransaction, SendResponse,
+        TransparentAddressBlockFilter, TreeState, TxFilter,
+    },
+    Hash,
+};
+
+use super::chain::TestChain;
+
+type ResponseStream<T> = tokio_stream::Iter<std::vec::IntoIter<Result<T, Status>>>;
+
+/// In-process lightwalletd serving a `TestChain`. The chain can be
+/// extended or forked while the server runs.
+///
+/// It is also a `ChainSource`, for tests of the scanner that do not need
+/// the gRPC round trip.
+#[derive(Clone)]
+pub struct MockLightwalletd {
+    chain: Arc<Mutex<TestChain>>,
+}
+
+impl MockLightwalletd {
+    pub fn new(chain: TestChain) -> Self {
+        Self {
+            chain: Arc::new(Mutex::new(chain)),
+        }
+    }
+
+    pub fn chain(&self) -> MutexGuard<'_, TestChain> {
+        self.chain.lock().unwrap()
+    }
+
+    /// Listen on a local port and return the URL of the server
+    pub async fn spawn(&self) -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let service = CompactTxStreamerServer::new(self.clone());
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        Ok(format!("http://{address}"))
+    }
+}
+
+fn block_not_found(height: u64) -> Status {
+    Status::not_found(format!("No block at height {height}"))
+}
+
+#[tonic::async_trait]
+impl CompactTxStreamer for MockLightwalletd {
+    async fn get_latest_block(
+        &self,
+        _request: Request<ChainSpec>,
+    ) -> Result<Response<BlockId>, Status> {
+        let chain = self.chain();
+        let tip = chain.tip();
+        let block = chain.block(tip).unwrap();
+        Ok(Response::new(BlockId {
+            height: tip as u64,
+            hash: block.hash.clone(),
+        }))
+    }
+
+    async fn get_block(&self, request: Request<BlockId>) -> Result<Response<CompactBlock>, Status> {
+        let height = request.into_inner().height;
+        let block = self
+            .chain()
+            .block(height as u32)
+            .cloned()
+            .ok_or_else(|| block_not_found(height))?;
+        Ok(Response::new(block))
+    }
+
+    type GetBlockRangeStream = ResponseStream<CompactBlock>;
+
+    async fn get_block_range(
+        &self,
+        request: Request<BlockRange>,
+    ) -> Result<Response<Self::GetBlockRangeStream>, Status> {
+        let range = request.into_inner();
+        let start = range.start.map(|b| b.height).unwrap_or_default() as u32;
+        let end = range.end.map(|b| b.height).unwrap_or_default() as u32;
+        let blocks = self.chain().blocks(start, end);
+        Ok(Response::new(tokio_stream::iter(
+            blocks.into_iter().map(Ok).collect::<Vec<_>>(),
+        )))
+    }
+
+    async fn get_transaction(
+        &self,
+        request: Request<TxFilter>,
+    ) -> Result<Response<RawTransaction>, Status> {
+        let filter = request.into_inner();
+        let tx = self
+            .chain()
+            .transaction(&filter.hash)
+            .ok_or_else(|| Status::not_found("Unknown transaction"))?;
+        Ok(Response::new(tx))
+    }
+
+    async fn send_transaction(
+        &self,
+        _request: Request<RawTransaction>,
+    ) -> Result<Response<SendResponse>, Status> {
+        Err(Status::unimplemented("send_transaction"))
+    }
+
+    type GetTaddressTxidsStream = ResponseStream<RawTransaction>;
+
+    async fn get_taddress_txids(
+        &self,
+        _request: Request<TransparentAddressBlockFilter>,
+    ) -> Result<Response<Self::GetTaddressTxidsStream>, Status> {
+        Err(Status::unimplemented("get_taddress_txids"))
+    }
+
+    async fn get_taddress_balance(
+        &self,
+        _request: Request<AddressList>,
+    ) -> Result<Response<Balance>, Status> {
+        Err(Status::unimplemented("get_taddress_balance"))
+    }
+
+    async fn get_taddress_balance_stream(
+        &self,
+        _request: Request<Streaming<Address>>,
+    ) -> Result<Response<Balance>, Status> {
+        Err(Status::unimplemented("get_taddress_balance_stream"))
+    }
+
+    type GetMempoolTxStream = ResponseStream<CompactTx>;
+
+    async fn get_mempool_tx(
+        &self,
+        _request: Request<Exclude>,
+    ) -> Result<Response<Self::GetMempoolTxStream>, Status> {
+        Err(Status::unimplemented("get_mempool_tx"))
+    }
+
+    type GetMempoolStreamStream = ResponseStream<RawTransaction>;
+
+    async fn get_mempool_stream(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::GetMempoolStreamStream>, Status> {
+        Err(Status::unimplemented("get_mempool_stream"))
+    }
+
+    async fn get_tree_state(
+        &self,
+        request: Request<BlockId>,
+    ) -> Result<Response<TreeState>, Status> {
+        let height = request.into_inner().height;
+        let tree_state = self
+            .chain()
+            .tree_state(height as u32)
+            .ok_or_else(|| block_not_found(height))?;
+        Ok(Response::new(tree_state))
+    }
+
+    async fn get_address_utxos(
+        &self,
+        _request: Request<GetAddressUtxosArg>,
+    ) -> Result<Response<GetAddressUtxosReplyList>, Status> {
+        Err(Status::unimplemented("get_address_utxos"))
+    }
+
+    type GetAddressUtxosStreamStream = ResponseStream<GetAddressUtxosReply>;
+
+    async fn get_address_utxos_stream(
+        &self,
+        _request: Request<GetAddressUtxosArg>,
+    ) -> Result<Response<Self::GetAddressUtxosStreamStream>, Status> {
+        Err(Status::unimplemented("get_address_utxos_stream"))
+    }
+
+    async fn get_lightd_info(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<LightdInfo>, Status> {
+        let tip = self.chain().tip() as u64;
+        Ok(Response::new(LightdInfo {
+            chain_name: "regtest".to_string(),
+            block_height: tip,
+            estimated_height: tip,
+            ..LightdInfo::default()
+        }))
+    }
+
+    async fn ping(&self, _request: Request<Duration>) -> Result<Response<PingResponse>, Status> {
+        Err(Status::unimplemented("ping"))
+    }
+}
+
+#[async_trait]
+impl ChainSource for MockLightwalletd {
+    async fn latest_block(&self) -> Result<BlockId> {
+        let chain = self.chain();
+        let tip = chain.tip();
+        Ok(BlockId {
+            height: tip as u64,
+            hash: chain.block(tip).unwrap().hash.clone(),
+        })
+    }
+
+    async fn chain_info(&self) -> Result<ChainInfo> {
+        let tip = self.chain().tip();
+        Ok(ChainInfo {
+            height: tip,
+            estimated_height: tip,
+        })
+    }
+
+    async fn block_range(&self, start: u32, end: u32) -> Result<BlockStream> {
+        let blocks = self.chain().blocks(start, end);
+        Ok(Box::pin(tokio_stream::iter(blocks.into_iter().map(Ok))))
+    }
+
+    async fn tree_state(&self, height: u32) -> Result<TreeState> {
+        self.chain()
+            .tree_state(height)
+            .ok_or_else(|| anyhow!("No block at height {height}"))
+    }
+
+    async fn raw_transaction(&self, txid: &Hash) -> Result<Vec<u8>> {
+        let tx = self
+            .chain()
+            .transaction(txid)
+            .ok_or_else(|| anyhow!("Unknown transaction"))?;
+        Ok(tx.data)
+    }
+
+    async fn block_hash(&self, height: u32) -> Result<Hash> {
+        let chain = self.chain();
+        let block = chain
+            .block(height)
+            .ok_or_else(|| anyhow!("No block at height {height}"))?;
+        Ok(block.hash.clone().try_into().unwrap())
+    }
+}
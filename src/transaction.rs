@@ -21,5 +21,8 @@ pub struct Transfer {
     pub txid: String,
     pub r#type: String,
     pub unlock_time: u32,
+    /// Received after the expiry of the address
+    pub late: bool,
+    /// Fiat rate of ZEC at the block time, if a rate provider is configured
+    pub rate: Option<f64>,
 }
-
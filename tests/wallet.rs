@@ -0,0 +1,722 @@
+use anyhow::Result;
+use zcash_keys::keys::UnifiedAddressRequest;
+use zcash_walletd::{
+    error::{self, WalletError},
+    export::{ExportFormat, ExportRange, ExportRow},
+    monitor::MonitorTask,
+    testing::{
+        encode_keys, orchard_address, receivers, spawn_rate_server, ufvk_from_seed, TestTx,
+        TestWallet,
+    },
+    AddressOptions, AddressType, BalanceMode, ZcashWalletd,
+};
+
+async fn new_address(t: &TestWallet) -> Result<String> {
+    Ok(t.wallet
+        .create_address(0, None, AddressOptions::default(), None)
+        .await?
+        .address)
+}
+
+async fn balance(t: &TestWallet) -> Result<u64> {
+    Ok(t.wallet.get_accounts(None).await?.total_balance)
+}
+
+async fn account_balance(wallet: &ZcashWalletd, account_index: u32) -> Result<u64> {
+    let accounts = wallet.get_accounts(None).await?.subaddress_accounts;
+    Ok(accounts
+        .iter()
+        .find(|a| a.account_index == account_index)
+        .map(|a| a.balance)
+        .unwrap_or_default())
+}
+
+#[tokio::test]
+async fn receive_sapling_and_orchard() -> Result<()> {
+    let t = TestWallet::new("it-receive", 1).await?;
+    let address = new_address(&t).await?;
+    let (sapling, orchard) = receivers(&address);
+
+    t.mine(vec![
+        TestTx::new().sapling_output(&sapling.unwrap(), 10_000),
+        TestTx::new().orchard_output(&orchard.unwrap(), 20_000),
+    ]);
+    t.wallet.request_scan().await?;
+
+    let transfers = t.wallet.get_transfers(0, true, vec![1]).await?.r#in;
+    let mut amounts: Vec<u64> = transfers.iter().map(|t| t.amount).collect();
+    amounts.sort();
+    assert_eq!(amounts, vec![10_000, 20_000]);
+    assert!(transfers
+        .iter()
+        .all(|t| t.height == 101 && t.confirmations == 1));
+    assert_eq!(balance(&t).await?, 30_000);
+    assert_eq!(t.wallet.get_wallet_height().await?.height, 101);
+    Ok(())
+}
+
+#[tokio::test]
+async fn spends_reduce_balance() -> Result<()> {
+    let t = TestWallet::new("it-spend", 2).await?;
+    let address = new_address(&t).await?;
+    let (sapling, orchard) = receivers(&address);
+
+    let received = t.mine(vec![TestTx::new()
+        .sapling_output(&sapling.unwrap(), 10_000)
+        .orchard_output(&orchard.unwrap(), 20_000)]);
+    t.wallet.request_scan().await?;
+    assert_eq!(balance(&t).await?, 30_000);
+
+    let notes = &received[0].notes;
+    t.mine(vec![
+        TestTx::new().sapling_spend(&notes[0].nullifier(&t.ufvk))
+    ]);
+    t.wallet.request_scan().await?;
+    assert_eq!(balance(&t).await?, 20_000);
+
+    t.mine(vec![
+        TestTx::new().orchard_spend(&notes[1].nullifier(&t.ufvk))
+    ]);
+    t.wallet.request_scan().await?;
+    assert_eq!(balance(&t).await?, 0);
+
+    // Incoming transfers are still reported
+    let transfers = t.wallet.get_transfers(0, true, vec![1]).await?.r#in;
+    assert_eq!(transfers.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reorg_drops_orphaned_notes() -> Result<()> {
+    let t = TestWallet::new("it-reorg", 3).await?;
+    let address = new_address(&t).await?;
+    let (sapling, _) = receivers(&address);
+    let sapling = sapling.unwrap();
+
+    t.mine(vec![TestTx::new().sapling_output(&sapling, 10_000)]);
+    t.wallet.request_scan().await?;
+    assert_eq!(balance(&t).await?, 10_000);
+
+    // The payment is replaced by another one, one block later
+    {
+        let mut chain = t.lwd.chain();
+        chain.fork(100);
+        chain.add_empty_blocks(1);
+        chain.add_block(vec![TestTx::new().sapling_output(&sapling, 5_000)]);
+    }
+    // The first scan detects the reorg and rewinds, the second one
+    // follows the new chain
+    t.wallet.request_scan().await?;
+    t.wallet.request_scan().await?;
+
+    let transfers = t.wallet.get_transfers(0, true, vec![1]).await?.r#in;
+    assert_eq!(transfers.len(), 1);
+    assert_eq!(transfers[0].amount, 5_000);
+    assert_eq!(transfers[0].height, 102);
+    assert_eq!(balance(&t).await?, 5_000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn receive_on_unknown_diversifier() -> Result<()> {
+    let t = TestWallet::new("it-unknown-diversifier", 4).await?;
+
+    // An address the wallet never handed out
+    let (ua, _) = t
+        .ufvk
+        .find_address(1000u32.into(), UnifiedAddressRequest::AllAvailableKeys)?;
+    let sapling = *ua.sapling().unwrap();
+
+    t.mine(vec![TestTx::new().sapling_output(&sapling, 7_000)]);
+    t.wallet.request_scan().await?;
+
+    assert_eq!(balance(&t).await?, 7_000);
+    // It goes to the unassigned account
+    assert!(t
+        .wallet
+        .get_transfers(0, true, (0..10).collect())
+        .await?
+        .r#in
+        .is_empty());
+    let transfers = t
+        .wallet
+        .get_transfers(1, true, (0..10).collect())
+        .await?
+        .r#in;
+    assert_eq!(transfers.len(), 1);
+    assert_eq!(receivers(&transfers[0].address).0, Some(sapling));
+    let e = t
+        .wallet
+        .create_address(1, None, AddressOptions::default(), None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(WalletError::from(e).code(), error::INVALID_ARGUMENT);
+
+    let invoice = t
+        .wallet
+        .create_address(0, None, AddressOptions::default(), None)
+        .await?;
+    let moved = t
+        .wallet
+        .reassign_address(&transfers[0].address, 0, invoice.address_index)
+        .await?;
+    assert_eq!(moved.notes, 1);
+    let transfers = t
+        .wallet
+        .get_transfers(0, true, (0..10).collect())
+        .await?
+        .r#in;
+    assert_eq!(transfers.len(), 1);
+    assert_eq!(transfers[0].subaddr_index.minor, invoice.address_index);
+    assert_eq!(account_balance(&t.wallet, 1).await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn address_types() -> Result<()> {
+    let t = TestWallet::new("it-address-types", 14).await?;
+    let options = |address_type, transparent| AddressOptions {
+        address_type,
+        transparent,
+    };
+
+    let legacy = options(AddressType::Sapling, false);
+    let legacy = t.wallet.create_address(0, None, legacy, None).await?;
+    assert!(legacy.address.starts_with("zregtestsapling"));
+    assert_eq!(legacy.receivers.sapling.as_ref(), Some(&legacy.address));
+    assert!(legacy.receivers.orchard.is_none());
+
+    let orchard = options(AddressType::Orchard, false);
+    let orchard = t.wallet.create_address(0, None, orchard, None).await?;
+    assert_eq!(receivers(&orchard.address).0, None);
+    assert_eq!(orchard.receivers.orchard.as_ref(), Some(&orchard.address));
+
+    let full = t
+        .wallet
+        .create_address(0, None, options(AddressType::SaplingOrchard, false), None)
+        .await?;
+    assert!(full.receivers.sapling.is_some());
+    assert!(full.receivers.orchard.is_some());
+    assert!(full.receivers.transparent.is_none());
+
+    // Payments to a transparent receiver would not be detected
+    let transparent = options(AddressType::Unified, true);
+    let e = t
+        .wallet
+        .create_address(0, None, transparent, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<WalletError>(),
+        Some(WalletError::InvalidArgument(_))
+    ));
+    assert!(new_address(&t).await?.starts_with("uregtest"));
+
+    // Payments to the legacy address go to its sub-account
+    let (sapling, _) = receivers(&legacy.address);
+    t.mine(vec![TestTx::new().sapling_output(&sapling.unwrap(), 3_000)]);
+    t.wallet.request_scan().await?;
+    let transfers = t
+        .wallet
+        .get_transfers(0, true, (0..10).collect())
+        .await?
+        .r#in;
+    assert_eq!(transfers.len(), 1);
+    assert_eq!(transfers[0].address, legacy.address);
+    assert_eq!(transfers[0].subaddr_index.minor, legacy.address_index);
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_addresses() -> Result<()> {
+    let t = TestWallet::new("it-expiry", 15).await?;
+    // Before the time of the blocks of the test chain
+    let expiry = Some(1_700_000_000);
+    let options = AddressOptions::default();
+    let late = t.wallet.create_address(0, None, options, expiry).await?;
+    let unpaid = t.wallet.create_address(0, None, options, expiry).await?;
+    let open = t.wallet.create_address(0, None, options, None).await?;
+
+    let (sapling, _) = receivers(&late.address);
+    let received = t.mine(vec![TestTx::new().sapling_output(&sapling.unwrap(), 4_000)]);
+    t.wallet.request_scan().await?;
+
+    // Still watched, but flagged
+    let transfers = t
+        .wallet
+        .get_transfers(0, true, vec![late.address_index])
+        .await?
+        .r#in;
+    assert_eq!(transfers.len(), 1);
+    assert!(transfers[0].late);
+    assert!(transfers[0].timestamp > 1_700_000_000);
+    assert_eq!(t.notifier.late_txids(), vec![received[0].txid]);
+
+    let payments = t.wallet.get_late_payments().await?.payments;
+    assert_eq!(payments.len(), 1);
+    assert_eq!(payments[0].address_index, late.address_index);
+    assert_eq!(payments[0].amount, 4_000);
+
+    let addresses = t.wallet.get_addresses().await?.addresses;
+    let active = |index: u32| {
+        addresses
+            .iter()
+            .find(|a| a.account_index == 0 && a.sub_account_index == index)
+            .map(|a| a.active)
+    };
+    assert_eq!(active(late.address_index), Some(true));
+    assert_eq!(active(unpaid.address_index), Some(false));
+    assert_eq!(active(open.address_index), Some(true));
+    Ok(())
+}
+
+#[tokio::test]
+async fn export_transfers() -> Result<()> {
+    let t = TestWallet::new("it-export", 16).await?;
+    let address = new_address(&t).await?;
+    let (sapling, orchard) = receivers(&address);
+
+    let received = t.mine(vec![TestTx::new()
+        .sapling_output_with_memo(&sapling.unwrap(), 10_000, "invoice, 42")
+        .orchard_output(&orchard.unwrap(), 20_000)
+        .fee(15_000)]);
+    let spent = t.mine(vec![
+        TestTx::new().orchard_spend(&received[0].notes[1].nullifier(&t.ufvk))
+    ]);
+    t.wallet.request_scan().await?;
+
+    let json = t
+        .wallet
+        .export(&ExportRange::default(), ExportFormat::Json)
+        .await?;
+    let rows: Vec<ExportRow> = serde_json::from_str(&json)?;
+    let kinds: Vec<_> = rows
+        .iter()
+        .map(|r| (r.r#type.as_str(), r.height, r.amount))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ("in", 101, 10_000),
+            ("in", 101, 20_000),
+            ("out", 102, 20_000)
+        ]
+    );
+    assert!(rows
+        .iter()
+        .all(|r| r.address == address && r.address_index == 1));
+    assert_eq!(rows[0].memo, "invoice, 42");
+    assert_eq!(rows[0].pool, "sapling");
+    assert_eq!(rows[0].confirmations, 2);
+    let mut txid = spent[0].txid;
+    txid.reverse();
+    assert_eq!(rows[2].txid, hex::encode(txid));
+    assert_eq!(rows[2].pool, "orchard");
+    // Only the fee of the transaction that paid the wallet is known
+    let fees: Vec<_> = rows.iter().map(|r| r.fee).collect();
+    assert_eq!(fees, vec![Some(15_000), Some(15_000), None]);
+
+    let range = ExportRange {
+        from_height: Some(102),
+        ..Default::default()
+    };
+    let csv = t.wallet.export(&range, ExportFormat::Csv).await?;
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].starts_with("out,"));
+    // The command line export reads the same database
+    let config = &t.wallet.config;
+    let offline = ZcashWalletd::export_offline(config, None, &range, ExportFormat::Csv).await?;
+    assert_eq!(offline, csv);
+
+    // Blocks of the test chain are from November 2023
+    let range = ExportRange {
+        to_date: Some("2023-11-13".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        t.wallet
+            .export(&range, ExportFormat::Csv)
+            .await?
+            .lines()
+            .count(),
+        1
+    );
+    let range = ExportRange {
+        from_date: Some("2023-11-14".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        t.wallet
+            .export(&range, ExportFormat::Csv)
+            .await?
+            .lines()
+            .count(),
+        4
+    );
+    let range = ExportRange {
+        from_date: Some("14/11/2023".to_string()),
+        ..Default::default()
+    };
+    let e = t
+        .wallet
+        .export(&range, ExportFormat::Csv)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<WalletError>(),
+        Some(WalletError::InvalidArgument(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn balance_at_height_and_time() -> Result<()> {
+    let t = TestWallet::new("it-balance-at", 17).await?;
+    let first = new_address(&t).await?;
+    let second = new_address(&t).await?;
+    let (sapling, orchard) = receivers(&first);
+
+    let received = t.mine(vec![TestTx::new()
+        .sapling_output(&sapling.unwrap(), 10_000)
+        .orchard_output(&orchard.unwrap(), 20_000)]);
+    let (sapling, _) = receivers(&second);
+    t.mine(vec![TestTx::new().sapling_output(&sapling.unwrap(), 5_000)]);
+    t.mine(vec![
+        TestTx::new().orchard_spend(&received[0].notes[1].nullifier(&t.ufvk))
+    ]);
+    t.wallet.request_scan().await?;
+
+    let wallet = &t.wallet;
+    let total = |height: u32| async move {
+        let rep = wallet.get_balance_at(Some(height), None).await?;
+        Ok::<_, anyhow::Error>(rep.total_balance)
+    };
+    assert_eq!(total(100).await?, 0);
+    assert_eq!(total(101).await?, 30_000);
+    assert_eq!(total(102).await?, 35_000);
+    assert_eq!(total(103).await?, 15_000);
+    let rep = t.wallet.get_balance_at(Some(102), None).await?;
+    let sub_accounts: Vec<_> = rep.accounts[0]
+        .sub_accounts
+        .iter()
+        .map(|s| (s.address_index, s.balance))
+        .collect();
+    assert_eq!(sub_accounts, vec![(1, 30_000), (2, 5_000)]);
+
+    // Blocks are 75 seconds apart from the birth height
+    let at_time = |timestamp: u64| async move {
+        let rep = wallet.get_balance_at(None, Some(timestamp)).await?;
+        Ok::<_, anyhow::Error>((rep.height, rep.total_balance))
+    };
+    assert_eq!(at_time(1_699_999_999).await?, (99, 0));
+    assert_eq!(at_time(1_700_000_160).await?, (102, 35_000));
+    assert_eq!(at_time(1_700_000_225).await?, (103, 15_000));
+    assert_eq!(at_time(1_800_000_000).await?, (103, 15_000));
+
+    let code = |e: anyhow::Error| WalletError::from(e).code();
+    let e = t.wallet.get_balance_at(Some(104), None).await.unwrap_err();
+    assert_eq!(code(e), error::NOT_SYNCED);
+    let e = t
+        .wallet
+        .get_balance_at(Some(101), Some(1_700_000_000))
+        .await
+        .unwrap_err();
+    assert_eq!(code(e), error::INVALID_ARGUMENT);
+    Ok(())
+}
+
+#[tokio::test]
+async fn received_payments_carry_the_rate() -> Result<()> {
+    let url = spawn_rate_server(vec![(1_700_000_000, 30.5), (1_700_000_200, 31.25)])?;
+    let config = serde_json::json!({ "rate_url": url });
+    let t = TestWallet::new_with_config("it-rates", 18, config).await?;
+    let address = new_address(&t).await?;
+    let (sapling, _) = receivers(&address);
+    let sapling = sapling.unwrap();
+
+    // Blocks 101 and 103, on each side of the rate change
+    t.mine(vec![TestTx::new().sapling_output(&sapling, 10_000)]);
+    t.lwd.chain().add_empty_blocks(1);
+    t.mine(vec![TestTx::new().sapling_output(&sapling, 20_000)]);
+    t.wallet.request_scan().await?;
+
+    // Rates are recorded in the background after the scan
+    let mut rates = vec![];
+    for _ in 0..500 {
+        let transfers = t.wallet.get_transfers(0, true, vec![1]).await?.r#in;
+        rates = transfers.iter().map(|t| (t.height, t.rate)).collect();
+        if rates.iter().all(|(_, rate)| rate.is_some()) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(rates, vec![(101, Some(30.5)), (103, Some(31.25))]);
+
+    let json = t
+        .wallet
+        .export(&ExportRange::default(), ExportFormat::Json)
+        .await?;
+    let rows: Vec<ExportRow> = serde_json::from_str(&json)?;
+    assert_eq!(
+        rows.iter().map(|r| r.rate).collect::<Vec<_>>(),
+        vec![Some(30.5), Some(31.25)]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn notifier_called_once_per_transaction() -> Result<()> {
+    let t = TestWallet::new("it-notifier", 5).await?;
+    let address = new_address(&t).await?;
+    let (sapling, orchard) = receivers(&address);
+
+    let received = t.mine(vec![TestTx::new()
+        .sapling_output(&sapling.unwrap(), 10_000)
+        .orchard_output(&orchard.unwrap(), 20_000)]);
+    t.lwd.chain().add_empty_blocks(1);
+    t.wallet.request_scan().await?;
+    assert_eq!(t.notifier.txids(), vec![received[0].txid]);
+
+    let spent = t.mine(vec![
+        TestTx::new().orchard_spend(&received[0].notes[1].nullifier(&t.ufvk))
+    ]);
+    t.wallet.request_scan().await?;
+    assert_eq!(t.notifier.txids(), vec![received[0].txid, spent[0].txid]);
+
+    // Scanning again does not notify twice
+    t.wallet.request_scan().await?;
+    assert_eq!(t.notifier.txids().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn errors_have_codes() -> Result<()> {
+    let t = TestWallet::new("it-errors", 6).await?;
+    t.lwd.chain().add_empty_blocks(1);
+    t.wallet.request_scan().await?;
+
+    let code = |e: anyhow::Error| WalletError::from(e).code();
+    let unknown_txid = hex::encode([7u8; 32]);
+    let e = t.wallet.get_transaction(unknown_txid, 0).await.unwrap_err();
+    assert_eq!(code(e), error::NOT_FOUND);
+    let e = t
+        .wallet
+        .get_transaction("xyz".to_string(), 0)
+        .await
+        .unwrap_err();
+    assert_eq!(code(e), error::INVALID_ARGUMENT);
+    let e = t
+        .wallet
+        .get_transfers(0, false, vec![0])
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(code(e), error::INVALID_ARGUMENT);
+    let e = t
+        .wallet
+        .create_address(5, None, AddressOptions::default(), None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(code(e), error::NOT_FOUND);
+
+    // A wallet behind the chain cannot tell if a transaction exists
+    t.lwd.chain().add_empty_blocks(1);
+    let e = t
+        .wallet
+        .get_transaction(hex::encode([7u8; 32]), 0)
+        .await
+        .unwrap_err();
+    assert_eq!(code(e), error::NOT_SYNCED);
+    Ok(())
+}
+
+#[tokio::test]
+async fn wallets_scanned_together() -> Result<()> {
+    let shop_ufvk = ufvk_from_seed(1);
+    let t = TestWallet::new_hosting("it-multi", 7, &[("shop", &shop_ufvk)]).await?;
+    let shop = t.wallet.for_wallet("shop")?;
+    let (sapling, _) = receivers(&new_address(&t).await?);
+    let shop_address = shop
+        .create_address(0, None, AddressOptions::default(), None)
+        .await?;
+    let (_, shop_orchard) = receivers(&shop_address.address);
+
+    t.mine(vec![TestTx::new()
+        .sapling_output(&sapling.unwrap(), 10_000)
+        .orchard_output(&shop_orchard.unwrap(), 20_000)]);
+    t.wallet.request_scan().await?;
+
+    assert_eq!(balance(&t).await?, 10_000);
+    assert_eq!(shop.get_accounts(None).await?.total_balance, 20_000);
+    assert_eq!(shop.get_wallet_height().await?.height, 101);
+    assert_eq!(t.wallet.wallet_ids(), vec!["default", "shop"]);
+
+    let e = t.wallet.for_wallet("unknown").err().unwrap();
+    assert_eq!(WalletError::from(e).code(), error::NOT_FOUND);
+    Ok(())
+}
+
+#[tokio::test]
+async fn imported_key_catches_up() -> Result<()> {
+    let t = TestWallet::new("it-import", 8).await?;
+    let imported = ufvk_from_seed(2);
+    let (ufvk, _) = encode_keys(&imported);
+    let (_, uivk) = encode_keys(&ufvk_from_seed(3));
+
+    // Paid before the import
+    let received = t.mine(vec![
+        TestTx::new().orchard_output(&orchard_address(&imported, 0), 5_000)
+    ]);
+    t.lwd.chain().add_empty_blocks(1);
+    t.wallet.request_scan().await?;
+
+    let account = t.wallet.import_viewing_key(&ufvk, 100, None).await?;
+    assert_eq!(account.account_index, 1);
+    assert_eq!(account.balance_mode, BalanceMode::Unspent);
+    let e = t
+        .wallet
+        .import_viewing_key(&ufvk, 100, None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(WalletError::from(e).code(), error::INVALID_ARGUMENT);
+
+    // Wait for the background scan
+    for _ in 0..500 {
+        let status = t.wallet.status().await?;
+        if status.viewing_keys.iter().all(|k| k.synced_height == 102) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(account_balance(&t.wallet, 1).await?, 5_000);
+
+    // Then scanned at the tip with the wallet key
+    let watched = t.wallet.import_viewing_key(&uivk, 102, None).await?;
+    assert_eq!(watched.balance_mode, BalanceMode::ReceivedTotals);
+    let (_, watched_orchard) = receivers(&watched.address);
+    let payment = t.mine(vec![
+        TestTx::new().orchard_spend(&received[0].notes[0].nullifier(&imported)),
+        TestTx::new().orchard_output(&watched_orchard.unwrap(), 3_000),
+    ]);
+    t.wallet.request_scan().await?;
+    assert_eq!(account_balance(&t.wallet, 1).await?, 0);
+    assert_eq!(
+        account_balance(&t.wallet, watched.account_index).await?,
+        3_000
+    );
+
+    // Spends of a UIVK are not seen
+    let nf = payment[1].notes[0].nullifier(&ufvk_from_seed(3));
+    t.mine(vec![TestTx::new().orchard_spend(&nf)]);
+    t.wallet.request_scan().await?;
+    assert_eq!(
+        account_balance(&t.wallet, watched.account_index).await?,
+        3_000
+    );
+    assert_eq!(balance(&t).await?, 3_000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn incoming_viewing_key_mode() -> Result<()> {
+    let t = TestWallet::new_incoming("it-uivk", 9).await?;
+    let address = new_address(&t).await?;
+    let (sapling, orchard) = receivers(&address);
+
+    let received = t.mine(vec![TestTx::new()
+        .sapling_output_with_memo(&sapling.unwrap(), 10_000, "order 1")
+        .orchard_output(&orchard.unwrap(), 20_000)]);
+    t.wallet.request_scan().await?;
+    let transfers = t.wallet.get_transfers(0, true, vec![1]).await?.r#in;
+    assert_eq!(transfers.len(), 2);
+    assert!(transfers.iter().any(|t| t.note == "order 1"));
+
+    // The spend is not detected, the balance is what was received
+    t.mine(vec![
+        TestTx::new().orchard_spend(&received[0].notes[1].nullifier(&t.ufvk))
+    ]);
+    t.wallet.request_scan().await?;
+    assert_eq!(balance(&t).await?, 30_000);
+
+    let accounts = t.wallet.get_accounts(None).await?.subaddress_accounts;
+    assert_eq!(accounts[0].balance_mode, BalanceMode::ReceivedTotals);
+    assert_eq!(
+        t.wallet.status().await?.balance_mode,
+        BalanceMode::ReceivedTotals
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_to_the_tip() -> Result<()> {
+    let t = TestWallet::new("it-scan-tip", 1).await?;
+    t.wallet.create_address(0, None).await?;
+
+    t.lwd.chain().add_empty_blocks(10);
+    t.wallet.request_scan().await?;
+
+    assert_eq!(t.wallet.get_wallet_height().await?.height, 110);
+    assert_eq!(balance(&t).await?, 0);
+    assert!(t
+        .wallet
+        .get_transfers(0, true, vec![1])
+        .await?
+        .r#in
+        .is_empty());
+    assert!(t.notifier.txids().is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reorg_follows_the_new_chain() -> Result<()> {
+    let t = TestWallet::new("it-reorg-empty", 3).await?;
+
+    t.lwd.chain().add_empty_blocks(4);
+    t.wallet.request_scan().await?;
+    assert_eq!(t.wallet.get_wallet_height().await?.height, 104);
+
+    // Blocks above 102 are replaced by a longer branch
+    {
+        let mut chain = t.lwd.chain();
+        chain.fork(102);
+        chain.add_empty_blocks(3);
+    }
+    // The first scan detects the reorg and rewinds, the second one
+    // follows the new chain
+    t.wallet.request_scan().await?;
+    t.wallet.request_scan().await?;
+    assert_eq!(t.wallet.get_wallet_height().await?.height, 105);
+    Ok(())
+}
+
+#[tokio::test]
+async fn tip_watcher_scans_after_a_rewind() -> Result<()> {
+    let t = TestWallet::new("it-rewind-watch", 3).await?;
+    t.lwd.chain().add_empty_blocks(4);
+    let poll = std::time::Duration::from_millis(20);
+    let monitor = MonitorTask::spawn(t.wallet.clone(), poll, poll);
+    let wait_for_height = |height: u32| {
+        let wallet = t.wallet.clone();
+        async move {
+            for _ in 0..100 {
+                if wallet.get_wallet_height().await?.height == height {
+                    return Ok(true);
+                }
+                tokio::time::sleep(poll).await;
+            }
+            anyhow::Ok(false)
+        }
+    };
+    assert!(wait_for_height(104).await?);
+
+    // No block arrives after the rewind
+    assert_eq!(t.wallet.rewind_to(101).await?.height, 101);
+    assert!(wait_for_height(104).await?);
+
+    monitor.shutdown().await;
+    Ok(())
+}